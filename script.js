@@ -1,4 +1,4 @@
-import init, { get_paths } from "./wasm-paths/pkg/wasm_paths.js";
+import init, { get_fill_rule, get_paths } from "./wasm-paths/pkg/wasm_paths.js";
 
 const canvas = document.getElementById("canvas");
 const ctx = canvas.getContext("2d");
@@ -79,9 +79,10 @@ function drawPaths() {
   ctx.strokeStyle = "none";
   ctx.fillStyle = textColor;
 
+  const fillRule = get_fill_rule(input);
   for (let path of paths) {
     const p = new Path2D(path);
-    ctx.fill(p);
+    ctx.fill(p, fillRule);
   }
 }
 
@@ -1,8 +1,10 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
-use std::mem::MaybeUninit;
-use std::sync::Once;
+use std::rc::Rc;
 
 use glam::{DAffine2, DVec2};
+pub use icu::properties::Script;
 use rustybuzz as hb; // alias for harfbuzz
 use wasm_bindgen::prelude::*;
 
@@ -21,168 +23,3207 @@ macro_rules! log {
 }
 
 #[derive(Default)]
-struct AppState<'a> {
-    fonts: FontRegistry<'a>,
+struct AppState {
+    fonts: FontRegistry,
     inputs: Vec<Input>,
     last_input: usize,
     last_text_size: usize,
     already_performed_layout: bool,
     prev_layout: Vec<Vec<ShapedFragment>>,
+    /// How many times text was handed to the shaper, for [`Layout::shaping_calls`].
+    shaping_calls: std::cell::Cell<usize>,
 }
 
-struct InputTransform {
+#[wasm_bindgen]
+pub fn get_paths(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> Vec<String> {
+    with_state(|state| {
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            ..state.inputs[input].options.clone()
+        };
+
+        state.resolve_input(&options, input)
+    })
+}
+
+/// Like [`get_paths`], but only returns the glyphs whose outline intersects the clip rectangle,
+/// for documents much larger than the part that is visible.
+#[wasm_bindgen]
+#[allow(clippy::too_many_arguments)]
+pub fn get_paths_clipped(
     x: i32,
     y: i32,
     w: i32,
     h: i32,
     size: usize,
+    input: usize,
+    clip_x: f64,
+    clip_y: f64,
+    clip_w: f64,
+    clip_h: f64,
+) -> Vec<String> {
+    with_state(|state| {
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            clip: Some(Rect {
+                x: clip_x,
+                y: clip_y,
+                w: clip_w,
+                h: clip_h,
+            }),
+            ..state.inputs[input].options.clone()
+        };
+
+        state.resolve_input(&options, input)
+    })
+}
+
+/// Lays out arbitrary text with the registered fonts and returns the structured result instead
+/// of only the path strings. Nothing is cached between calls.
+pub fn layout(text: &str, options: &LayoutOptions) -> Layout {
+    with_state(|state| state.layout_text(text, options, None).0)
+}
+
+/// Like [`layout`], but every glyph is moved by the transform `transform` returns for its index
+/// in [`Layout::glyphs`] and its current transform, e.g. to animate it. Pen positions, bounds and
+/// paths follow the new transform. Ruby glyphs stay where they are and clipping happens before
+/// anything moves.
+pub fn layout_with_transform(
+    text: &str,
+    options: &LayoutOptions,
+    transform: impl Fn(usize, DAffine2) -> DAffine2,
+) -> Layout {
+    let mut result = layout(text, options);
+    for (i, glyph) in result.glyphs.iter_mut().enumerate() {
+        glyph.set_transform(transform(i, glyph.transform), options.device_pixel_ratio);
+        glyph.finish_path(options);
+    }
+
+    result
+}
+
+/// Lays out `text` and draws it into pixels as big as the box, for rendering without a browser.
+/// See [`Layout::rasterize`].
+#[cfg(feature = "raster")]
+pub fn render(text: &str, options: &LayoutOptions) -> Raster {
+    let scale = options.device_pixel_ratio;
+    let width = ((options.x + options.w) as f64 * scale).ceil().max(0.0) as u32;
+    let height = ((options.y + options.h) as f64 * scale).ceil().max(0.0) as u32;
+
+    layout(text, options).rasterize(width, height)
+}
+
+/// Shapes `text` on a single line with the font, size and shaping options of `options`, for
+/// callers doing their own layout. Glyphs come in logical order, pen positions start at the
+/// origin on the baseline and the advances, letter spacing included, add up to the run's width.
+pub fn shape(text: &str, options: &LayoutOptions) -> Vec<LayoutGlyph> {
+    with_state(|state| state.shape_text(text, options))
+}
+
+/// Glyph ids `text` shapes to in `font_id` at `size`, in logical order like [`shape`], e.g. to
+/// subset the font. Glyph outlines aren't built.
+#[wasm_bindgen]
+pub fn shape_gids(text: &str, font_id: &str, size: usize) -> Vec<u16> {
+    let options = LayoutOptions {
+        size,
+        fallback_font: font_id.into(),
+        skeleton: Skeleton::Lines,
+        ..Default::default()
+    };
+
+    shape(text, &options)
+        .iter()
+        .map(|glyph| glyph.glyph_id)
+        .collect()
+}
+
+/// Height of a box `w` wide that fits all of `text` laid out in `font_id`, padding included.
+#[wasm_bindgen]
+pub fn preferred_height(text: &str, font_id: &str, w: i32, size: usize) -> f64 {
+    let options = LayoutOptions {
+        w,
+        size,
+        fallback_font: font_id.into(),
+        ..Default::default()
+    };
+    let layout = layout(text, &options);
+
+    layout.lines.len() as f64 * layout.line_height + options.insets.top + options.insets.bottom
+}
+
+/// Source byte offsets where the lines of `text` laid out `w` wide in `font_id` start, the first
+/// one included, for editors doing their own drawing. Glyph outlines aren't built.
+#[wasm_bindgen]
+pub fn line_break_offsets(text: &str, font_id: &str, w: i32, size: usize) -> Vec<usize> {
+    let options = LayoutOptions {
+        w,
+        size,
+        fallback_font: font_id.into(),
+        skeleton: Skeleton::Lines,
+        ..Default::default()
+    };
+
+    layout(text, &options)
+        .lines
+        .iter()
+        .map(|line| line.source.start)
+        .collect()
+}
+
+/// Lines of demo input `input` laid out like [`get_paths`] does, as a JSON array of objects
+/// with their `text`, `x`, `y`, `width` and `direction`, `ltr` or `rtl`, to position `<text>`
+/// elements with. See [`Layout::text_lines`].
+#[wasm_bindgen]
+pub fn get_text_lines(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> String {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't get the lines of demo input {} because there is no such input!",
+                input
+            );
+            return "[]".into();
+        };
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            skeleton: Skeleton::Lines,
+            ..input.options.clone()
+        };
+
+        let layout = state.layout_text(&input.text, &options, None).0;
+        let lines = layout.text_lines(&input.text).into_iter().map(|line| {
+            format!(
+                "{{\"text\":{},\"x\":{},\"y\":{},\"width\":{},\"direction\":\"{}\"}}",
+                json_string(line.text),
+                line.x,
+                line.y,
+                line.width,
+                if line.rtl { "rtl" } else { "ltr" }
+            )
+        });
+        format!("[{}]", lines.collect::<Vec<_>>().join(","))
+    })
+}
+
+/// Words of demo input `input` laid out like [`get_paths`] does, as a JSON array of objects with
+/// their source bytes `start` and `end` and their box `x`, `y`, `w` and `h`, e.g. to select a
+/// word on double click. See [`Layout::word_boxes`].
+#[wasm_bindgen]
+pub fn get_word_boxes(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> String {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't get the words of demo input {} because there is no such input!",
+                input
+            );
+            return "[]".into();
+        };
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            skeleton: Skeleton::Lines,
+            ..input.options.clone()
+        };
+
+        let layout = state.layout_text(&input.text, &options, None).0;
+        let words = layout.word_boxes().into_iter().map(|word| {
+            format!(
+                "{{\"start\":{},\"end\":{},\"x\":{},\"y\":{},\"w\":{},\"h\":{}}}",
+                word.start, word.end, word.rect.x, word.rect.y, word.rect.w, word.rect.h
+            )
+        });
+        format!("[{}]", words.collect::<Vec<_>>().join(","))
+    })
+}
+
+/// Glyphs of demo input `input` laid out like [`get_paths`] does, each drawn from the top left
+/// corner of its bounds to pack them into an atlas. A JSON array of objects with the `glyph`
+/// index, the `path`, the pen position in it `origin_x` and `origin_y`, and `x` and `y` to draw
+/// it at. See [`Layout::local_glyphs`].
+#[wasm_bindgen]
+pub fn get_local_glyphs(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> String {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't get the glyphs of demo input {} because there is no such input!",
+                input
+            );
+            return "[]".into();
+        };
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            ..input.options.clone()
+        };
+
+        let layout = state.layout_text(&input.text, &options, None).0;
+        let glyphs = layout
+            .local_glyphs(options.device_pixel_ratio)
+            .into_iter()
+            .map(|glyph| {
+                format!(
+                    "{{\"glyph\":{},\"path\":{},\"origin_x\":{},\"origin_y\":{},\"x\":{},\"y\":{}}}",
+                    glyph.glyph,
+                    json_string(&glyph.local_path),
+                    glyph.atlas_origin.x,
+                    glyph.atlas_origin.y,
+                    glyph.place_x,
+                    glyph.place_y
+                )
+            });
+        format!("[{}]", glyphs.collect::<Vec<_>>().join(","))
+    })
+}
+
+/// Glyphs of demo input `input` laid out like [`get_paths`] does that are displayed out of
+/// order, one message for every pair. Empty when bidi reordering went right, see
+/// [`Layout::reordering_anomalies`].
+#[wasm_bindgen]
+pub fn validate_layout(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> Vec<String> {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't validate the layout of demo input {} because there is no such input!",
+                input
+            );
+            return Vec::new();
+        };
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            skeleton: Skeleton::Lines,
+            ..input.options.clone()
+        };
+
+        let layout = state.layout_text(&input.text, &options, None).0;
+        layout
+            .reordering_anomalies()
+            .into_iter()
+            .map(|anomaly| {
+                let first = &layout.glyphs[anomaly.glyphs.0];
+                let second = &layout.glyphs[anomaly.glyphs.1];
+                let direction = if first.rtl {
+                    "right-to-left"
+                } else {
+                    "left-to-right"
+                };
+                format!(
+                    "Line {}: cluster {} is displayed before cluster {} in {} text.",
+                    anomaly.line, first.cluster, second.cluster, direction,
+                )
+            })
+            .collect()
+    })
+}
+
+/// Width of the longest line of `text` laid out `w` wide in `font_id`, e.g. to size a table
+/// column to its content. With a `w` of zero or less the paragraphs aren't wrapped. Glyph
+/// outlines aren't built.
+#[wasm_bindgen]
+pub fn max_line_width(text: &str, font_id: &str, w: i32, size: usize) -> f64 {
+    let options = LayoutOptions {
+        w: if w > 0 { w } else { i32::MAX },
+        size,
+        fallback_font: font_id.into(),
+        skeleton: Skeleton::Lines,
+        ..Default::default()
+    };
+
+    layout(text, &options)
+        .lines
+        .iter()
+        .map(|line| line.width)
+        .fold(0.0, f64::max)
+}
+
+/// Text of every line of demo input `input` laid out like [`get_paths`] does, for a text
+/// alternative of what's drawn. See [`Layout::line_texts`].
+#[wasm_bindgen]
+pub fn get_line_texts(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> Vec<String> {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't get the lines of demo input {} because there is no such input!",
+                input
+            );
+            return Vec::new();
+        };
+        let options = LayoutOptions {
+            x,
+            y,
+            w,
+            h,
+            size,
+            skeleton: Skeleton::Lines,
+            ..input.options.clone()
+        };
+
+        let layout = state.layout_text(&input.text, &options, None).0;
+        layout
+            .line_texts(&input.text)
+            .into_iter()
+            .map(String::from)
+            .collect()
+    })
+}
+
+/// Largest size in `min..=max` at which `text` fits a `w`×`h` box without wrapping inside a word
+/// or running out of lines, together with the paths at that size. Gives `min` if nothing fits.
+pub fn fit_to_box(
+    text: &str,
+    font_id: &str,
+    min: usize,
+    max: usize,
+    w: i32,
+    h: i32,
+) -> (usize, Vec<String>) {
+    let options = |size| LayoutOptions {
+        w,
+        h,
+        size,
+        fallback_font: font_id.into(),
+        ..Default::default()
+    };
+    let max_line_length = options(min).content_box().w;
+    let fits = |layout: &Layout| {
+        !layout.overflow
+            && layout
+                .lines
+                .iter()
+                .all(|line| line.width <= max_line_length)
+    };
+
+    let (mut low, mut high) = (min, max.max(min));
+    let mut best = layout(text, &options(low));
+    while low < high {
+        let size = low + (high - low).div_ceil(2);
+        let candidate = layout(text, &options(size));
+        if fits(&candidate) {
+            low = size;
+            best = candidate;
+        } else {
+            high = size - 1;
+        }
+    }
+
+    (
+        low,
+        best.glyphs.into_iter().map(|glyph| glyph.path).collect(),
+    )
+}
+
+/// Start and end of every paragraph of `text` by bidi analysis, in bytes and with the separator
+/// ending it, e.g. to match styles to paragraphs. A separator at the very end doesn't start
+/// another one, so there is one more in `"a\n\n"` but not in `"a\n"`.
+pub fn paragraph_ranges(text: &str) -> Vec<(usize, usize)> {
+    use icu::properties::bidi::BidiClassAdapter;
+    use icu::properties::maps;
+    use unicode_bidi::BidiInfo;
+
+    let adapter = BidiClassAdapter::new(maps::bidi_class());
+    BidiInfo::new_with_data_source(&adapter, text, None)
+        .paragraphs
+        .iter()
+        .map(|paragraph| (paragraph.range.start, paragraph.range.end))
+        .collect()
+}
+
+/// Problems with `text` and `options` that [`layout`] works around, found without laying anything
+/// out: fonts that aren't registered, text that isn't UTF-8, malformed feature tags and scripts
+/// no font has glyphs for. `text` is taken as bytes so that it can be checked before it is
+/// turned into a string.
+pub fn validate_input(text: &[u8], options: &LayoutOptions) -> Vec<Warning> {
+    with_state(|state| state.validate_input(text, options))
+}
+
+/// [`validate_input`] for demo input `input`, one message for every warning.
+#[wasm_bindgen]
+pub fn get_input_warnings(input: usize) -> Vec<String> {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't validate demo input {} because there is no such input!",
+                input
+            );
+            return Vec::new();
+        };
+
+        state
+            .validate_input(input.text.as_bytes(), &input.options)
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    })
+}
+
+/// Whether the bidi levels of demo input `input` differ, so that some of it is displayed in a
+/// different order than it's stored and the reordering pass matters. Text running in a single
+/// direction doesn't need it.
+#[wasm_bindgen]
+pub fn needs_reordering(input: usize) -> bool {
+    use icu::properties::bidi::BidiClassAdapter;
+    use icu::properties::maps;
+    use unicode_bidi::BidiInfo;
+
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't check demo input {} for reordering because there is no such input!",
+                input
+            );
+            return false;
+        };
+        let adapter = BidiClassAdapter::new(maps::bidi_class());
+        let base_level = bidi_base_level(&input.options);
+        mixes_levels(&BidiInfo::new_with_data_source(&adapter, &input.text, base_level).levels)
+    })
+}
+
+/// `fill-rule` of demo input `input`, `nonzero` or `evenodd`, to fill the paths of [`get_paths`]
+/// with, e.g. as the second argument of canvas `fill()`. See [`LayoutOptions::fill_rule`].
+#[wasm_bindgen]
+pub fn get_fill_rule(input: usize) -> String {
+    with_state(|state| {
+        let Some(input) = state.inputs.get(input) else {
+            log!(
+                "Can't get the fill rule of demo input {} because there is no such input!",
+                input
+            );
+            return FillRule::default().as_str().into();
+        };
+        input.options.fill_rule.as_str().into()
+    })
+}
+
+/// `black` or `white`, whichever contrasts more with the background color `bg` by the WCAG
+/// relative luminance. `bg` is a hex color like `#1e1e1e` or `#fff`, or `rgb(30, 30, 30)`.
+#[wasm_bindgen]
+pub fn suggest_text_color(bg: String) -> String {
+    let Some([r, g, b]) = parse_rgb(&bg) else {
+        log!(
+            "Can't suggest a text color for {} because it isn't a color! Using black.",
+            bg
+        );
+        return "black".into();
+    };
+
+    let linear = |channel: u8| {
+        let channel = channel as f64 / 255.0;
+        if channel <= 0.04045 {
+            channel / 12.92
+        } else {
+            ((channel + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    let luminance = 0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b);
+    let on_white = 1.05 / (luminance + 0.05);
+    let on_black = (luminance + 0.05) / 0.05;
+
+    let color = if on_black >= on_white {
+        "black"
+    } else {
+        "white"
+    };
+
+    color.into()
+}
+
+/// Red, green and blue of a `#rgb`, `#rrggbb` or `rgb(r, g, b)` color, alpha ignored.
+fn parse_rgb(color: &str) -> Option<[u8; 3]> {
+    let color = color.trim();
+    if let Some(hex) = color.strip_prefix('#') {
+        let digit = |i: usize| u8::from_str_radix(hex.get(i..i + 1)?, 16).ok();
+        return match hex.len() {
+            3 | 4 => Some([digit(0)? * 17, digit(1)? * 17, digit(2)? * 17]),
+            6 | 8 => Some([
+                digit(0)? * 16 + digit(1)?,
+                digit(2)? * 16 + digit(3)?,
+                digit(4)? * 16 + digit(5)?,
+            ]),
+            _ => None,
+        };
+    }
+
+    let arguments = color
+        .strip_prefix("rgba(")
+        .or_else(|| color.strip_prefix("rgb("))?
+        .strip_suffix(')')?;
+    let mut channels = arguments
+        .split(',')
+        .map(|channel| channel.trim().parse::<u8>().ok());
+    Some([channels.next()??, channels.next()??, channels.next()??])
+}
+
+/// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => result += "\\\"",
+            '\\' => result += "\\\\",
+            c if c.is_control() => result += &format!("\\u{:04x}", c as u32),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+
+    result
+}
+
+/// `text` escaped to go between the double quotes of an XML attribute.
+fn xml_attribute(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => result += "&amp;",
+            '<' => result += "&lt;",
+            '"' => result += "&quot;",
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// [`LayoutOptions::base_level`] as a bidi level, if it's a valid one.
+fn bidi_base_level(options: &LayoutOptions) -> Option<unicode_bidi::Level> {
+    let level = options.base_level?;
+    match unicode_bidi::Level::new_explicit(level) {
+        Ok(level) => Some(level),
+        Err(_) => {
+            log!(
+                "Can't use {} as the base bidi level because it's too deep! Ignoring it.",
+                level
+            );
+            None
+        }
+    }
+}
+
+fn mixes_levels(levels: &[unicode_bidi::Level]) -> bool {
+    levels.windows(2).any(|pair| pair[0] != pair[1])
+}
+
+/// OpenType feature tags in the GSUB and GPOS tables of `font_id`, sorted and without duplicates.
+#[wasm_bindgen]
+pub fn font_features(font_id: &str) -> Vec<String> {
+    let Some(font) = registered_font(font_id) else {
+        log!(
+            "Can't list the features of font {} because it was not found!",
+            font_id
+        );
+        return vec![];
+    };
+
+    let tables = font.face().tables();
+    let mut result = [tables.gsub, tables.gpos]
+        .into_iter()
+        .flatten()
+        .flat_map(|table| table.features)
+        .map(|feature| feature.tag.to_string())
+        .collect::<Vec<_>>();
+    result.sort();
+    result.dedup();
+
+    result
+}
+
+/// Size to lay text in `font_id` out at, with [`SizeNormalization::None`], for its capital
+/// letters to be `cap_height` pixels tall, rounded to the nearest whole size and at least 1.
+#[wasm_bindgen]
+pub fn size_for_cap_height(font_id: &str, cap_height: f64) -> usize {
+    let cap_height_per_em = match registered_font(font_id) {
+        Some(font) => {
+            let face = font.face();
+            AppState::letter_height(face, face.capital_height(), 'H')
+                .map(|metric| metric as f64 / face.units_per_em() as f64)
+        }
+        None => {
+            log!(
+                "Can't measure the cap height of font {} because it was not found!",
+                font_id
+            );
+            None
+        }
+    };
+
+    (cap_height / cap_height_per_em.unwrap_or(CAP_HEIGHT))
+        .round()
+        .max(1.0) as usize
+}
+
+/// Every glyph of `font_id` by id with the code points its Unicode cmap subtables map to it,
+/// sorted. Glyphs only reachable through shaping, like most alternates, have none.
+pub fn font_glyph_coverage(font_id: &str) -> Vec<(u16, Vec<u32>)> {
+    let Some(font) = registered_font(font_id) else {
+        log!(
+            "Can't list the glyphs of font {} because it was not found!",
+            font_id
+        );
+        return vec![];
+    };
+
+    let face = font.face();
+    let mut result = (0..face.number_of_glyphs())
+        .map(|glyph_id| (glyph_id, vec![]))
+        .collect::<Vec<_>>();
+    let subtables = face
+        .tables()
+        .cmap
+        .into_iter()
+        .flat_map(|cmap| cmap.subtables);
+    for subtable in subtables.filter(|subtable| subtable.is_unicode()) {
+        subtable.codepoints(|codepoint| {
+            let glyph = subtable
+                .glyph_index(codepoint)
+                .map(|glyph| glyph.0 as usize);
+            if let Some((_, codepoints)) = glyph.and_then(|glyph| result.get_mut(glyph)) {
+                codepoints.push(codepoint);
+            }
+        });
+    }
+    for (_, codepoints) in result.iter_mut() {
+        codepoints.sort();
+        codepoints.dedup();
+    }
+
+    result
+}
+
+/// Makes `new_id` resolve to the font registered as `existing_id`, sharing the already loaded
+/// face. Returns `false` if there is no font named `existing_id`.
+#[wasm_bindgen]
+pub fn alias_font(existing_id: &str, new_id: &str) -> bool {
+    with_state(|state| match state.fonts.get(existing_id) {
+        Some(font) => {
+            let font = Rc::clone(font);
+            // Fragments shaped with the font `new_id` named before can't be reused.
+            state.fonts.insert(new_id.into(), font);
+            state.already_performed_layout = false;
+            true
+        }
+        None => {
+            log!("Can't alias font {} because it was not found!", existing_id);
+            false
+        }
+    })
+}
+
+/// Registers the font file `data` as `id`, replacing the font that had that name unless it was
+/// loaded from the same data. Returns `false` if `data` can't be parsed as a font, see
+/// [`validate_font`] for whether it can draw text.
+#[wasm_bindgen]
+pub fn register_font(id: &str, data: Vec<u8>) -> bool {
+    with_state(|state| {
+        // Uploading the same file again keeps the font that is already loaded.
+        if state
+            .fonts
+            .get(id)
+            .is_some_and(|font| font.raw_data() == data.as_slice())
+        {
+            return true;
+        }
+        let Some(font) = Font::from_data(data) else {
+            log!("Can't register font {} because its data is not a font!", id);
+            return false;
+        };
+
+        // The font it replaces is freed once nothing uses it anymore, aliases included.
+        state.fonts.insert(id.into(), Rc::new(font));
+        state.already_performed_layout = false;
+        true
+    })
+}
+
+/// What `font_id` has of the tables needed to draw text. Unknown fonts have nothing.
+#[wasm_bindgen]
+pub fn validate_font(font_id: &str) -> FontValidation {
+    match registered_font(font_id) {
+        Some(font) => font.validation(),
+        None => {
+            log!("Can't validate font {} because it was not found!", font_id);
+            FontValidation::default()
+        }
+    }
+}
+
+/// Result of [`validate_font`].
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FontValidation {
+    /// Characters can be mapped to glyphs.
+    pub has_cmap: bool,
+    /// There are TrueType or CFF outlines to draw the glyphs with.
+    pub has_outlines: bool,
+    pub number_of_glyphs: u16,
+    pub units_per_em: u16,
+}
+
+#[wasm_bindgen]
+impl FontValidation {
+    /// Whether text can be drawn with the font at all.
+    pub fn is_usable(&self) -> bool {
+        self.has_cmap && self.has_outlines && self.number_of_glyphs > 0
+    }
+}
+
+self_cell::self_cell!(
+    /// Face parsed from a font file, kept together with the bytes it borrows from.
+    struct OwnedFace {
+        owner: Cow<'static, [u8]>,
+
+        #[covariant]
+        dependent: Face,
+    }
+);
+
+type Face<'a> = hb::Face<'a>;
+
+struct Font {
+    face: OwnedFace,
+}
+
+impl Font {
+    /// Font of the built-in font file `raw_data`, which has to be one.
+    fn new(raw_data: &'static [u8]) -> Self {
+        Self::from_data(raw_data).unwrap()
+    }
+
+    /// Font of the font file `data`, if it is one.
+    fn from_data(data: impl Into<Cow<'static, [u8]>>) -> Option<Self> {
+        let face = OwnedFace::try_new(data.into(), |data| hb::Face::from_slice(data, 0).ok_or(()));
+
+        face.ok().map(|face| Font { face })
+    }
+
+    /// Bytes of the font file.
+    fn raw_data(&self) -> &[u8] {
+        self.face.borrow_owner()
+    }
+
+    fn face(&self) -> &hb::Face<'_> {
+        self.face.borrow_dependent()
+    }
+
+    /// Sets the variation axis `tag` of a variable font to `value`.
+    fn set_variation(&mut self, tag: &[u8; 4], value: f32) {
+        self.face.with_dependent_mut(|_, face| {
+            face.set_variation(hb::ttf_parser::Tag::from_bytes(tag), value);
+        });
+    }
+
+    fn validation(&self) -> FontValidation {
+        use hb::ttf_parser::{RawFace, Tag};
+
+        let has_table = |tag: &[u8; 4]| {
+            RawFace::parse(self.raw_data(), 0)
+                .is_ok_and(|face| face.table(Tag::from_bytes(tag)).is_some())
+        };
+
+        FontValidation {
+            has_cmap: has_table(b"cmap"),
+            has_outlines: [b"glyf", b"CFF ", b"CFF2"].into_iter().any(has_table),
+            number_of_glyphs: self.face().number_of_glyphs(),
+            units_per_em: hb::ttf_parser::Face::units_per_em(self.face()),
+        }
+    }
+}
+
+pub type FontId = String;
+type FontRegistry = HashMap<FontId, Rc<Font>>;
+/// Font from [`LayoutOptions::script_fonts`] a run is shaped with, `None` for the paragraph's.
+type FallbackFont<'s> = Option<(&'s FontId, &'s Font)>;
+/// A run of text shaped in one go, with its orientation, size, level and font.
+type ShapedRun<'s> = (
+    std::ops::Range<usize>,
+    GlyphOrientation,
+    usize,
+    unicode_bidi::Level,
+    FallbackFont<'s>,
+    hb::GlyphBuffer,
+);
+
+const GLOBAL_FALLBACK_FONT: &str = "pt";
+/// The base HarfBuzz draws marks without one on.
+const DOTTED_CIRCLE: char = '\u{25cc}';
+/// Default space kept free between the text and every edge of the box.
+const PAD: f64 = 12.0;
+/// Size of ruby annotations relative to their base text.
+const RUBY_SCALE: f64 = 0.5;
+/// Longest paragraphs, in lines, that [`LineBreaking::Balance`] evens out.
+const BALANCE_MAX_LINES: usize = 6;
+/// Smallest [`LayoutOptions::flatten_tolerance`], in pixels. Finer ones take millions of lines
+/// per curve.
+const MIN_FLATTEN_TOLERANCE: f64 = 1e-3;
+/// Cap height and x-height `size` is normalized to, relative to it. They're the ones of PT Serif,
+/// the global fallback font.
+const CAP_HEIGHT: f64 = 0.7;
+const X_HEIGHT: f64 = 0.5;
+
+const FONT_DATA: [&[u8]; 5] = [
+    include_bytes!("../fonts/PTSerif-Regular.ttf"),
+    include_bytes!("../fonts/SeoulNamsanvert.otf"),
+    include_bytes!("../fonts/Roboto-VariableFont_wdth,wght.ttf"),
+    include_bytes!("../fonts/Roboto-Italic-VariableFont_wdth,wght.ttf"),
+    include_bytes!("../fonts/NotoSansHebrew-VariableFont_wdth,wght.ttf"),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum HorizontalAlignment {
+    #[default]
+    Normal,
+    Reverse,
+    Center,
+    /// Widen the gaps between the fragments of wrapped lines until they fill the line. The last
+    /// line of a paragraph follows [`LayoutOptions::last_line_alignment`]. Whitespace a line ends
+    /// with hangs past its end, so past the left edge in right-to-left text. Vertical text is
+    /// aligned to the start instead.
+    Justify,
+}
+
+/// Alignment of the last line of a justified paragraph, like CSS `text-align-last`.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LastLineAlignment {
+    #[default]
+    Start,
+    Justify,
+    Center,
+    End,
+}
+
+/// Granularity of the clusters the shaper produces, like HarfBuzz's cluster levels. Ligatures
+/// always merge the clusters of the characters they're made of.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ClusterLevel {
+    /// Marks and other characters that extend a grapheme share the cluster of its base.
+    MonotoneGraphemes,
+    /// Every character that has a glyph of its own has its own cluster.
+    #[default]
+    MonotoneCharacters,
+    /// Like `MonotoneCharacters`, but clusters of a run can be out of order, e.g. in right to
+    /// left text where glyphs were reordered.
+    Characters,
+}
+
+impl ClusterLevel {
+    fn to_buffer_cluster_level(self) -> hb::BufferClusterLevel {
+        match self {
+            ClusterLevel::MonotoneGraphemes => hb::BufferClusterLevel::MonotoneGraphemes,
+            ClusterLevel::MonotoneCharacters => hb::BufferClusterLevel::MonotoneCharacters,
+            ClusterLevel::Characters => hb::BufferClusterLevel::Characters,
+        }
+    }
+}
+
+/// What to draw combining marks on that have no base to combine with, e.g. at the start of a
+/// paragraph or after a space, see [`LayoutOptions::dotted_circles`]. Break opportunities don't
+/// change it: runs are shaped knowing the text around them.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum DottedCircles {
+    /// Leave it to the shaper, which inserts a dotted circle (U+25CC) into broken clusters of
+    /// scripts like Devanagari and draws other marks on nothing.
+    #[default]
+    Shaper,
+    /// Never insert a dotted circle.
+    Suppress,
+    /// Insert a dotted circle before every mark without a base, in the mark's cluster, if the
+    /// font has one.
+    Always,
+}
+
+/// Font metric kept at the same fraction of the size in every font, so that text in different
+/// fonts at the same size looks equally big.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SizeNormalization {
+    /// The size is the em size, like in CSS.
+    #[default]
+    None,
+    /// Capital letters are 0.7 of the size tall.
+    CapHeight,
+    /// Lowercase letters are half the size tall.
+    XHeight,
+}
+
+/// How the pen position of every glyph is quantized. Snapped glyphs look crisper, but the
+/// rounding makes the gaps between them uneven. Only the glyphs move: the pen keeps going from
+/// where they would have been, so errors don't add up along a line.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Positioning {
+    /// Glyphs are drawn at fractional positions, anti-aliased.
+    #[default]
+    Subpixel,
+    /// Glyphs are moved to a whole pixel horizontally.
+    SnapX,
+    /// Glyphs are moved to a whole pixel both horizontally and vertically.
+    SnapXy,
+}
+
+impl Positioning {
+    /// `offset` changed so that it moves a glyph with its pen at `origin` to a quantized
+    /// position.
+    fn snap(self, offset: DVec2, origin: DVec2) -> DVec2 {
+        let position = offset + origin;
+        match self {
+            Positioning::Subpixel => offset,
+            Positioning::SnapX => DVec2::new(position.x.round() - origin.x, offset.y),
+            Positioning::SnapXy => position.round() - origin,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum VerticalAlignment {
+    #[default]
+    Normal,
+    Reverse,
+    Center,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum WritingMode {
+    #[default]
+    HorizontalTb,
+    /// Lines run top to bottom and are stacked from right to left. The alignments keep their
+    /// meaning relative to the lines: `horizontal_alignment` applies along a column and
+    /// `vertical_alignment` to the block of columns, starting from the right edge.
+    VerticalRl,
+}
+
+/// Orientation of glyphs in vertical text, like the CSS `text-orientation` property.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TextOrientation {
+    /// East Asian wide characters are upright, everything else is rotated 90° clockwise and
+    /// centered on the column.
+    #[default]
+    Mixed,
+    Upright,
+    Sideways,
+}
+
+/// Everything that controls how a piece of text is laid out, except the text itself.
+///
+/// Either fill in the fields on top of `..Default::default()` or chain the setters starting
+/// from [`LayoutOptions::new`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayoutOptions {
+    pub x: i32,
+    pub y: i32,
+    pub w: i32,
+    pub h: i32,
+    /// Margins of the text inside the box.
+    pub insets: Insets,
+    pub size: usize,
+    /// How `size` maps to each font's units, see [`SizeNormalization`].
+    pub size_normalization: SizeNormalization,
+    /// Parts of the text drawn at another size than `size`. Lines are pushed apart so that the
+    /// tallest glyphs on them don't run into their neighbours.
+    pub size_ranges: Vec<SizedRange>,
+    /// Font of each paragraph, paragraphs without an entry use `fallback_font`.
+    pub paragraphs_fonts: Vec<FontId>,
+    pub fallback_font: FontId,
+    pub horizontal_alignment: HorizontalAlignment,
+    /// Alignment of each paragraph, paragraphs without an entry use `horizontal_alignment`.
+    pub paragraphs_alignment: Vec<HorizontalAlignment>,
+    /// Longest line of each paragraph, paragraphs without an entry or with `None` use all the room
+    /// of the box. Only makes lines shorter: the paragraph is still aligned across the whole box.
+    pub paragraphs_max_width: Vec<Option<f64>>,
+    /// Only used with [`HorizontalAlignment::Justify`].
+    pub last_line_alignment: LastLineAlignment,
+    pub vertical_alignment: VerticalAlignment,
+    /// Bidi embedding level of every paragraph, odd for right-to-left, instead of the direction
+    /// of its first strong character. Set it to the level of the surrounding text when the
+    /// layout is embedded in it, so that text with no strong characters, like punctuation, runs
+    /// in the same direction. Levels above 125 are ignored.
+    pub base_level: Option<u8>,
+    /// BCP 47 language tag handed to the shaper. For languages that write numbers with
+    /// Arabic-Indic digits (`ar`, `fa`, `ur`) ASCII digits are displayed in the native form as
+    /// long as the font has glyphs for it.
+    pub language: Option<String>,
+    /// Shape every tab as this many spaces instead of with the font's glyph for it.
+    pub soft_tabs: Option<usize>,
+    /// Stops tabs advance to, in any order. A tab goes to the first stop past it and keeps its
+    /// own advance when there is none, or when the text after it doesn't fit before the stop.
+    /// Only used by left-to-right paragraphs of horizontal text that aren't justified, and lines
+    /// are still broken as if every tab had its own advance.
+    pub tab_stops: Vec<TabStop>,
+    /// OpenType features turned on or off for parts of the text, e.g. small caps on a phrase.
+    pub features: Vec<FeatureRange>,
+    /// Parts of the text shaped in another case, like CSS `text-transform`. Glyphs keep the
+    /// clusters of the characters they were mapped from, so carets and hit tests still land in
+    /// the source text.
+    pub text_transforms: Vec<TextTransformRange>,
+    /// How much of the source the shaper merges into one [`LayoutGlyph::cluster`].
+    pub cluster_level: ClusterLevel,
+    /// Whether marks without a base are drawn on a dotted circle.
+    pub dotted_circles: DottedCircles,
+    /// Fonts for the characters of a script the paragraph's font doesn't have, e.g. Hebrew
+    /// falling back to `noto` in a Latin paragraph.
+    pub script_fonts: HashMap<Script, FontId>,
+    /// Stop shaping once this many glyphs are shaped, leaving the rest of the text out and
+    /// setting [`Layout::truncated`]. Guards against inputs too long to lay out in time.
+    pub max_glyphs: Option<usize>,
+    /// Shape each run of a font, size and direction in one go up to the next line separator,
+    /// instead of once for every fragment between break opportunities, which takes far fewer
+    /// shaping calls. Kerning then applies across break opportunities too, and ligatures across
+    /// them go to the fragment they start in.
+    pub merge_runs: bool,
+    /// Extra space in pixels between clusters. It only goes between glyphs, never before the start
+    /// or after the end of a line, and never between a base and its marks.
+    pub letter_spacing: f64,
+    /// Extra space in pixels between two characters when they follow each other on a line, in
+    /// reading order, on top of the font's kerning: negative to tighten a pair like `AV`. Like
+    /// letter spacing it only goes between clusters, and it isn't added across break
+    /// opportunities or where the font changes.
+    pub kerning_overrides: HashMap<(char, char), f64>,
+    /// Factor the advances and the spacing between glyphs are multiplied by, to condense text
+    /// below 1 or expand it above, in fonts without a width axis. Only horizontal text is
+    /// scaled.
+    pub horizontal_scale: f64,
+    /// Scale the outlines and the shaper's offsets along with the advances instead of keeping
+    /// the glyphs at the font's width, see [`LayoutOptions::horizontal_scale`].
+    pub horizontal_scale_outlines: bool,
+    /// Source ranges to draw a background behind, see [`Layout::highlights`].
+    pub highlights: Vec<Highlight>,
+    /// Source ranges to underline or strike through, see [`Layout::decorations`].
+    pub decorations: Vec<Decoration>,
+    /// Source ranges to fill with another color, see [`LayoutGlyph::color`].
+    pub colors: Vec<ColorRange>,
+    /// Opacity ramps the glyphs are faded by, see [`LayoutGlyph::opacity`].
+    pub fades: Vec<Fade>,
+    /// Moves and turns every glyph a little, see [`Jitter`]. Highlights, decorations and
+    /// skeleton boxes stay where the glyphs were before.
+    pub jitter: Option<Jitter>,
+    /// Count the first line as only the font's ascent tall instead of a whole line height, so the
+    /// block is shorter by the difference like with CSS `text-box-trim`. Top aligned text starts
+    /// at the ascent either way, but centered and bottom aligned text moves and less height is
+    /// needed to fit. Only horizontal text is trimmed.
+    pub leading_trim: bool,
+    /// Make every line this many times its largest font size tall, like a unitless CSS
+    /// `line-height`, instead of the height of the font at `size`. The leading beyond the
+    /// line's ascent and descent is split evenly above and below it, so a line with bigger text
+    /// gets proportionally more. Only horizontal text is spaced this way.
+    pub line_height_ratio: Option<f64>,
+    /// Round the baseline of every line to a whole pixel so small text isn't blurred. Lines are
+    /// still stepped by the exact line height so no error accumulates.
+    pub pixel_snap: bool,
+    /// Whether glyphs are drawn where they are or moved to the nearest whole pixel.
+    pub positioning: Positioning,
+    /// Round every glyph advance, letter spacing included, to a whole pixel like hinted text, so
+    /// small text is evenly spaced. Lines are broken with the rounded advances, and glyphs of
+    /// lines starting on a whole pixel all stay on whole pixels as long as fragments aren't
+    /// spread apart by a fractional letter spacing or justification.
+    pub round_advances: bool,
+    pub line_breaking: LineBreaking,
+    /// Fewest words, the pieces lines are broken between, the last line of a wrapped paragraph
+    /// should have. Lines with fewer take words from the line before as long as the paragraph
+    /// doesn't get longer, so that a word isn't left alone on the last line. Balanced paragraphs
+    /// (see [`LineBreaking::Balance`]) are balanced first. Only the last line is checked, and
+    /// there is no largest number of words or limit in glyphs.
+    pub min_last_line_words: usize,
+    /// Leave out the empty paragraphs the text ends with, like the second one of `"Fin.\n\n"`,
+    /// instead of drawing them as blank lines. The separator ending the last paragraph never
+    /// starts another line of its own either way.
+    pub drop_trailing_empty_paragraphs: bool,
+    /// Report how the lines were broken in [`Layout::break_diagnostics`], to debug line
+    /// breaking. Every paragraph has its `fragments` with their `text`, `width` and `x`, the
+    /// sum of the widths before them, and its `lines` with the index of their
+    /// `first_fragment`, their `length` and `max_length`, and the `reason` they start there: the
+    /// `start` of the paragraph, a `mandatory_break`, an `overflow` of the line before or
+    /// `balance` for breaks made early to even the lines out or keep words off the last line.
+    pub break_diagnostics: bool,
+    /// Only keep the glyphs whose outline intersects this rectangle.
+    pub clip: Option<Rect>,
+    /// Areas the text flows around, like an image floated into it. Lines running into one that
+    /// is on the left half of the text start after it, and lines running into one on the right
+    /// half end before it. Lines are taken to be a line height apart from the top of the box, so
+    /// they are exact for top aligned text with nothing taller than the font in it. Vertical
+    /// text doesn't flow around anything.
+    pub exclusions: Vec<Rect>,
+    /// Placeholder boxes to lay out in [`Layout::skeleton`]. Glyph outlines aren't built then.
+    pub skeleton: Skeleton,
+    /// What to draw for glyphs the font has no outline for.
+    pub empty_outlines: EmptyOutlines,
+    /// Scale of the SVG paths for high-DPI screens. Positions, advances and every other metric
+    /// stay in logical pixels, so the text is laid out exactly as with a ratio of 1.
+    pub device_pixel_ratio: f64,
+    /// Write the coordinates of the SVG paths with this many decimals, e.g. to compare output
+    /// with a golden file. Glyphs always come in the same order, so the same input then gives
+    /// the same paths and [`Layout::to_svg`] documents byte for byte on every platform, as long
+    /// as documents with [`SvgOptions::use_defs`] get the same precision.
+    pub path_precision: Option<usize>,
+    /// Draw the curves of the SVG paths as lines that stay within this many pixels of them,
+    /// for backends that can only draw polylines. Paths are flattened before they are rounded
+    /// to [`LayoutOptions::path_precision`]. Tolerances below 0.001 pixels, 0 and negative ones
+    /// included, are taken as 0.001.
+    pub flatten_tolerance: Option<f64>,
+    /// `fill-rule` the glyph paths are to be filled with, see [`Layout::fill_rule`].
+    pub fill_rule: FillRule,
+    /// Affine transform `[a, b, c, d, e, f]` like SVG's `matrix()` that the glyphs, ruby
+    /// included, are moved by once they are placed, e.g. to rotate the block. Lines are broken
+    /// and clipped untransformed, and lines, highlights and the other boxes aren't moved.
+    pub transform: Option<[f64; 6]>,
+    /// Whether y grows downwards in the output like in SVG, or upwards like in OpenGL. Y-up
+    /// layouts are mirrored about the x axis, so every y is negated, while the box, clip and
+    /// exclusions are still given with y down.
+    pub y_down: bool,
+    /// Byte offsets in the text where a line may break on top of the ones the segmenter finds.
+    pub break_opportunities: Vec<usize>,
+    /// Byte offsets where a line must not break, even if the segmenter allows it.
+    pub suppressed_breaks: Vec<usize>,
+    /// Annotations drawn smaller and centered above their base text, see [`Layout::ruby_glyphs`].
+    /// Every line gets taller to make room for them. Only horizontal text supports ruby.
+    pub ruby: Vec<Ruby>,
+    pub writing_mode: WritingMode,
+    /// Only used by vertical writing modes.
+    pub text_orientation: TextOrientation,
 }
 
-#[wasm_bindgen]
-pub fn get_paths(x: i32, y: i32, w: i32, h: i32, size: usize, input: usize) -> Vec<String> {
-    let state = app_state();
-    let input_transform = InputTransform { x, y, w, h, size };
+impl Default for LayoutOptions {
+    fn default() -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            w: 600,
+            h: 400,
+            insets: Insets::default(),
+            size: 16,
+            size_normalization: SizeNormalization::default(),
+            size_ranges: vec![],
+            paragraphs_fonts: vec![],
+            fallback_font: GLOBAL_FALLBACK_FONT.into(),
+            horizontal_alignment: HorizontalAlignment::default(),
+            paragraphs_alignment: vec![],
+            paragraphs_max_width: vec![],
+            last_line_alignment: LastLineAlignment::default(),
+            vertical_alignment: VerticalAlignment::default(),
+            base_level: None,
+            language: None,
+            soft_tabs: None,
+            tab_stops: vec![],
+            features: vec![],
+            text_transforms: vec![],
+            cluster_level: ClusterLevel::default(),
+            dotted_circles: DottedCircles::default(),
+            script_fonts: HashMap::new(),
+            max_glyphs: None,
+            merge_runs: false,
+            letter_spacing: 0.0,
+            kerning_overrides: HashMap::new(),
+            horizontal_scale: 1.0,
+            horizontal_scale_outlines: false,
+            highlights: vec![],
+            decorations: vec![],
+            colors: vec![],
+            fades: vec![],
+            jitter: None,
+            leading_trim: false,
+            line_height_ratio: None,
+            pixel_snap: false,
+            positioning: Positioning::default(),
+            round_advances: false,
+            line_breaking: LineBreaking::default(),
+            min_last_line_words: 0,
+            drop_trailing_empty_paragraphs: false,
+            break_diagnostics: false,
+            clip: None,
+            exclusions: vec![],
+            skeleton: Skeleton::default(),
+            empty_outlines: EmptyOutlines::default(),
+            device_pixel_ratio: 1.0,
+            path_precision: None,
+            flatten_tolerance: None,
+            fill_rule: FillRule::default(),
+            transform: None,
+            y_down: true,
+            break_opportunities: vec![],
+            suppressed_breaks: vec![],
+            ruby: vec![],
+            writing_mode: WritingMode::default(),
+            text_orientation: TextOrientation::default(),
+        }
+    }
+}
+
+impl LayoutOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The text box, in pixels.
+    pub fn bounds(mut self, x: i32, y: i32, w: i32, h: i32) -> Self {
+        self.x = x;
+        self.y = y;
+        self.w = w;
+        self.h = h;
+        self
+    }
+
+    pub fn size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    pub fn size_normalization(mut self, normalization: SizeNormalization) -> Self {
+        self.size_normalization = normalization;
+        self
+    }
+
+    /// Draws `start..end` at `size`, can be called several times.
+    pub fn size_range(mut self, start: usize, end: usize, size: usize) -> Self {
+        self.size_ranges.push(SizedRange { start, end, size });
+        self
+    }
+
+    pub fn paragraphs_fonts(mut self, fonts: Vec<FontId>) -> Self {
+        self.paragraphs_fonts = fonts;
+        self
+    }
+
+    pub fn fallback_font(mut self, font: impl Into<FontId>) -> Self {
+        self.fallback_font = font.into();
+        self
+    }
+
+    pub fn horizontal_alignment(mut self, alignment: HorizontalAlignment) -> Self {
+        self.horizontal_alignment = alignment;
+        self
+    }
+
+    pub fn paragraphs_alignment(mut self, alignments: Vec<HorizontalAlignment>) -> Self {
+        self.paragraphs_alignment = alignments;
+        self
+    }
+
+    pub fn paragraphs_max_width(mut self, widths: Vec<Option<f64>>) -> Self {
+        self.paragraphs_max_width = widths;
+        self
+    }
+
+    pub fn last_line_alignment(mut self, alignment: LastLineAlignment) -> Self {
+        self.last_line_alignment = alignment;
+        self
+    }
+
+    pub fn vertical_alignment(mut self, alignment: VerticalAlignment) -> Self {
+        self.vertical_alignment = alignment;
+        self
+    }
+
+    pub fn base_level(mut self, level: u8) -> Self {
+        self.base_level = Some(level);
+        self
+    }
+
+    pub fn language(mut self, language: impl Into<String>) -> Self {
+        self.language = Some(language.into());
+        self
+    }
+
+    pub fn soft_tabs(mut self, spaces: usize) -> Self {
+        self.soft_tabs = Some(spaces);
+        self
+    }
+
+    /// Adds a stop `position` pixels from the start of the line, can be called several times.
+    pub fn tab_stop(mut self, position: f64, alignment: TabAlignment) -> Self {
+        self.tab_stops.push(TabStop {
+            position,
+            alignment,
+        });
+        self
+    }
+
+    pub fn cluster_level(mut self, level: ClusterLevel) -> Self {
+        self.cluster_level = level;
+        self
+    }
+
+    pub fn dotted_circles(mut self, dotted_circles: DottedCircles) -> Self {
+        self.dotted_circles = dotted_circles;
+        self
+    }
+
+    pub fn feature(mut self, start: usize, end: usize, tag: impl Into<String>, value: u32) -> Self {
+        self.features.push(FeatureRange {
+            start,
+            end,
+            tag: tag.into(),
+            value,
+        });
+        self
+    }
+
+    /// Shapes `start..end` in another case, can be called several times.
+    pub fn text_transform(mut self, start: usize, end: usize, transform: TextTransform) -> Self {
+        self.text_transforms.push(TextTransformRange {
+            start,
+            end,
+            transform,
+        });
+        self
+    }
+
+    pub fn script_font(mut self, script: Script, font: &str) -> Self {
+        self.script_fonts.insert(script, font.into());
+        self
+    }
+
+    pub fn max_glyphs(mut self, count: usize) -> Self {
+        self.max_glyphs = Some(count);
+        self
+    }
+
+    pub fn merge_runs(mut self, merge_runs: bool) -> Self {
+        self.merge_runs = merge_runs;
+        self
+    }
+
+    pub fn letter_spacing(mut self, spacing: f64) -> Self {
+        self.letter_spacing = spacing;
+        self
+    }
+
+    /// Adds `delta` pixels between `left` and `right`, can be called several times.
+    pub fn kerning_override(mut self, left: char, right: char, delta: f64) -> Self {
+        self.kerning_overrides.insert((left, right), delta);
+        self
+    }
+
+    pub fn horizontal_scale(mut self, scale: f64) -> Self {
+        self.horizontal_scale = scale;
+        self
+    }
+
+    pub fn horizontal_scale_outlines(mut self, scale_outlines: bool) -> Self {
+        self.horizontal_scale_outlines = scale_outlines;
+        self
+    }
+
+    /// Adds a highlight, can be called several times.
+    pub fn highlight(mut self, start: usize, end: usize, color: impl Into<String>) -> Self {
+        self.highlights.push(Highlight {
+            start,
+            end,
+            color: color.into(),
+        });
+        self
+    }
+
+    /// Adds an underline or a line-through, can be called several times.
+    pub fn decoration(
+        mut self,
+        start: usize,
+        end: usize,
+        line: DecorationLine,
+        color: impl Into<String>,
+    ) -> Self {
+        self.decorations.push(Decoration {
+            start,
+            end,
+            line,
+            color: color.into(),
+        });
+        self
+    }
+
+    /// Colors the glyphs of `start..end`, can be called several times.
+    pub fn color(mut self, start: usize, end: usize, color: impl Into<String>) -> Self {
+        self.colors.push(ColorRange {
+            start,
+            end,
+            color: color.into(),
+        });
+        self
+    }
+
+    /// Jitters the glyphs, see [`Jitter`], replacing any jitter set before.
+    pub fn jitter(mut self, amplitude: f64, rotation: f64, seed: u64) -> Self {
+        self.jitter = Some(Jitter {
+            amplitude,
+            rotation,
+            seed,
+        });
+        self
+    }
+
+    /// Fades the glyphs along `axis` from transparent at `transparent` to opaque at `opaque`,
+    /// can be called several times.
+    pub fn fade(mut self, axis: FadeAxis, transparent: f64, opaque: f64) -> Self {
+        self.fades.push(Fade {
+            axis,
+            transparent,
+            opaque,
+        });
+        self
+    }
+
+    pub fn skeleton(mut self, skeleton: Skeleton) -> Self {
+        self.skeleton = skeleton;
+        self
+    }
+
+    pub fn empty_outlines(mut self, empty_outlines: EmptyOutlines) -> Self {
+        self.empty_outlines = empty_outlines;
+        self
+    }
+
+    pub fn leading_trim(mut self, leading_trim: bool) -> Self {
+        self.leading_trim = leading_trim;
+        self
+    }
+
+    pub fn line_height_ratio(mut self, ratio: f64) -> Self {
+        self.line_height_ratio = Some(ratio);
+        self
+    }
+
+    pub fn pixel_snap(mut self, pixel_snap: bool) -> Self {
+        self.pixel_snap = pixel_snap;
+        self
+    }
+
+    pub fn positioning(mut self, positioning: Positioning) -> Self {
+        self.positioning = positioning;
+        self
+    }
+
+    pub fn round_advances(mut self, round_advances: bool) -> Self {
+        self.round_advances = round_advances;
+        self
+    }
+
+    pub fn line_breaking(mut self, line_breaking: LineBreaking) -> Self {
+        self.line_breaking = line_breaking;
+        self
+    }
+
+    pub fn min_last_line_words(mut self, words: usize) -> Self {
+        self.min_last_line_words = words;
+        self
+    }
+
+    pub fn drop_trailing_empty_paragraphs(mut self, drop: bool) -> Self {
+        self.drop_trailing_empty_paragraphs = drop;
+        self
+    }
+
+    pub fn break_diagnostics(mut self, break_diagnostics: bool) -> Self {
+        self.break_diagnostics = break_diagnostics;
+        self
+    }
+
+    pub fn clip(mut self, clip: Rect) -> Self {
+        self.clip = Some(clip);
+        self
+    }
+
+    /// Adds an area to flow around, can be called several times.
+    pub fn exclusion(mut self, exclusion: Rect) -> Self {
+        self.exclusions.push(exclusion);
+        self
+    }
+
+    pub fn device_pixel_ratio(mut self, ratio: f64) -> Self {
+        self.device_pixel_ratio = ratio;
+        self
+    }
+
+    pub fn path_precision(mut self, decimals: usize) -> Self {
+        self.path_precision = Some(decimals);
+        self
+    }
+
+    pub fn flatten_tolerance(mut self, tolerance: f64) -> Self {
+        self.flatten_tolerance = Some(tolerance);
+        self
+    }
+
+    pub fn fill_rule(mut self, fill_rule: FillRule) -> Self {
+        self.fill_rule = fill_rule;
+        self
+    }
+
+    pub fn transform(mut self, transform: [f64; 6]) -> Self {
+        self.transform = Some(transform);
+        self
+    }
+
+    pub fn y_down(mut self, y_down: bool) -> Self {
+        self.y_down = y_down;
+        self
+    }
+
+    pub fn break_opportunities(mut self, offsets: Vec<usize>) -> Self {
+        self.break_opportunities = offsets;
+        self
+    }
+
+    pub fn suppressed_breaks(mut self, offsets: Vec<usize>) -> Self {
+        self.suppressed_breaks = offsets;
+        self
+    }
+
+    /// Adds a ruby annotation, can be called several times.
+    pub fn ruby(mut self, start: usize, end: usize, text: impl Into<String>) -> Self {
+        self.ruby.push(Ruby {
+            start,
+            end,
+            text: text.into(),
+        });
+        self
+    }
+
+    pub fn writing_mode(mut self, writing_mode: WritingMode) -> Self {
+        self.writing_mode = writing_mode;
+        self
+    }
+
+    pub fn text_orientation(mut self, orientation: TextOrientation) -> Self {
+        self.text_orientation = orientation;
+        self
+    }
+
+    pub fn insets(mut self, top: f64, right: f64, bottom: f64, left: f64) -> Self {
+        self.insets = Insets {
+            top,
+            right,
+            bottom,
+            left,
+        };
+        self
+    }
+
+    /// The box without its insets, where the text goes.
+    fn content_box(&self) -> Rect {
+        let Insets {
+            top,
+            right,
+            bottom,
+            left,
+        } = self.insets;
+
+        Rect {
+            x: self.x as f64 + left,
+            y: self.y as f64 + top,
+            w: self.w as f64 - left - right,
+            h: self.h as f64 - top - bottom,
+        }
+    }
+}
+
+/// Space in pixels kept free between the text and each edge of the box, 12 on every side by
+/// default.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Insets {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+impl Default for Insets {
+    fn default() -> Self {
+        Self {
+            top: PAD,
+            right: PAD,
+            bottom: PAD,
+            left: PAD,
+        }
+    }
+}
+
+/// Background color for the byte range `start..end` of the text, e.g. for search results.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Highlight {
+    pub start: usize,
+    pub end: usize,
+    /// Any CSS color.
+    pub color: String,
+}
+
+/// Line drawn along the glyphs of the byte range `start..end` of the text. Only horizontal text
+/// is decorated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoration {
+    pub start: usize,
+    pub end: usize,
+    pub line: DecorationLine,
+    /// Any CSS color.
+    pub color: String,
+}
+
+/// Where a [`Decoration`] goes, at the position and thickness the paragraph's font asks for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DecorationLine {
+    /// Below the baseline and behind the glyphs.
+    Underline,
+    /// Through the middle of lowercase letters and in front of the glyphs.
+    LineThrough,
+}
+
+/// Fill color for the glyphs of the byte range `start..end` of the text, e.g. for syntax
+/// highlighting. Later ranges win where they overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColorRange {
+    pub start: usize,
+    pub end: usize,
+    /// Any CSS color.
+    pub color: String,
+}
+
+/// Linear opacity ramp over the coordinates between `transparent` and `opaque` on `axis`, e.g. to
+/// fade text out at the edges of a scrolling view. Glyphs past `transparent` are invisible and
+/// glyphs past `opaque` aren't faded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fade {
+    pub axis: FadeAxis,
+    pub transparent: f64,
+    pub opaque: f64,
+}
+
+/// Small pseudo-random offsets and rotations of every glyph, e.g. to make text look handwritten.
+/// They only depend on the seed and the glyph's index in [`Layout::glyphs`], followed by
+/// [`Layout::ruby_glyphs`], so the same text is always jittered the same way.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Jitter {
+    /// Largest distance in pixels a glyph is moved along each axis.
+    pub amplitude: f64,
+    /// Largest angle in degrees a glyph is turned by, about the center of its bounds.
+    pub rotation: f64,
+    pub seed: u64,
+}
+
+impl Jitter {
+    /// Offset and rotation of the glyph at `index` as a transform in the text box.
+    fn transform(&self, index: usize, bounds: &Rect) -> DAffine2 {
+        let noise = |i: u64| jitter_noise(self.seed, 3 * index as u64 + i);
+        let center = DVec2::new(bounds.x + bounds.w / 2.0, bounds.y + bounds.h / 2.0);
+        let offset = DVec2::new(noise(0), noise(1)) * self.amplitude;
+
+        DAffine2::from_translation(center + offset)
+            * DAffine2::from_angle((noise(2) * self.rotation).to_radians())
+            * DAffine2::from_translation(-center)
+    }
+}
+
+/// Pseudo-random number between -1 and 1 for `index`, SplitMix64 of the seed and the index.
+fn jitter_noise(seed: u64, index: u64) -> f64 {
+    const GOLDEN_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+    let mut z = seed.wrapping_add(index.wrapping_add(1).wrapping_mul(GOLDEN_GAMMA));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+
+    (z >> 11) as f64 / (1_u64 << 53) as f64 * 2.0 - 1.0
+}
+
+/// How paragraphs are split into lines, see [`LayoutOptions::line_breaking`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineBreaking {
+    /// Put as much on every line as fits.
+    #[default]
+    Greedy,
+    /// Keep the number of lines greedy breaking needs but make them as even as possible, like
+    /// CSS `text-wrap: balance` for headings. Only paragraphs of a few lines without mandatory
+    /// breaks inside them are balanced, the others are broken greedily.
+    Balance,
+}
+
+/// What the placeholder boxes of a loading state stand for, see [`LayoutOptions::skeleton`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Skeleton {
+    /// Draw the real glyphs.
+    #[default]
+    Off,
+    /// A box as long as each line.
+    Lines,
+    /// A box around each visually contiguous piece of a word.
+    Words,
+}
+
+/// What to do with glyphs that have no outline, like spaces or glyphs the font only has as
+/// bitmaps, which aren't drawn at all, see [`LayoutOptions::empty_outlines`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum EmptyOutlines {
+    /// Keep them with an empty path.
+    #[default]
+    Keep,
+    /// Leave them out of [`Layout::glyphs`]. Lines keep their width, but the skipped characters
+    /// can't be hit or placed a caret at.
+    Skip,
+    /// Draw a box as wide as the glyph's advance and as tall as the font's ascent instead, unless
+    /// the glyph stands for whitespace.
+    Placeholder,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FadeAxis {
+    #[default]
+    X,
+    Y,
+}
+
+impl Fade {
+    /// Opacity of a glyph centered at `position`.
+    fn opacity(&self, position: DVec2) -> f64 {
+        let position = match self.axis {
+            FadeAxis::X => position.x,
+            FadeAxis::Y => position.y,
+        };
+        if self.transparent == self.opaque {
+            return 1.0;
+        }
+
+        ((position - self.transparent) / (self.opaque - self.transparent)).clamp(0.0, 1.0)
+    }
+}
+
+/// Font size for the byte range `start..end` of the text. Later ranges win where they overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizedRange {
+    pub start: usize,
+    pub end: usize,
+    pub size: usize,
+}
+
+/// Where the text after a tab goes, `position` pixels from the start of the line, see
+/// [`LayoutOptions::tab_stops`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TabStop {
+    pub position: f64,
+    pub alignment: TabAlignment,
+}
+
+/// What part of the text between a tab and the next one, or the end of the line, lines up with
+/// its [`TabStop`].
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum TabAlignment {
+    /// The text starts at the stop.
+    #[default]
+    Left,
+    Center,
+    /// The text ends at the stop.
+    Right,
+    /// The first `.` of the text is at the stop, text without one ends there like with `Right`.
+    Decimal,
+}
+
+/// OpenType feature `tag`, e.g. `smcp`, set to `value` for the byte range `start..end` of the
+/// text. A value of 0 turns the feature off, alternates are picked with larger values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FeatureRange {
+    pub start: usize,
+    pub end: usize,
+    pub tag: String,
+    pub value: u32,
+}
+
+/// Case the characters of a [`TextTransformRange`] are shaped in, mapped with the full Unicode
+/// case mappings for [`LayoutOptions::language`], so `ß` is uppercased to `SS` and a Turkish
+/// `i` to `İ`. Characters are mapped one at a time, without the context a final sigma needs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextTransform {
+    Uppercase,
+    Lowercase,
+    /// Titlecase the first letter of every word, leaving the others as they are.
+    Capitalize,
+}
+
+impl TextTransform {
+    /// `c` in this case, `word_start` telling whether it's the first letter of a word.
+    fn apply(self, c: char, word_start: bool, language: &icu::locid::LanguageIdentifier) -> String {
+        use icu::casemap::titlecase::TitlecaseOptions;
+        use icu::casemap::CaseMapper;
+
+        let case_mapper = CaseMapper::new();
+        let mut buffer = [0; 4];
+        let c = &*c.encode_utf8(&mut buffer);
+        match self {
+            TextTransform::Uppercase => case_mapper.uppercase_to_string(c, language),
+            TextTransform::Lowercase => case_mapper.lowercase_to_string(c, language),
+            TextTransform::Capitalize if word_start => case_mapper
+                .titlecase_segment_with_only_case_data_to_string(
+                    c,
+                    language,
+                    TitlecaseOptions::default(),
+                ),
+            TextTransform::Capitalize => c.into(),
+        }
+    }
+}
+
+/// Case for the byte range `start..end` of the text. Later ranges win where they overlap.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextTransformRange {
+    pub start: usize,
+    pub end: usize,
+    pub transform: TextTransform,
+}
+
+/// Annotation for the byte range `start..end` of the text, like furigana over kanji.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ruby {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rect {
+    pub x: f64,
+    pub y: f64,
+    pub w: f64,
+    pub h: f64,
+}
+
+impl Rect {
+    /// Whether the rectangles overlap, touching edges included.
+    pub fn intersects(&self, other: &Rect) -> bool {
+        self.x <= other.x + other.w
+            && other.x <= self.x + self.w
+            && self.y <= other.y + other.h
+            && other.y <= self.y + self.h
+    }
+
+    /// Smallest rectangle containing both.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        Rect {
+            x,
+            y,
+            w: (self.x + self.w).max(other.x + other.w) - x,
+            h: (self.y + self.h).max(other.y + other.h) - y,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HighlightRect {
+    pub rect: Rect,
+    pub color: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DecorationRect {
+    pub rect: Rect,
+    pub line: DecorationLine,
+    pub color: String,
+}
+
+/// Part of the text drawn with the same font and color, see [`Layout::styled_runs`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct StyledRun {
+    /// Source bytes of the run, in logical order.
+    pub source: std::ops::Range<usize>,
+    pub font: FontId,
+    /// See [`LayoutGlyph::color`].
+    pub color: Option<String>,
+}
+
+/// Glyphs drawn the same at regular intervals along a line, see [`Layout::repeat_runs`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RepeatRun {
+    /// Index in [`Layout::glyphs`] of the first glyph, the others follow it.
+    pub glyph: usize,
+    pub count: usize,
+    /// From each pen position to the next, zero for runs of one glyph.
+    pub step: DVec2,
+}
+
+/// Something [`validate_input`] found that layout would have to work around.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning {
+    /// No font is registered with the id, text in it is drawn with the fallback font, or with
+    /// the default one when the fallback font is missing too.
+    UnknownFont(FontId),
+    /// The text isn't valid UTF-8 from this byte on. Invalid bytes are drawn as U+FFFD.
+    InvalidUtf8(usize),
+    /// The tag of a [`LayoutOptions::features`] range isn't one to four printable ASCII
+    /// characters.
+    InvalidFeatureTag(String),
+    /// Neither the paragraph's font nor the [`LayoutOptions::script_fonts`] one has a glyph for
+    /// the character at `offset`, which is drawn as `.notdef`. Only the first one of every
+    /// script is reported.
+    UncoveredScript { script: Script, offset: usize },
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Warning::UnknownFont(font_id) => write!(f, "Font {} was not found!", font_id),
+            Warning::InvalidUtf8(offset) => {
+                write!(f, "The text isn't valid UTF-8 from byte {} on!", offset)
+            }
+            Warning::InvalidFeatureTag(tag) => {
+                write!(
+                    f,
+                    "Feature tag {:?} isn't one to four ASCII characters!",
+                    tag
+                )
+            }
+            Warning::UncoveredScript { script, offset } => write!(
+                f,
+                "No font has glyphs for the {} text at byte {}!",
+                Script::enum_to_long_name_mapper()
+                    .get(*script)
+                    .unwrap_or("Unknown"),
+                offset
+            ),
+        }
+    }
+}
+
+/// Two glyphs next to each other on a line that are displayed in the wrong order for their
+/// direction, see [`Layout::reordering_anomalies`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReorderingAnomaly {
+    pub line: usize,
+    /// Indices in [`Layout::glyphs`] of the glyph displayed first, on the left or at the top,
+    /// and of the one after it.
+    pub glyphs: (usize, usize),
+}
+
+/// A glyph of [`Layout::local_glyphs`] drawn from the top left corner of its bounds, e.g. to
+/// pack it into a texture atlas. Everything is in the coordinates of [`LayoutGlyph::path`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalGlyph {
+    /// Index of the glyph in [`Layout::glyphs`].
+    pub glyph: usize,
+    /// The path moved so that its smallest coordinates are 0.
+    pub local_path: String,
+    /// Where the pen position is in `local_path`, to line glyphs from the atlas up on a
+    /// baseline.
+    pub atlas_origin: DVec2,
+    /// Where the top left corner of `local_path` goes to draw the glyph where it was laid out.
+    pub place_x: f64,
+    pub place_y: f64,
+}
+
+/// A word of [`Layout::word_boxes`] with its source bytes `start..end`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WordBox {
+    pub start: usize,
+    pub end: usize,
+    pub rect: Rect,
+}
+
+/// A line of [`Layout::text_lines`], to draw with `<text>` elements instead of paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextLine<'a> {
+    /// What the line shows, see [`Layout::line_texts`].
+    pub text: &'a str,
+    /// Left end of the line on its baseline, see [`LayoutLine::x`]. Right-to-left lines start
+    /// `width` further right.
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub rtl: bool,
+}
+
+/// RGBA pixels of [`Layout::rasterize`], row by row from the top left corner, with straight
+/// (not premultiplied) alpha like PNG files have.
+#[cfg(feature = "raster")]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Raster {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Glyphs that differ between two layouts, see [`Layout::diff`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LayoutDiff {
+    /// Indices in the new [`Layout::glyphs`] of glyphs the old layout doesn't have.
+    pub added: Vec<usize>,
+    /// Indices in the old [`Layout::glyphs`] of glyphs the new layout doesn't have.
+    pub removed: Vec<usize>,
+    /// Old and new indices of glyphs both layouts have, but drawn differently: at another
+    /// position, or with another outline or transform, e.g. at another size.
+    pub moved: Vec<(usize, usize)>,
+}
+
+impl LayoutDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.moved.is_empty()
+    }
+}
+
+/// What [`Layout::paint_layers`] puts in one layer. Layers are painted in order, each one above
+/// the ones before it.
+#[derive(Debug, Clone)]
+pub enum PaintLayer<'l> {
+    Highlights(&'l [HighlightRect]),
+    Decorations(Vec<&'l DecorationRect>),
+    /// The glyphs of the lines and then the ruby glyphs.
+    Glyphs(Vec<&'l LayoutGlyph>),
+}
+
+/// Which parts of an outline with overlapping or nested contours are filled.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FillRule {
+    /// What fonts are drawn with: counters, like the inside of `O`, wind the other way than the
+    /// contour around them, and contours overlapping the same way, as in variable fonts, are
+    /// filled once.
+    #[default]
+    NonZero,
+    /// Every other nested or overlapping contour is a hole whichever way it winds.
+    EvenOdd,
+}
+
+impl FillRule {
+    /// Value of the SVG `fill-rule` property, also what canvas `fill()` takes.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FillRule::NonZero => "nonzero",
+            FillRule::EvenOdd => "evenodd",
+        }
+    }
+}
+
+/// How [`Layout::to_svg`] writes the document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SvgOptions {
+    /// Size of the document in pixels.
+    pub width: f64,
+    pub height: f64,
+    /// Any CSS color.
+    pub fill: String,
+    /// Put the glyphs of every paragraph in their own `<g>` with a `data-paragraph-index`.
+    pub group_paragraphs: bool,
+    /// Write every distinct outline once in `<defs>` and draw the glyphs as `<use>` elements
+    /// with their [`LayoutGlyph::transform`]. The outlines and transforms follow the three path
+    /// options below, so the glyphs come out where and as their paths are.
+    pub use_defs: bool,
+    /// [`LayoutOptions::device_pixel_ratio`], [`LayoutOptions::path_precision`] and
+    /// [`LayoutOptions::flatten_tolerance`] of the layout. Its paths are already written with
+    /// them, they are only needed for [`SvgOptions::use_defs`].
+    pub device_pixel_ratio: f64,
+    pub path_precision: Option<usize>,
+    pub flatten_tolerance: Option<f64>,
+    /// Give every glyph element `data-cluster` and `data-glyph-id` attributes with its
+    /// [`LayoutGlyph::cluster`] and [`LayoutGlyph::glyph_id`], to map events on it back to the
+    /// source text.
+    pub glyph_data: bool,
+}
+
+impl Default for SvgOptions {
+    fn default() -> Self {
+        Self {
+            width: 600.0,
+            height: 400.0,
+            fill: "black".into(),
+            group_paragraphs: false,
+            use_defs: false,
+            device_pixel_ratio: 1.0,
+            path_precision: None,
+            flatten_tolerance: None,
+            glyph_data: false,
+        }
+    }
+}
+
+/// A glyph placed in the text box.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LayoutGlyph {
+    pub glyph_id: u16,
+    /// Byte offset in the source text of the cluster this glyph belongs to.
+    pub cluster: usize,
+    /// Pen position the glyph was drawn at.
+    pub x: f64,
+    pub y: f64,
+    /// Offset of the outline from the pen position, as the shaper gave it.
+    pub offset: DVec2,
+    /// Advance along the line, downwards in vertical text.
+    pub advance: f64,
+    /// Advance along the line in font design units, as the shaper gave it: without letter
+    /// spacing and before scaling to `size`.
+    pub advance_units: i32,
+    /// The glyph comes from a buffer shaped right to left, whose glyphs are in visual order, the
+    /// reverse of the text.
+    pub rtl: bool,
+    /// Maps the outline from font units to the text box.
+    pub transform: DAffine2,
+    /// Box around the outline's points, empty at the pen position for glyphs without one.
+    pub bounds: Rect,
+    /// SVG path data of the outline.
+    pub path: String,
+    /// SVG path data of the outline in font units, `transform` maps it to `path`.
+    pub outline: String,
+    /// Color of the last [`LayoutOptions::colors`] range the cluster is in, glyphs outside all
+    /// of them are drawn with the default fill.
+    pub color: Option<String>,
+    /// Product of the [`LayoutOptions::fades`] at the center of the glyph's bounds.
+    pub opacity: f64,
+    /// Font the glyph is drawn with, which differs from the paragraph's for glyphs taken from
+    /// [`LayoutOptions::script_fonts`].
+    pub font: FontId,
+}
+
+impl LayoutGlyph {
+    /// Redraws the glyph from its outline with `transform`, the paths scaled by `scale`.
+    fn set_transform(&mut self, transform: DAffine2, scale: f64) {
+        let moved = transform * self.transform.inverse();
+        let pen = moved.transform_point2(DVec2::new(self.x, self.y));
+        let mut path = String::new();
+        let (mut min, mut max) = (DVec2::splat(f64::MAX), DVec2::splat(f64::MIN));
+
+        for cmd in PathCmd::parse(&self.outline) {
+            let cmd = cmd.map(|point| {
+                let point = transform.transform_point2(point);
+                (min, max) = (min.min(point), max.max(point));
+                point
+            });
+            cmd.map(|point| point * scale).write_svg(&mut path);
+        }
+
+        self.x = pen.x;
+        self.y = pen.y;
+        self.transform = transform;
+        self.bounds = if min.x <= max.x {
+            Rect {
+                x: min.x,
+                y: min.y,
+                w: max.x - min.x,
+                h: max.y - min.y,
+            }
+        } else {
+            Rect {
+                x: pen.x,
+                y: pen.y,
+                ..Default::default()
+            }
+        };
+        self.path = path;
+    }
+
+    /// Writes the path the way `options` asks for once the glyph doesn't move anymore, with the
+    /// curves flattened and then the numbers rounded.
+    fn finish_path(&mut self, options: &LayoutOptions) {
+        if let Some(tolerance) = options.flatten_tolerance {
+            self.path = flatten_path(&self.path, tolerance * options.device_pixel_ratio);
+        }
+        if let Some(decimals) = options.path_precision {
+            self.path = round_numbers(&self.path, decimals);
+        }
+    }
+
+    /// Mirrors the glyph about the x axis for y-up output.
+    fn mirror_y(&mut self, scale: f64) {
+        let (x, y) = (self.x, -self.y);
+        let flip = DAffine2::from_scale(DVec2::new(1.0, -1.0));
+        self.set_transform(flip * self.transform, scale);
+        // Negated exactly, set_transform maps the pen through the inverse of the old transform.
+        (self.x, self.y) = (x, y);
+        self.offset.y = -self.offset.y;
+    }
+}
+
+/// A line of text, or a column in vertical writing modes.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LayoutLine {
+    pub paragraph: usize,
+    /// The line's paragraph runs right to left.
+    pub rtl: bool,
+    /// Where the line starts on its baseline: its left end for horizontal lines, the top of the
+    /// column's center line for vertical ones.
+    pub x: f64,
+    pub y: f64,
+    /// Extent along the line.
+    pub width: f64,
+    /// Extent of the line box on each side of the baseline. Columns are centered on their
+    /// baseline, so both are half the column width. Y-up layouts swap the two, so `y - ascent`
+    /// is the smaller edge of the line box either way.
+    pub ascent: f64,
+    pub descent: f64,
+    /// The line ends in a mandatory break (e.g. `\n`) instead of being wrapped.
+    pub hard_break: bool,
+    /// Range of the line's glyphs in [`Layout::glyphs`].
+    pub glyphs: std::ops::Range<usize>,
+    /// Bytes of the source text on the line, with the whitespace it starts or ends with but
+    /// without the paragraph separator.
+    pub source: std::ops::Range<usize>,
+    /// Source bytes of the pieces of text between the break opportunities on the line in logical
+    /// order, roughly its words with the whitespace after them. See [`Layout::word_boxes`].
+    pub words: Vec<std::ops::Range<usize>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layout {
+    pub glyphs: Vec<LayoutGlyph>,
+    pub lines: Vec<LayoutLine>,
+    /// Rectangles to paint behind the glyphs for [`LayoutOptions::highlights`].
+    pub highlights: Vec<HighlightRect>,
+    /// Lines of [`LayoutOptions::decorations`], one for every visually contiguous piece of a
+    /// range on each line. See [`Layout::paint_layers`] for the order to paint them in.
+    pub decorations: Vec<DecorationRect>,
+    /// Glyphs of [`LayoutOptions::ruby`]. They don't belong to any line and their clusters are
+    /// offsets in the annotation instead of the text.
+    pub ruby_glyphs: Vec<LayoutGlyph>,
+    pub writing_mode: WritingMode,
+    /// [`LayoutOptions::fill_rule`], which [`Layout::to_svg`] and `Layout::rasterize` fill the
+    /// glyphs with and drawing code like a canvas `fill()` should pass along.
+    pub fill_rule: FillRule,
+    /// Distance between consecutive baselines, or the column width in vertical text.
+    pub line_height: f64,
+    /// The lines need more room than the box has across them: more height for horizontal text,
+    /// more width for vertical text.
+    pub overflow: bool,
+    /// Glyphs the fonts don't have and that are drawn as `.notdef` (tofu) instead.
+    pub notdef_count: usize,
+    /// Only part of the text is laid out because [`LayoutOptions::max_glyphs`] was reached.
+    pub truncated: bool,
+    /// Placeholder boxes for [`LayoutOptions::skeleton`], as tall as the line box.
+    pub skeleton: Vec<Rect>,
+    /// The text mixes bidi levels, see [`needs_reordering`].
+    pub needs_reordering: bool,
+    /// JSON with the fragments of every paragraph and why each line starts where it does, for
+    /// [`LayoutOptions::break_diagnostics`].
+    pub break_diagnostics: Option<String>,
+    /// How many times the shaper was called, paragraphs reused from the previous layout not
+    /// counted. See [`LayoutOptions::merge_runs`].
+    pub shaping_calls: usize,
+}
+
+impl Layout {
+    /// Mirrors everything about the x axis for [`LayoutOptions::y_down`] off, the paths scaled by
+    /// `scale`.
+    fn mirror_y(&mut self, scale: f64) {
+        let mirror = |rect: &mut Rect| rect.y = -rect.y - rect.h;
+
+        for glyph in self.glyphs.iter_mut().chain(self.ruby_glyphs.iter_mut()) {
+            glyph.mirror_y(scale);
+        }
+        for line in self.lines.iter_mut() {
+            line.y = -line.y;
+            std::mem::swap(&mut line.ascent, &mut line.descent);
+        }
+        self.highlights
+            .iter_mut()
+            .for_each(|highlight| mirror(&mut highlight.rect));
+        self.decorations
+            .iter_mut()
+            .for_each(|decoration| mirror(&mut decoration.rect));
+        self.skeleton.iter_mut().for_each(mirror);
+    }
+
+    /// Drops the glyphs outside `clip`. Lines are kept, their glyph ranges just shrink.
+    fn cull(&mut self, clip: &Rect) {
+        self.retain_glyphs(|glyph| glyph.bounds.intersects(clip));
+    }
+
+    /// Drops the glyphs `keep` returns false for, lines and their glyph ranges as in [`cull`].
+    ///
+    /// [`cull`]: Self::cull
+    fn retain_glyphs(&mut self, keep: impl Fn(&LayoutGlyph) -> bool) {
+        let mut glyphs = Vec::with_capacity(self.glyphs.len());
+        for line in self.lines.iter_mut() {
+            let first_glyph = glyphs.len();
+            glyphs.extend(
+                self.glyphs[line.glyphs.clone()]
+                    .iter()
+                    .filter(|glyph| keep(glyph))
+                    .cloned(),
+            );
+            line.glyphs = first_glyph..glyphs.len();
+        }
+        self.glyphs = glyphs;
+        self.ruby_glyphs.retain(keep);
+    }
+
+    /// Everything there is to paint, back to front: highlights, then underlines, then glyphs and
+    /// then line-throughs. Layers with nothing in them are left out.
+    pub fn paint_layers(&self) -> Vec<PaintLayer<'_>> {
+        let decorations = |line: DecorationLine| {
+            PaintLayer::Decorations(
+                self.decorations
+                    .iter()
+                    .filter(|decoration| decoration.line == line)
+                    .collect(),
+            )
+        };
+        let glyphs = self
+            .lines
+            .iter()
+            .flat_map(|line| self.glyphs[line.glyphs.clone()].iter())
+            .chain(self.ruby_glyphs.iter())
+            .collect();
+
+        [
+            PaintLayer::Highlights(&self.highlights),
+            decorations(DecorationLine::Underline),
+            PaintLayer::Glyphs(glyphs),
+            decorations(DecorationLine::LineThrough),
+        ]
+        .into_iter()
+        .filter(|layer| match layer {
+            PaintLayer::Highlights(rects) => !rects.is_empty(),
+            PaintLayer::Decorations(rects) => !rects.is_empty(),
+            PaintLayer::Glyphs(glyphs) => !glyphs.is_empty(),
+        })
+        .collect()
+    }
+
+    /// A standalone SVG document with the highlights, the decorations and the glyphs.
+    pub fn to_svg(&self, options: &SvgOptions) -> String {
+        let drawn_glyphs = self
+            .lines
+            .iter()
+            .flat_map(|line| self.glyphs[line.glyphs.clone()].iter())
+            .chain(self.ruby_glyphs.iter())
+            .filter(|glyph| !glyph.outline.is_empty());
+        let mut defs = HashMap::<&str, usize>::new();
+        // Every distinct outline with the largest scale it's drawn at.
+        let mut outlines = vec![];
+        if options.use_defs {
+            for glyph in drawn_glyphs {
+                let def = *defs.entry(&glyph.outline).or_insert_with(|| {
+                    outlines.push((glyph.outline.trim_end(), 0.0_f64));
+                    outlines.len() - 1
+                });
+                let matrix = glyph.transform.matrix2;
+                let scale = matrix.x_axis.length().max(matrix.y_axis.length());
+                outlines[def].1 = outlines[def].1.max(scale);
+            }
+        }
+        let device_pixels = DAffine2::from_scale(DVec2::splat(options.device_pixel_ratio));
+        let draw = |glyph: &LayoutGlyph| {
+            let mut attrs = glyph
+                .color
+                .as_ref()
+                .map(|color| format!(" fill=\"{}\"", xml_attribute(color)))
+                .unwrap_or_default();
+            if glyph.opacity < 1.0 {
+                attrs += &format!(" fill-opacity=\"{}\"", glyph.opacity);
+            }
+            if options.glyph_data {
+                attrs += &format!(
+                    " data-cluster=\"{}\" data-glyph-id=\"{}\"",
+                    glyph.cluster, glyph.glyph_id
+                );
+            }
+            if glyph.outline.is_empty() {
+                String::new()
+            } else if options.use_defs {
+                let DAffine2 {
+                    matrix2: m,
+                    translation: t,
+                } = device_pixels * glyph.transform;
+                let mut scale = format!(
+                    "{} {} {} {}",
+                    m.x_axis.x, m.x_axis.y, m.y_axis.x, m.y_axis.y
+                );
+                let mut translation = format!("{} {}", t.x, t.y);
+                if let Some(decimals) = options.path_precision {
+                    // Outlines span up to about 10⁴ font units, so the scale needs four more
+                    // decimals for the points to be as precise as those of the paths.
+                    scale = round_numbers(&scale, decimals + 4);
+                    translation = round_numbers(&translation, decimals);
+                }
+                let matrix = format!("matrix({scale} {translation})");
+                format!(
+                    "<use href=\"#glyph-{}\" transform=\"{matrix}\"{attrs}></use>",
+                    defs[glyph.outline.as_str()],
+                )
+            } else {
+                format!("<path d=\"{}\"{attrs}></path>", glyph.path.trim_end())
+            }
+        };
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">",
+            options.width, options.height
+        );
+        let rect = |rect: &Rect, color: &str| {
+            let Rect { x, y, w, h } = rect;
+            let color = xml_attribute(color);
+            format!(
+                "<rect x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{h}\" fill=\"{color}\"></rect>"
+            )
+        };
+        let decorations = |line: DecorationLine| {
+            self.decorations
+                .iter()
+                .filter(move |decoration| decoration.line == line)
+                .map(move |decoration| rect(&decoration.rect, &decoration.color))
+        };
+        // In the order of `paint_layers`.
+        for highlight in self.highlights.iter() {
+            svg += &rect(&highlight.rect, &highlight.color);
+        }
+        svg.extend(decorations(DecorationLine::Underline));
+        if !outlines.is_empty() {
+            svg += "<defs>";
+            for (i, (outline, scale)) in outlines.iter().enumerate() {
+                let mut outline = outline.to_string();
+                if let Some(tolerance) = options.flatten_tolerance {
+                    // In font units, so that the lines are as many as in the glyph paths.
+                    let tolerance = (tolerance * options.device_pixel_ratio)
+                        .max(MIN_FLATTEN_TOLERANCE)
+                        / (scale * options.device_pixel_ratio);
+                    outline = flatten_path(&outline, tolerance);
+                }
+                if let Some(decimals) = options.path_precision {
+                    outline = round_numbers(&outline, decimals);
+                }
+                svg += &format!(
+                    "<path id=\"glyph-{i}\" d=\"{}\"></path>",
+                    outline.trim_end()
+                );
+            }
+            svg += "</defs>";
+        }
+
+        svg += &format!(
+            "<g fill=\"{}\" fill-rule=\"{}\">",
+            xml_attribute(&options.fill),
+            self.fill_rule.as_str()
+        );
+        let mut paragraph = None;
+        for line in self.lines.iter() {
+            if options.group_paragraphs && paragraph != Some(line.paragraph) {
+                if paragraph.is_some() {
+                    svg += "</g>";
+                }
+                svg += &format!("<g data-paragraph-index=\"{}\">", line.paragraph);
+                paragraph = Some(line.paragraph);
+            }
+            for glyph in self.glyphs[line.glyphs.clone()].iter() {
+                svg += &draw(glyph);
+            }
+        }
+        if paragraph.is_some() {
+            svg += "</g>";
+        }
+        for glyph in self.ruby_glyphs.iter() {
+            svg += &draw(glyph);
+        }
+        svg += "</g>";
+        svg.extend(decorations(DecorationLine::LineThrough));
+        svg += "</svg>";
+
+        svg
+    }
+
+    /// Rectangles covering the glyphs of the source byte range `start..end`: one for every
+    /// visually contiguous piece of each line, so a range crossing a line break or a change of
+    /// direction gives several.
+    pub fn selection_rects(&self, start: usize, end: usize) -> Vec<Rect> {
+        self.piece_rects(|glyph| (start..end).contains(&glyph.cluster))
+    }
+
+    /// Rectangles covering every visually contiguous run of glyphs `in_piece` is true for, on
+    /// each line.
+    fn piece_rects(&self, in_piece: impl Fn(&LayoutGlyph) -> bool) -> Vec<Rect> {
+        self.line_piece_rects(in_piece)
+            .into_iter()
+            .map(|(_, rect)| rect)
+            .collect()
+    }
+
+    /// Like [`Layout::piece_rects`], with the index of the line each rectangle is on.
+    fn line_piece_rects(&self, in_piece: impl Fn(&LayoutGlyph) -> bool) -> Vec<(usize, Rect)> {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        let mut result = vec![];
+
+        for (line_index, line) in self.lines.iter().enumerate() {
+            let mut glyphs = self.glyphs[line.glyphs.clone()].iter().collect::<Vec<_>>();
+            if vertical {
+                glyphs.sort_by(|a, b| a.y.total_cmp(&b.y));
+            } else {
+                glyphs.sort_by(|a, b| a.x.total_cmp(&b.x));
+            }
+
+            let mut run: Option<(f64, f64)> = None;
+            let mut push_run = |run: Option<(f64, f64)>| {
+                if let Some((from, to)) = run {
+                    result.push((
+                        line_index,
+                        if vertical {
+                            Rect {
+                                x: line.x - line.ascent,
+                                y: from,
+                                w: line.ascent + line.descent,
+                                h: to - from,
+                            }
+                        } else {
+                            Rect {
+                                x: from,
+                                y: line.y - line.ascent,
+                                w: to - from,
+                                h: line.ascent + line.descent,
+                            }
+                        },
+                    ));
+                }
+            };
+
+            for glyph in glyphs {
+                if in_piece(glyph) {
+                    let from = if vertical { glyph.y } else { glyph.x };
+                    let to = from + glyph.advance;
+                    run = Some(run.map_or((from, to), |(a, b)| (a.min(from), b.max(to))));
+                } else {
+                    push_run(run.take());
+                }
+            }
+            push_run(run);
+        }
+
+        result
+    }
+
+    /// The line box of `line`, as long as the line.
+    fn line_rect(&self, line: &LayoutLine) -> Rect {
+        if self.writing_mode == WritingMode::VerticalRl {
+            Rect {
+                x: line.x - line.ascent,
+                y: line.y,
+                w: line.ascent + line.descent,
+                h: line.width,
+            }
+        } else {
+            Rect {
+                x: line.x,
+                y: line.y - line.ascent,
+                w: line.width,
+                h: line.ascent + line.descent,
+            }
+        }
+    }
+
+    /// Every glyph of [`Layout::glyphs`] with an outline, moved to the origin. `scale` is the
+    /// [`LayoutOptions::device_pixel_ratio`] the layout was made with, which the paths are
+    /// multiplied by.
+    pub fn local_glyphs(&self, scale: f64) -> Vec<LocalGlyph> {
+        let mut result = vec![];
+        for (i, glyph) in self.glyphs.iter().enumerate() {
+            let cmds = PathCmd::parse(&glyph.path);
+            let mut points = cmds.iter().flat_map(PathCmd::points);
+            let Some(first) = points.next() else {
+                continue;
+            };
+            let min = points.fold(first, DVec2::min);
+
+            let mut local_path = String::with_capacity(glyph.path.len());
+            for cmd in cmds.iter() {
+                cmd.map(|point| point - min).write_svg(&mut local_path);
+            }
+            result.push(LocalGlyph {
+                glyph: i,
+                local_path,
+                atlas_origin: DVec2::new(glyph.x, glyph.y) * scale - min,
+                place_x: min.x,
+                place_y: min.y,
+            });
+        }
+
+        result
+    }
+
+    /// Box of every word in [`LayoutLine::words`] that has glyphs, made of their [cells], e.g. to
+    /// select a word on double click. The boxes of a line whose words run in a single
+    /// direction follow each other without gaps, the whitespace ending a word included.
+    ///
+    /// [cells]: Layout::cells
+    pub fn word_boxes(&self) -> Vec<WordBox> {
+        let cells = self.cells();
+        let mut result = vec![];
+
+        for line in self.lines.iter() {
+            for word in line.words.iter() {
+                let start = word.start.max(line.source.start);
+                let end = word.end.min(line.source.end);
+                let rect = line
+                    .glyphs
+                    .clone()
+                    .filter(|i| (start..end).contains(&self.glyphs[*i].cluster))
+                    .map(|i| cells[i])
+                    .reduce(|a, b| a.union(&b));
+                if let Some(rect) = rect {
+                    result.push(WordBox { start, end, rect });
+                }
+            }
+        }
 
-    state.resolve_input(&input_transform, input)
-}
+        result
+    }
 
-struct Font<'a> {
-    raw_data: &'a [u8],
-    face: hb::Face<'a>,
-}
+    /// Cell of every glyph in [`Layout::glyphs`]: across the line it spans the line's ascent and
+    /// descent, along it from the glyph's pen position to the next one on the line, so letter
+    /// spacing and justification belong to the cell before them. With a monospaced font the
+    /// cells of a line are a grid a cursor or a selection can snap to, like in a terminal, only
+    /// the last one is short of the letter spacing that never comes after a line.
+    pub fn cells(&self) -> Vec<Rect> {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        let mut result = vec![];
 
-impl<'a> Font<'a> {}
+        for line in self.lines.iter() {
+            let position = |glyph: &LayoutGlyph| if vertical { glyph.y } else { glyph.x };
+            let mut stops = self.glyphs[line.glyphs.clone()]
+                .iter()
+                .map(position)
+                .collect::<Vec<_>>();
+            stops.sort_by(f64::total_cmp);
 
-type FontId = String;
-type FontRegistry<'a> = HashMap<FontId, Font<'a>>;
+            for glyph in self.glyphs[line.glyphs.clone()].iter() {
+                let from = position(glyph);
+                // Marks and trimmed whitespace don't take any room.
+                let to = if glyph.advance > 0.0 {
+                    let next = stops.iter().find(|stop| **stop > from);
+                    next.map_or(from + glyph.advance, |stop| *stop)
+                } else {
+                    from
+                };
+                result.push(if vertical {
+                    Rect {
+                        x: line.x - line.ascent,
+                        y: from,
+                        w: line.ascent + line.descent,
+                        h: to - from,
+                    }
+                } else {
+                    Rect {
+                        x: from,
+                        y: line.y - line.ascent,
+                        w: to - from,
+                        h: line.ascent + line.descent,
+                    }
+                });
+            }
+        }
 
-const GLOBAL_FALLBACK_FONT: &'static str = "pt";
+        result
+    }
 
-const FONT_DATA: [&'static [u8]; 5] = [
-    include_bytes!("../fonts/PTSerif-Regular.ttf"),
-    include_bytes!("../fonts/SeoulNamsanvert.otf"),
-    include_bytes!("../fonts/Roboto-VariableFont_wdth,wght.ttf"),
-    include_bytes!("../fonts/Roboto-Italic-VariableFont_wdth,wght.ttf"),
-    include_bytes!("../fonts/NotoSansHebrew-VariableFont_wdth,wght.ttf"),
-];
+    /// `text`, the text of this layout, split into runs in logical order with the font and color
+    /// its glyphs are drawn with, e.g. to copy it with its formatting. Runs follow each other
+    /// without gaps from the start to the end of `text`: characters without glyphs, like
+    /// paragraph separators, belong to the run before them.
+    pub fn styled_runs(&self, text: &str) -> Vec<StyledRun> {
+        let mut glyphs = self
+            .lines
+            .iter()
+            .flat_map(|line| self.glyphs[line.glyphs.clone()].iter())
+            .collect::<Vec<_>>();
+        glyphs.sort_by_key(|glyph| glyph.cluster);
 
-#[derive(Debug, Clone, Copy)]
-enum HorizontalAlignment {
-    Normal,
-    Reverse,
-    Center,
-}
+        let mut result = Vec::<StyledRun>::new();
+        for glyph in glyphs {
+            match result.last_mut() {
+                Some(run) if run.font == glyph.font && run.color == glyph.color => {}
+                last => {
+                    let start = if last.is_some() { glyph.cluster } else { 0 };
+                    if let Some(run) = last {
+                        run.source.end = start;
+                    }
+                    result.push(StyledRun {
+                        source: start..text.len(),
+                        font: glyph.font.clone(),
+                        color: glyph.color.clone(),
+                    });
+                }
+            }
+        }
 
-impl Default for HorizontalAlignment {
-    fn default() -> Self {
-        HorizontalAlignment::Normal
+        result
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-enum VerticalAlignment {
-    Normal,
-    Reverse,
-    Center,
+    /// The glyphs on the lines grouped into runs of the same glyph, drawn alike and evenly
+    /// spaced, e.g. to tile rows of dashes with one path instead of drawing every copy. Every
+    /// glyph is in exactly one run, in the order of [`Layout::glyphs`].
+    pub fn repeat_runs(&self) -> Vec<RepeatRun> {
+        let mut result = Vec::<RepeatRun>::new();
+        for line in self.lines.iter() {
+            let line_start = result.len();
+            for i in line.glyphs.clone() {
+                let glyph = &self.glyphs[i];
+                let pen = DVec2::new(glyph.x, glyph.y);
+                if let Some(run) = result[line_start..].last_mut() {
+                    let first = &self.glyphs[run.glyph];
+                    let last = DVec2::new(first.x, first.y) + run.step * (run.count - 1) as f64;
+                    let step = pen - last;
+                    let alike = first.glyph_id == glyph.glyph_id
+                        && first.font == glyph.font
+                        && first.color == glyph.color
+                        && first.opacity == glyph.opacity
+                        && first.offset == glyph.offset;
+                    if alike && (run.count == 1 || step.abs_diff_eq(run.step, 1e-9)) {
+                        run.step = step;
+                        run.count += 1;
+                        continue;
+                    }
+                }
+                result.push(RepeatRun {
+                    glyph: i,
+                    count: 1,
+                    step: DVec2::ZERO,
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Glyphs displayed out of order for their text, a sign that bidi reordering went wrong.
+    /// From left to right within a line, clusters have to grow along glyphs shaped left to right
+    /// and shrink along glyphs shaped right to left. Columns of vertical text always read from
+    /// the top. Marks with clusters of their own, like with [`ClusterLevel::Characters`], can be
+    /// reported too.
+    pub fn reordering_anomalies(&self) -> Vec<ReorderingAnomaly> {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        let mut result = vec![];
+        for (line_index, line) in self.lines.iter().enumerate() {
+            let mut visual_order = line.glyphs.clone().collect::<Vec<_>>();
+            if !vertical {
+                // Stable, so that glyphs on the same pen position keep their shaping order.
+                visual_order.sort_by(|a, b| self.glyphs[*a].x.total_cmp(&self.glyphs[*b].x));
+            }
+            for pair in visual_order.windows(2) {
+                let (first, second) = (&self.glyphs[pair[0]], &self.glyphs[pair[1]]);
+                let in_order = match (first.rtl, second.rtl) {
+                    (false, false) => first.cluster <= second.cluster,
+                    (true, true) => first.cluster >= second.cluster,
+                    // Runs of either direction can follow each other in any order.
+                    _ => true,
+                };
+                if !in_order {
+                    result.push(ReorderingAnomaly {
+                        line: line_index,
+                        glyphs: (pair[0], pair[1]),
+                    });
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Glyphs that were added, removed or moved since the layout `old`, e.g. of the text before
+    /// an edit, so that only those are repainted. Glyphs are matched by font, glyph id and cluster
+    /// from both ends of [`Layout::glyphs`] until the first difference, everything between is
+    /// removed and added again. Clusters at the end are compared shifted by how much longer the
+    /// text got, as the ends of the last lines tell. Matched glyphs drawn differently have moved.
+    pub fn diff(&self, old: &Layout) -> LayoutDiff {
+        let text_end = |layout: &Layout| layout.lines.last().map_or(0, |line| line.source.end);
+        let shift = text_end(self) as isize - text_end(old) as isize;
+        let same = |old: &LayoutGlyph, new: &LayoutGlyph, shift: isize| {
+            old.glyph_id == new.glyph_id
+                && old.font == new.font
+                && old.cluster as isize + shift == new.cluster as isize
+        };
+        let prefix = old
+            .glyphs
+            .iter()
+            .zip(self.glyphs.iter())
+            .take_while(|(old, new)| same(old, new, 0))
+            .count();
+        let suffix = old.glyphs[prefix..]
+            .iter()
+            .rev()
+            .zip(self.glyphs[prefix..].iter().rev())
+            .take_while(|(old, new)| same(old, new, shift))
+            .count();
+        let old_end = old.glyphs.len() - suffix;
+        let new_end = self.glyphs.len() - suffix;
+
+        let matched = (0..prefix)
+            .map(|i| (i, i))
+            .chain((0..suffix).map(|i| (old_end + i, new_end + i)));
+        LayoutDiff {
+            added: (prefix..new_end).collect(),
+            removed: (prefix..old_end).collect(),
+            moved: matched
+                .filter(|(i, j)| {
+                    let (old, new) = (&old.glyphs[*i], &self.glyphs[*j]);
+                    (old.x, old.y) != (new.x, new.y)
+                        || old.transform != new.transform
+                        || old.path != new.path
+                })
+                .collect(),
+        }
+    }
+
+    /// The part of `text`, the text of this layout, every line shows, without the whitespace it
+    /// starts or ends with, e.g. for an `aria-label` per line. Glyphs dropped by
+    /// [`LayoutOptions::clip`] don't change it.
+    pub fn line_texts<'a>(&self, text: &'a str) -> Vec<&'a str> {
+        self.lines
+            .iter()
+            .map(|line| text.get(line.source.clone()).unwrap_or_default().trim())
+            .collect()
+    }
+
+    /// Every line with its text, baseline and direction, for consumers drawing real text with
+    /// the layout's line breaks, e.g. to keep it selectable. `text` is the text of this layout.
+    pub fn text_lines<'a>(&self, text: &'a str) -> Vec<TextLine<'a>> {
+        self.lines
+            .iter()
+            .zip(self.line_texts(text))
+            .map(|(line, text)| TextLine {
+                text,
+                x: line.x,
+                y: line.y,
+                width: line.width,
+                rtl: line.rtl,
+            })
+            .collect()
+    }
+
+    /// Where along its line every character of `text`, the text of this layout, starts: the
+    /// left edge of its cell in horizontal text and the top edge in vertical text. Characters
+    /// sharing a cluster, like the letters of a ligature, split its width evenly, and a
+    /// character drawn with several glyphs starts at the first of them. Characters that aren't
+    /// drawn, like paragraph separators or text left out by [`LayoutOptions::max_glyphs`], have
+    /// no position.
+    pub fn char_positions(&self, text: &str) -> Vec<Option<f64>> {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        // Extent of every cluster, its direction and the end of its line.
+        let mut clusters = std::collections::BTreeMap::<usize, (f64, f64, bool, usize)>::new();
+        for line in self.lines.iter() {
+            for glyph in self.glyphs[line.glyphs.clone()].iter() {
+                let from = if vertical { glyph.y } else { glyph.x };
+                let to = from + glyph.advance;
+                clusters
+                    .entry(glyph.cluster)
+                    .and_modify(|(start, end, ..)| {
+                        *start = start.min(from);
+                        *end = end.max(to);
+                    })
+                    .or_insert((from, to, glyph.rtl, line.source.end));
+            }
+        }
+
+        let char_starts = text.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+        let mut result = vec![None; char_starts.len()];
+        let mut clusters = clusters.into_iter().peekable();
+        while let Some((cluster, (from, to, rtl, line_end))) = clusters.next() {
+            let end = clusters
+                .peek()
+                .map_or(line_end, |(next, _)| (*next).min(line_end));
+            let chars = char_starts.partition_point(|start| *start < cluster)
+                ..char_starts.partition_point(|start| *start < end);
+            let width = (to - from) / chars.len() as f64;
+            for (i, char_index) in chars.enumerate() {
+                // Right-to-left clusters start on their right.
+                result[char_index] = Some(if rtl {
+                    to - (i + 1) as f64 * width
+                } else {
+                    from + i as f64 * width
+                });
+            }
+        }
+
+        result
+    }
+
+    /// Paragraph and index in [`Layout::lines`] of the line the source byte `index` is on. An
+    /// index at the end of a paragraph is on its last line.
+    pub fn index_to_line(&self, index: usize) -> Option<(usize, usize)> {
+        let line = self
+            .lines
+            .iter()
+            .position(|line| line.source.contains(&index))
+            .or_else(|| self.lines.iter().position(|line| line.source.end == index))?;
+
+        Some((self.lines[line].paragraph, line))
+    }
+
+    /// Source index where the first line whose baseline is inside `clip` starts, e.g. to scroll
+    /// back to it after a relayout. Columns of vertical text are visible when their center line
+    /// is inside `clip` across them.
+    pub fn first_visible_index(&self, clip: &Rect) -> Option<usize> {
+        self.visible_lines(clip)
+            .next()
+            .map(|line| line.source.start)
+    }
+
+    /// Source index where the last line whose baseline is inside `clip` ends, so that the clip
+    /// shows the source from [`Layout::first_visible_index`] up to it.
+    pub fn last_visible_index(&self, clip: &Rect) -> Option<usize> {
+        self.visible_lines(clip)
+            .next_back()
+            .map(|line| line.source.end)
+    }
+
+    fn visible_lines(&self, clip: &Rect) -> impl DoubleEndedIterator<Item = &LayoutLine> {
+        let clip = *clip;
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        self.lines.iter().filter(move |line| {
+            if vertical {
+                (clip.x..=clip.x + clip.w).contains(&line.x)
+            } else {
+                (clip.y..=clip.y + clip.h).contains(&line.y)
+            }
+        })
+    }
+
+    /// Source index on the line `lines` below the one `index` is on, above it for negative
+    /// values, closest to where the caret at `index` is along the line. This is what the up and
+    /// down arrows do in an editor.
+    pub fn move_vertically(&self, index: usize, lines: isize) -> Option<usize> {
+        let (_, line) = self.index_to_line(index)?;
+        let target = line
+            .checked_add_signed(lines)
+            .filter(|target| *target < self.lines.len())?;
+
+        let stops = self.caret_stops(line);
+        let position = stops
+            .iter()
+            .filter(|(_, stop)| *stop <= index)
+            .max_by_key(|(_, stop)| *stop)
+            .or(stops.first())
+            .map(|(position, _)| *position)?;
+        self.caret_stops(target)
+            .into_iter()
+            .min_by(|(a, _), (b, _)| (a - position).abs().total_cmp(&(b - position).abs()))
+            .map(|(_, stop)| stop)
+    }
+
+    /// Where along `line` the caret can go and the source index it is at there: before every
+    /// cluster, and after the last one when the line isn't wrapped.
+    fn caret_stops(&self, line_index: usize) -> Vec<(f64, usize)> {
+        let vertical = self.writing_mode == WritingMode::VerticalRl;
+        let line = &self.lines[line_index];
+        let glyphs = &self.glyphs[line.glyphs.clone()];
+        let edges = |glyph: &LayoutGlyph| {
+            let from = if vertical { glyph.y } else { glyph.x };
+            let to = from + glyph.advance;
+            // Right-to-left clusters start on their right.
+            if glyph.rtl {
+                (to, from)
+            } else {
+                (from, to)
+            }
+        };
+
+        let mut stops = glyphs
+            .iter()
+            .map(|glyph| (edges(glyph).0, glyph.cluster))
+            .collect::<Vec<_>>();
+        let wrapped = self
+            .lines
+            .get(line_index + 1)
+            .is_some_and(|next| next.paragraph == line.paragraph && !line.hard_break);
+        match glyphs.iter().max_by_key(|glyph| glyph.cluster) {
+            Some(last) if !wrapped => stops.push((edges(last).1, line.source.end)),
+            None => stops.push((if vertical { line.y } else { line.x }, line.source.start)),
+            _ => {}
+        }
+
+        stops
+    }
 }
 
-impl Default for VerticalAlignment {
-    fn default() -> Self {
-        VerticalAlignment::Normal
+#[cfg(feature = "raster")]
+impl Layout {
+    /// Draws the glyphs, ruby included, into `width`×`height` transparent pixels. Their paths
+    /// are filled in black by the [`Layout::fill_rule`] at their [`LayoutGlyph::opacity`] with
+    /// the same coordinates as in the SVG output, so a device pixel ratio scales them too.
+    /// Colors and highlights are only in the SVG output.
+    pub fn rasterize(&self, width: u32, height: u32) -> Raster {
+        let Some(mut pixmap) = tiny_skia::Pixmap::new(width, height) else {
+            log!("Can't rasterize a layout into {}x{} pixels!", width, height);
+            return Raster::default();
+        };
+
+        let glyphs = self
+            .lines
+            .iter()
+            .flat_map(|line| self.glyphs[line.glyphs.clone()].iter())
+            .chain(self.ruby_glyphs.iter());
+        for glyph in glyphs {
+            let Some(path) = Self::raster_path(&glyph.path) else {
+                continue;
+            };
+            let mut paint = tiny_skia::Paint::default();
+            paint.set_color_rgba8(
+                0,
+                0,
+                0,
+                (glyph.opacity.clamp(0.0, 1.0) * 255.0).round() as u8,
+            );
+            paint.anti_alias = true;
+            pixmap.fill_path(
+                &path,
+                &paint,
+                match self.fill_rule {
+                    FillRule::NonZero => tiny_skia::FillRule::Winding,
+                    FillRule::EvenOdd => tiny_skia::FillRule::EvenOdd,
+                },
+                tiny_skia::Transform::identity(),
+                None,
+            );
+        }
+
+        Raster {
+            width,
+            height,
+            pixels: pixmap
+                .pixels()
+                .iter()
+                .flat_map(|pixel| {
+                    let color = pixel.demultiply();
+                    [color.red(), color.green(), color.blue(), color.alpha()]
+                })
+                .collect(),
+        }
+    }
+
+    /// Reads back the path data the outline builder of [`GlyphPath`] writes. Empty outlines
+    /// give `None`.
+    fn raster_path(data: &str) -> Option<tiny_skia::Path> {
+        let mut builder = tiny_skia::PathBuilder::new();
+        let mut command = ' ';
+        let mut args = Vec::<f32>::with_capacity(6);
+        for token in data.split([' ', ',']).filter(|token| !token.is_empty()) {
+            let number = match token.strip_prefix(char::is_alphabetic) {
+                Some(rest) => {
+                    command = token.chars().next().unwrap();
+                    args.clear();
+                    rest
+                }
+                None => token,
+            };
+            if !number.is_empty() {
+                args.push(number.parse().ok()?);
+            }
+            match (command, args.as_slice()) {
+                ('M', &[x, y]) => builder.move_to(x, y),
+                ('L', &[x, y]) => builder.line_to(x, y),
+                ('Q', &[x1, y1, x, y]) => builder.quad_to(x1, y1, x, y),
+                ('C', &[x1, y1, x2, y2, x, y]) => builder.cubic_to(x1, y1, x2, y2, x, y),
+                ('Z', &[]) => builder.close(),
+                _ => continue,
+            }
+            args.clear();
+        }
+
+        builder.finish()
     }
 }
 
 struct Input {
     text: String,
-    paragraphs_fonts: Vec<FontId>,
-    horizontal_alignment: HorizontalAlignment,
-    vertical_alignment: VerticalAlignment,
-    fallback_font: FontId,
+    options: LayoutOptions,
+}
+
+/// A bidi paragraph waiting to be shaped and broken into lines.
+struct ParagraphSource<'f> {
+    text: String,
+    /// Byte offset of `text` in the whole input.
+    text_offset: usize,
+    font_id: FontId,
+    font: &'f Font,
+    is_rtl: bool,
+    /// The paragraph is terminated by a paragraph separator, not by the end of the text.
+    hard_break: bool,
+    /// Font ascent and descent in pixels.
+    ascent: f64,
+    descent: f64,
 }
 
-impl<'a> AppState<'a> {
-    fn new() -> AppState<'a> {
-        let mut fonts = HashMap::<FontId, Font<'a>>::new();
+impl AppState {
+    fn new() -> AppState {
+        let mut fonts = FontRegistry::new();
 
         fonts.insert(
             GLOBAL_FALLBACK_FONT.into(),
-            Font {
-                raw_data: FONT_DATA[0],
-                face: hb::Face::from_slice(FONT_DATA[0], 0).unwrap(),
-            },
-        );
-        fonts.insert(
-            "seoul".into(),
-            Font {
-                raw_data: FONT_DATA[1],
-                face: hb::Face::from_slice(FONT_DATA[1], 0).unwrap(),
-            },
+            Rc::new(Font::new(FONT_DATA[0])),
         );
+        fonts.insert("seoul".into(), Rc::new(Font::new(FONT_DATA[1])));
 
-        let mut roboto = Font {
-            raw_data: FONT_DATA[2],
-            face: hb::Face::from_slice(FONT_DATA[2], 0).unwrap(),
-        };
-        roboto
-            .face
-            .set_variation(hb::ttf_parser::Tag::from_bytes(b"wght"), 400.0);
-        fonts.insert("roboto".into(), roboto);
+        let mut roboto = Font::new(FONT_DATA[2]);
+        roboto.set_variation(b"wght", 400.0);
+        fonts.insert("roboto".into(), Rc::new(roboto));
 
-        let mut roboto_italic = Font {
-            raw_data: FONT_DATA[3],
-            face: hb::Face::from_slice(FONT_DATA[3], 0).unwrap(),
-        };
-        roboto_italic
-            .face
-            .set_variation(hb::ttf_parser::Tag::from_bytes(b"wght"), 600.0);
-        fonts.insert("roboto-italic".into(), roboto_italic);
+        let mut roboto_italic = Font::new(FONT_DATA[3]);
+        roboto_italic.set_variation(b"wght", 600.0);
+        fonts.insert("roboto-italic".into(), Rc::new(roboto_italic));
 
-        let mut noto = Font {
-            raw_data: FONT_DATA[4],
-            face: hb::Face::from_slice(FONT_DATA[4], 0).unwrap(),
-        };
-        noto.face
-            .set_variation(hb::ttf_parser::Tag::from_bytes(b"wght"), 400.0);
-        fonts.insert("noto".into(), noto);
+        let mut noto = Font::new(FONT_DATA[4]);
+        noto.set_variation(b"wght", 400.0);
+        fonts.insert("noto".into(), Rc::new(noto));
 
         let inputs = vec![
             Input {
                 text: "아무도 자의적인 체포, 구금 또는 추방을 당하지 않아야 합니다. 모든 사람은 자신의 권리와 의무, 그리고 자신에게 제기된 형사 혐의를 결정함에 있어 독립적이고 공정한 재판소에 의해 평등하게 공정하고 공개적인 심리를 받을 권리를 갖습니다. 아무도 자신의 사생활, 가족, 가정 또는 서신에 대한 자의적인 간섭이나 명예와 평판에 대한 공격을 받아서는 안 됩니다. 모든 사람은 그러한 간섭이나 공격으로부터 법의 보호를 받을 권리를 갖습니다.".into(),
-                paragraphs_fonts: vec!["seoul".into()],
-                fallback_font: "seoul".into(),
-                horizontal_alignment: HorizontalAlignment::Center,
-                vertical_alignment: VerticalAlignment::Reverse,
+                options: LayoutOptions {
+                    paragraphs_fonts: vec!["seoul".into()],
+                    fallback_font: "seoul".into(),
+                    horizontal_alignment: HorizontalAlignment::Center,
+                    vertical_alignment: VerticalAlignment::Reverse,
+                    ..Default::default()
+                },
             },
             Input {
                 text: "איש לא יהיה נתון למעצר, מעצר שרירותי או גירוש. לכל אדם הזכות לשוויון מלא למשפט הוגן ופומבי בפני בית דין עצמאי ובלתי משוחד, לצורך הכרעה בזכויותיו וחובותיו ובכל אישום פלילי המופנה נגדו. איש לא יהיה נתון להתערבות שרירותית בפרטיותו, במשפחתו, בביתו או בהתכתבויותיו, ולא לפגיעות בכבודו או בשמו הטוב. לכל אדם הזכות להגנת החוק מפני התערבויות או פגיעות כאלה.".into(),
-                paragraphs_fonts: vec!["noto".into()],
-                fallback_font: "noto".into(),
-                horizontal_alignment: HorizontalAlignment::Normal,
-                vertical_alignment: VerticalAlignment::Normal,
+                options: LayoutOptions {
+                    paragraphs_fonts: vec!["noto".into()],
+                    fallback_font: "noto".into(),
+                    horizontal_alignment: HorizontalAlignment::Normal,
+                    vertical_alignment: VerticalAlignment::Normal,
+                    ..Default::default()
+                },
             },
             Input {
                 text: "Nul ne sera soumis à une arrestation, une détention ou un exil arbitraires.\n\nToute personne a droit, en pleine égalité, à ce que sa cause soit entendue équitablement et publiquement par un tribunal indépendant et impartial, qui décidera de ses droits et obligations ainsi que du bien-fondé de toute accusation en matière pénale portée contre elle. Nul ne sera l'objet d'immixtions arbitraires dans sa vie privée, sa famille, son domicile ou sa correspondance, ni d'atteintes à son honneur et à sa réputation. Toute personne a droit à la protection de la loi contre de telles immixtions ou de telles atteintes.\nFin.\n\n".into(),
-                paragraphs_fonts: vec!["pt".into(), "pt".into(), "pt".into(), "pt".into(), "pt".into(), "pt".into()],
-                fallback_font: "pt".into(),
-                horizontal_alignment: HorizontalAlignment::Reverse,
-                vertical_alignment: VerticalAlignment::Normal,
+                options: LayoutOptions {
+                    paragraphs_fonts: vec!["pt".into(), "pt".into(), "pt".into(), "pt".into(), "pt".into(), "pt".into()],
+                    fallback_font: "pt".into(),
+                    horizontal_alignment: HorizontalAlignment::Reverse,
+                    vertical_alignment: VerticalAlignment::Normal,
+                    ..Default::default()
+                },
             },
             Input {
                 text: "Nul ne sera soumis à une arrestation, une détention ou un exil arbitraires.\nאיש לא יהיה נתון להתערבות שרירותית בפרטיותו, במשפחתו, בביתו או בהתכתבויותיו, ולא לפגיעות בכבודו או בשמו הטוב\nToute personne a droit à la protection de la loi contre de telles immixtions ou de telles atteintes.".into(),
-                paragraphs_fonts: vec!["roboto-italic".into(), "noto".into(), "roboto".into()],
-                fallback_font: "roboto".into(),
-                horizontal_alignment: HorizontalAlignment::Normal,
-                vertical_alignment: VerticalAlignment::Center,
+                options: LayoutOptions {
+                    paragraphs_fonts: vec!["roboto-italic".into(), "noto".into(), "roboto".into()],
+                    fallback_font: "roboto".into(),
+                    horizontal_alignment: HorizontalAlignment::Normal,
+                    vertical_alignment: VerticalAlignment::Center,
+                    ..Default::default()
+                },
             }
         ];
 
-        AppState::<'a> {
+        AppState {
             fonts,
             inputs,
             last_input: 0,
             last_text_size: 16,
             already_performed_layout: false,
             prev_layout: vec![],
+            shaping_calls: std::cell::Cell::new(0),
         }
     }
 
@@ -190,284 +3231,1682 @@ impl<'a> AppState<'a> {
         if input != self.last_input {
             return true;
         }
-        if text_size != self.last_text_size {
-            return true;
+        if text_size != self.last_text_size {
+            return true;
+        }
+
+        !self.already_performed_layout
+    }
+
+    fn resolve_input(&mut self, options: &LayoutOptions, input: usize) -> Vec<String> {
+        let prev_layout = if self.needs_to_redo_layout(input, options.size) {
+            None
+        } else {
+            Some(self.prev_layout.as_slice())
+        };
+        let (layout, new_layout) = self.layout_text(&self.inputs[input].text, options, prev_layout);
+        self.already_performed_layout = true;
+        if let Some(value) = new_layout {
+            self.prev_layout = value;
+            self.last_input = input;
+            self.last_text_size = options.size;
+        }
+
+        layout
+            .glyphs
+            .into_iter()
+            .chain(layout.ruby_glyphs)
+            .map(|glyph| glyph.path)
+            .collect()
+    }
+
+    fn validate_input(&self, text: &[u8], options: &LayoutOptions) -> Vec<Warning> {
+        use icu::properties::{maps, sets};
+
+        let mut result = vec![];
+        let text = match std::str::from_utf8(text) {
+            Ok(text) => std::borrow::Cow::Borrowed(text),
+            Err(error) => {
+                result.push(Warning::InvalidUtf8(error.valid_up_to()));
+                String::from_utf8_lossy(text)
+            }
+        };
+
+        let mut unknown_fonts = options
+            .paragraphs_fonts
+            .iter()
+            .chain([&options.fallback_font])
+            .chain(options.script_fonts.values())
+            .filter(|font_id| !self.fonts.contains_key(*font_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        unknown_fonts.sort_unstable();
+        unknown_fonts.dedup();
+        result.extend(unknown_fonts.into_iter().map(Warning::UnknownFont));
+
+        let is_tag = |tag: &str| {
+            (1..=4).contains(&tag.len()) && tag.bytes().all(|b| b.is_ascii_graphic() || b == b' ')
+        };
+        result.extend(
+            options
+                .features
+                .iter()
+                .filter(|feature| !is_tag(&feature.tag))
+                .map(|feature| Warning::InvalidFeatureTag(feature.tag.clone())),
+        );
+
+        // Paragraphs fall back to the fallback font and then to the default one like in layout.
+        let face_of = |font_id: &FontId| self.fonts.get(font_id).map(|font| font.face());
+        let fallback = face_of(&options.fallback_font)
+            .or_else(|| face_of(&GLOBAL_FALLBACK_FONT.into()))
+            .unwrap();
+        let mut uncovered = vec![];
+        for (paragraph, (start, end)) in paragraph_ranges(&text).into_iter().enumerate() {
+            let face = options
+                .paragraphs_fonts
+                .get(paragraph)
+                .and_then(face_of)
+                .unwrap_or(fallback);
+            for (i, c) in text[start..end].char_indices() {
+                // Whitespace and default ignorables don't need glyphs, and replacement
+                // characters stand for text that was broken before.
+                if c.is_whitespace()
+                    || c.is_control()
+                    || c == char::REPLACEMENT_CHARACTER
+                    || sets::default_ignorable_code_point().contains(c)
+                    || face.glyph_index(c).is_some()
+                {
+                    continue;
+                }
+                let script = maps::script().get(c);
+                let script_face = options.script_fonts.get(&script).and_then(face_of);
+                if script_face.is_some_and(|face| face.glyph_index(c).is_some())
+                    || uncovered.contains(&script)
+                {
+                    continue;
+                }
+                uncovered.push(script);
+                result.push(Warning::UncoveredScript {
+                    script,
+                    offset: start + i,
+                });
+            }
+        }
+
+        result
+    }
+
+    fn layout_text(
+        &self,
+        text: &str,
+        options: &LayoutOptions,
+        prev_layout: Option<&[Vec<ShapedFragment>]>,
+    ) -> (Layout, Option<Vec<Vec<ShapedFragment>>>) {
+        use icu::properties::bidi::BidiClassAdapter;
+        use icu::properties::maps;
+        use unicode_bidi::BidiInfo;
+
+        let adapter = BidiClassAdapter::new(maps::bidi_class());
+        let bidi_info = BidiInfo::new_with_data_source(&adapter, text, bidi_base_level(options));
+        let shaping_calls = self.shaping_calls.get();
+
+        let mut paragraphs = bidi_info.paragraphs.as_slice();
+        if options.drop_trailing_empty_paragraphs {
+            while let Some((last, rest)) = paragraphs.split_last() {
+                if !strip_paragraph_separator(&text[last.range.clone()]).is_empty() {
+                    break;
+                }
+                paragraphs = rest;
+            }
+        }
+        let mut layout_paragraps = Vec::<ParagraphSource>::with_capacity(paragraphs.len());
+
+        let mut line_height = 0.0_f64;
+        let has_ruby =
+            !options.ruby.is_empty() && options.writing_mode == WritingMode::HorizontalTb;
+
+        for (i, paragraph) in paragraphs.iter().enumerate() {
+            let line = paragraph.range.clone();
+            let is_last = i == (paragraphs.len() - 1);
+            let text_offset = line.start;
+            let paragraph_text = &text[line];
+            let display_str = String::from(strip_paragraph_separator(paragraph_text));
+            let hard_break = !is_last || display_str.len() < paragraph_text.len();
+            let is_rtl = paragraph.level.is_rtl();
+
+            let mut font_id = options
+                .paragraphs_fonts
+                .get(i)
+                .unwrap_or(&options.fallback_font);
+            let mut font = self.fonts.get(font_id);
+            if font.is_none() {
+                log!(
+                    "Can't draw text with font {} because it was not found! Using {} instead.",
+                    font_id,
+                    options.fallback_font,
+                );
+                font_id = &options.fallback_font;
+                font = self.fonts.get(font_id);
+                if font.is_none() {
+                    log!(
+                        "Can't draw text with font {} because it was not found! Using {} instead.",
+                        options.fallback_font,
+                        GLOBAL_FALLBACK_FONT
+                    );
+                }
+            }
+            let (font_id, font): (FontId, &Font) = match font {
+                Some(font) => (font_id.clone(), font),
+                None => (
+                    GLOBAL_FALLBACK_FONT.into(),
+                    self.fonts.get(GLOBAL_FALLBACK_FONT).unwrap(),
+                ),
+            };
+            let to_px =
+                Self::pixels_per_unit(font.face(), options.size, options.size_normalization);
+            let face_height = (font.face().height() as f64) * to_px;
+            let ruby_height = if has_ruby {
+                face_height * RUBY_SCALE
+            } else {
+                0.0
+            };
+            line_height = line_height.max(face_height + ruby_height);
+            layout_paragraps.push(ParagraphSource {
+                text: display_str,
+                text_offset,
+                font_id,
+                font,
+                is_rtl,
+                hard_break,
+                ascent: font.face().ascender() as f64 * to_px,
+                descent: -font.face().descender() as f64 * to_px,
+            });
+        }
+
+        let (mut layout, new_layout) =
+            self.perform_layout_on_paragraphs(options, line_height, &layout_paragraps, prev_layout);
+        if has_ruby {
+            for ruby in options.ruby.iter() {
+                self.place_ruby(ruby, options, &layout_paragraps, &mut layout);
+            }
+        }
+        for glyph in layout.glyphs.iter_mut() {
+            glyph.color = options
+                .colors
+                .iter()
+                .rfind(|range| (range.start..range.end).contains(&glyph.cluster))
+                .map(|range| range.color.clone());
+        }
+        for glyph in layout
+            .glyphs
+            .iter_mut()
+            .chain(layout.ruby_glyphs.iter_mut())
+        {
+            let Rect { x, y, w, h } = glyph.bounds;
+            let center = DVec2::new(x + w / 2.0, y + h / 2.0);
+            glyph.opacity = options
+                .fades
+                .iter()
+                .map(|fade| fade.opacity(center))
+                .product();
+        }
+        for highlight in options.highlights.iter() {
+            for rect in layout.selection_rects(highlight.start, highlight.end) {
+                layout.highlights.push(HighlightRect {
+                    rect,
+                    color: highlight.color.clone(),
+                });
+            }
+        }
+        if options.writing_mode == WritingMode::HorizontalTb {
+            for decoration in options.decorations.iter() {
+                self.place_decoration(decoration, options, &layout_paragraps, &mut layout);
+            }
+        }
+        layout.skeleton = match options.skeleton {
+            Skeleton::Off => vec![],
+            Skeleton::Lines => layout
+                .lines
+                .iter()
+                .map(|line| layout.line_rect(line))
+                .collect(),
+            Skeleton::Words => {
+                layout.piece_rects(|glyph| !text[glyph.cluster..].starts_with(char::is_whitespace))
+            }
+        };
+        layout.needs_reordering = mixes_levels(&bidi_info.levels);
+        layout.shaping_calls = self.shaping_calls.get() - shaping_calls;
+        if let Some(jitter) = options.jitter {
+            let glyphs = layout
+                .glyphs
+                .iter_mut()
+                .chain(layout.ruby_glyphs.iter_mut());
+            for (i, glyph) in glyphs.enumerate() {
+                let transform = jitter.transform(i, &glyph.bounds) * glyph.transform;
+                glyph.set_transform(transform, options.device_pixel_ratio);
+            }
+        }
+        if let Some(clip) = options.clip {
+            layout.cull(&clip);
+        }
+        if options.empty_outlines == EmptyOutlines::Skip && options.skeleton == Skeleton::Off {
+            layout.retain_glyphs(|glyph| !glyph.path.is_empty());
+        }
+        if let Some(transform) = options.transform {
+            let transform = DAffine2::from_cols_array(&transform);
+            for glyph in layout
+                .glyphs
+                .iter_mut()
+                .chain(layout.ruby_glyphs.iter_mut())
+            {
+                glyph.set_transform(transform * glyph.transform, options.device_pixel_ratio);
+            }
+        }
+        if !options.y_down {
+            layout.mirror_y(options.device_pixel_ratio);
+        }
+        for glyph in layout
+            .glyphs
+            .iter_mut()
+            .chain(layout.ruby_glyphs.iter_mut())
+        {
+            glyph.finish_path(options);
+        }
+
+        (layout, new_layout)
+    }
+
+    /// Adds the rectangles of `decoration` to [`Layout::decorations`], at the position and with
+    /// the thickness the font of each line's paragraph has for the line.
+    fn place_decoration(
+        &self,
+        decoration: &Decoration,
+        options: &LayoutOptions,
+        paragraphs: &[ParagraphSource],
+        layout: &mut Layout,
+    ) {
+        let range = decoration.start..decoration.end;
+        let in_range = |glyph: &LayoutGlyph| range.contains(&glyph.cluster);
+        for (line_index, piece) in layout.line_piece_rects(in_range) {
+            let line = &layout.lines[line_index];
+            let face = paragraphs[line.paragraph].font.face();
+            let to_px = Self::pixels_per_unit(face, options.size, options.size_normalization);
+            let metrics = match decoration.line {
+                DecorationLine::Underline => face.underline_metrics(),
+                DecorationLine::LineThrough => face.strikeout_metrics(),
+            };
+            // Fonts without the metrics get a line a twentieth of the em thick, below the
+            // baseline or half way up to the x-height.
+            let metrics = metrics.unwrap_or_else(|| {
+                let thickness = (face.units_per_em() / 20) as i16;
+                let position = match decoration.line {
+                    DecorationLine::Underline => -thickness * 2,
+                    DecorationLine::LineThrough => face.x_height().unwrap_or(0) / 2 + thickness / 2,
+                };
+                hb::ttf_parser::LineMetrics {
+                    position,
+                    thickness,
+                }
+            });
+            // The position is the top of the line, above the baseline.
+            layout.decorations.push(DecorationRect {
+                rect: Rect {
+                    x: piece.x,
+                    y: line.y - metrics.position as f64 * to_px,
+                    w: piece.w,
+                    h: metrics.thickness as f64 * to_px,
+                },
+                line: decoration.line,
+                color: decoration.color.clone(),
+            });
+        }
+    }
+
+    /// Shapes `text` as one line starting at the origin, for [`shape`].
+    fn shape_text(&self, text: &str, options: &LayoutOptions) -> Vec<LayoutGlyph> {
+        use icu::properties::bidi::BidiClassAdapter;
+        use icu::properties::maps;
+        use unicode_bidi::BidiInfo;
+
+        let (font_id, font) = match self.fonts.get(&options.fallback_font) {
+            Some(font) => (options.fallback_font.as_str(), font),
+            None => {
+                log!(
+                    "Can't shape text with font {} because it was not found! Using {} instead.",
+                    options.fallback_font,
+                    GLOBAL_FALLBACK_FONT
+                );
+                (
+                    GLOBAL_FALLBACK_FONT,
+                    self.fonts.get(GLOBAL_FALLBACK_FONT).unwrap(),
+                )
+            }
+        };
+        let adapter = BidiClassAdapter::new(maps::bidi_class());
+        let bidi_info = BidiInfo::new_with_data_source(&adapter, text, bidi_base_level(options));
+        let is_rtl = bidi_info
+            .paragraphs
+            .first()
+            .is_some_and(|paragraph| paragraph.level.is_rtl());
+        let options = LayoutOptions {
+            writing_mode: WritingMode::HorizontalTb,
+            ..options.clone()
+        };
+
+        let mut fragments = self.shape_static_text(
+            text,
+            0,
+            (font_id, font.face()),
+            &options,
+            is_rtl,
+            options.max_glyphs,
+        );
+
+        let mut x = 0.0;
+        let mut result = Vec::<LayoutGlyph>::new();
+        for run in VisualRun::of(&fragments) {
+            // The spacing between two fragments goes to the glyph before the gap.
+            if let (true, Some(glyph)) = (run.gap_before, result.last_mut()) {
+                glyph.advance += options.letter_spacing;
+                x += options.letter_spacing;
+            }
+            for glyph in fragments[run.fragment].glyphs[run.glyphs].iter_mut() {
+                let offset = DVec2::new(x - glyph.origin.x, 0.0);
+                result.push(glyph.place(offset, options.device_pixel_ratio));
+                x += glyph.advance_x;
+            }
+            if let (true, Some(glyph)) = (run.gap_after, result.last_mut()) {
+                glyph.advance += options.letter_spacing;
+                x += options.letter_spacing;
+            }
+        }
+        // Runs are placed in visual order, but the glyphs are given in logical order.
+        result.sort_by_key(|glyph| glyph.cluster);
+        for glyph in result.iter_mut() {
+            if !options.y_down {
+                glyph.mirror_y(options.device_pixel_ratio);
+            }
+            glyph.finish_path(&options);
         }
 
-        !self.already_performed_layout
+        result
     }
 
-    fn resolve_input(&mut self, input_transform: &InputTransform, input: usize) -> Vec<String> {
-        use icu::properties::bidi::BidiClassAdapter;
-        use icu::properties::maps;
-        use unicode_bidi::BidiInfo;
-
-        let adapter = BidiClassAdapter::new(maps::bidi_class());
-        let bidi_info =
-            BidiInfo::new_with_data_source(&adapter, self.inputs[input].text.as_ref(), None);
+    /// Shapes `ruby` with the font of its base and centers it above the part of the base on the
+    /// line the base starts on.
+    fn place_ruby(
+        &self,
+        ruby: &Ruby,
+        options: &LayoutOptions,
+        paragraphs: &[ParagraphSource],
+        layout: &mut Layout,
+    ) {
+        let in_base = |glyph: &&LayoutGlyph| (ruby.start..ruby.end).contains(&glyph.cluster);
+        let Some(line) = layout.lines.iter().find(|line| {
+            layout.glyphs[line.glyphs.clone()]
+                .iter()
+                .any(|glyph| in_base(&glyph))
+        }) else {
+            log!(
+                "Can't place ruby {} because its base is not laid out!",
+                ruby.text
+            );
+            return;
+        };
+        let base = layout.glyphs[line.glyphs.clone()].iter().filter(in_base);
+        let (left, right) = base.fold((f64::MAX, f64::MIN), |(left, right), glyph| {
+            (left.min(glyph.x), right.max(glyph.x + glyph.advance))
+        });
 
-        let mut layout_paragraps =
-            Vec::<(String, &Font, bool)>::with_capacity(bidi_info.paragraphs.len());
+        let source = &paragraphs[line.paragraph];
+        let (font_id, font) = (&source.font_id, source.font);
+        let ruby_options = LayoutOptions {
+            size: ((options.size as f64 * RUBY_SCALE).round() as usize).max(1),
+            size_ranges: vec![],
+            features: vec![],
+            text_transforms: vec![],
+            letter_spacing: 0.0,
+            break_opportunities: vec![],
+            suppressed_breaks: vec![],
+            ..options.clone()
+        };
+        let to_px =
+            Self::pixels_per_unit(font.face(), ruby_options.size, options.size_normalization);
+        let descent = -font.face().descender() as f64 * to_px;
+        let mut fragments = self.shape_static_text(
+            &ruby.text,
+            0,
+            (font_id, font.face()),
+            &ruby_options,
+            false,
+            None,
+        );
 
-        let mut line_height = 0.0_f64;
+        let width = fragments
+            .iter()
+            .map(|fragment| fragment.length)
+            .sum::<f64>();
+        let mut x = (left + right - width) / 2.0;
+        let baseline_y = line.y - line.ascent - descent;
+        for fragment in fragments.iter_mut() {
+            let offset = DVec2::new(x, baseline_y);
+            for glyph in fragment.glyphs.iter_mut() {
+                layout
+                    .ruby_glyphs
+                    .push(glyph.place(offset, options.device_pixel_ratio));
+            }
+            x += fragment.length;
+        }
+    }
 
-        for (i, paragraph) in bidi_info.paragraphs.iter().enumerate() {
-            let line = paragraph.range.clone();
-            let display_str: String = String::from(if i == (bidi_info.paragraphs.len() - 1) {
-                let initial_guess = &self.inputs[input].text[line.start..line.end];
-                if initial_guess.ends_with("\n") {
-                    &self.inputs[input].text[line.start..line.end - 1]
-                } else {
-                    initial_guess
+    /// JSON for [`Layout::break_diagnostics`], `max_line_length` giving the room of every line of
+    /// the text from the index of its paragraph and its line number.
+    fn break_diagnostics(
+        sources: &[ParagraphSource],
+        paragraphs: &[ParagraphInfo],
+        max_line_length: impl Fn(usize, usize) -> f64,
+        fragment_spacing: f64,
+    ) -> String {
+        let mut json = String::from("{\"paragraphs\":[");
+        let mut line_number = 0;
+        for (i, (source, paragraph)) in sources.iter().zip(paragraphs.iter()).enumerate() {
+            if i > 0 {
+                json += ",";
+            }
+            json += "{\"fragments\":[";
+            let mut x = 0.0;
+            for (j, fragment) in paragraph.shaped_fragments.iter().enumerate() {
+                let start = fragment.source.start - source.text_offset;
+                let end = fragment.source.end - source.text_offset;
+                let text = source.text.get(start..end).unwrap_or_default();
+                if j > 0 {
+                    json += ",";
                 }
-            } else {
-                &self.inputs[input].text[line.start..line.end - 1]
-            });
-            let is_rtl = paragraph.level.is_rtl();
-
-            let mut font = self.fonts.get(&self.inputs[input].paragraphs_fonts[i]);
-            if font.is_none() {
-                log!(
-                    "Can't draw text with font {} because it was not found! Using {} instead.",
-                    self.inputs[input].paragraphs_fonts[i],
-                    self.inputs[input].fallback_font,
+                json += &format!(
+                    "{{\"text\":{},\"width\":{},\"x\":{}}}",
+                    json_string(text),
+                    fragment.length,
+                    x
                 );
-                font = self.fonts.get(&self.inputs[input].fallback_font);
-                if font.is_none() {
-                    log!(
-                        "Can't draw text with font {} because it was not found! Using {} instead.",
-                        self.inputs[input].fallback_font,
-                        GLOBAL_FALLBACK_FONT
-                    );
+                x += fragment.length;
+            }
+            json += "],\"lines\":[";
+            for (j, line) in paragraph.lines.iter().enumerate() {
+                let first = line.first_fragment_index;
+                let reason = if j == 0 {
+                    "start"
+                } else if paragraph.shaped_fragments[first - 1].mandatory_break {
+                    "mandatory_break"
+                } else {
+                    // The fragment didn't fit on the line before, or balancing moved it.
+                    let previous = &paragraph.lines[j - 1];
+                    let fragment = &paragraph.shaped_fragments[first];
+                    let length = previous.line_length + fragment_spacing + fragment.length;
+                    if length > max_line_length(i, line_number - 1) {
+                        "overflow"
+                    } else {
+                        "balance"
+                    }
+                };
+                if j > 0 {
+                    json += ",";
                 }
+                json += &format!(
+                    "{{\"first_fragment\":{},\"length\":{},\"max_length\":{},\"reason\":\"{}\"}}",
+                    first,
+                    line.line_length,
+                    max_line_length(i, line_number),
+                    reason
+                );
+                line_number += 1;
             }
-            let font = font.unwrap_or(self.fonts.get(GLOBAL_FALLBACK_FONT).unwrap());
-            let face_height = (font.face.height() as f64) * (input_transform.size as f64)
-                / (font.face.units_per_em() as f64);
-            line_height = line_height.max(face_height);
-            layout_paragraps.push((display_str, font, is_rtl));
-        }
-
-        let (result, new_layout) = self.perform_layout_on_paragraphs(
-            input,
-            input_transform,
-            line_height,
-            &layout_paragraps,
-            self.inputs[input].horizontal_alignment,
-            self.inputs[input].vertical_alignment,
-        );
-        self.already_performed_layout = true;
-        if let Some(value) = new_layout {
-            self.prev_layout = value;
-            self.last_input = input;
-            self.last_text_size = input_transform.size;
+            json += "]}";
         }
+        json += "]}";
 
-        result
+        json
     }
 
+    /// `block_height` is the distance taken by all the lines: the first one's height, the line
+    /// height for the others and whatever taller lines need on top. The first baseline is
+    /// `first_ascent` below the top of the block, before the first line makes room for taller
+    /// glyphs.
     fn init_baseline_y(
-        input_transform: &InputTransform,
-        pad: f64,
-        line_height: f64,
-        num_lines: usize,
-        v_align: VerticalAlignment,
+        options: &LayoutOptions,
+        content: &Rect,
+        first_ascent: f64,
+        block_height: f64,
     ) -> f64 {
-        match v_align {
-            VerticalAlignment::Normal => (input_transform.y as f64) + pad + line_height,
-            VerticalAlignment::Center => {
-                let center_baseline = (input_transform.y as f64)
-                    + (input_transform.h as f64) / 2.0
-                    + line_height / 2.0;
+        let top = match options.vertical_alignment {
+            VerticalAlignment::Normal => content.y,
+            VerticalAlignment::Center => content.y + (content.h - block_height) / 2.0,
+            VerticalAlignment::Reverse => content.y + content.h - block_height,
+        };
 
-                center_baseline - line_height * (num_lines as f64) / 2.0
-            }
-            VerticalAlignment::Reverse => {
-                let bottom_baseline = (input_transform.y + input_transform.h) as f64 - pad;
+        top + first_ascent
+    }
 
-                bottom_baseline - line_height * (num_lines as f64)
-            }
+    /// Alignment of paragraph `paragraph` from [`LayoutOptions::paragraphs_alignment`].
+    fn paragraph_alignment(options: &LayoutOptions, paragraph: usize) -> HorizontalAlignment {
+        options
+            .paragraphs_alignment
+            .get(paragraph)
+            .copied()
+            .unwrap_or(options.horizontal_alignment)
+    }
+
+    /// Alignment of a line of paragraph `paragraph` that ends it or not, with the last line of a
+    /// justified paragraph resolved to what it is aligned like.
+    fn line_alignment(
+        options: &LayoutOptions,
+        paragraph: usize,
+        is_last_line: bool,
+    ) -> HorizontalAlignment {
+        match (Self::paragraph_alignment(options, paragraph), is_last_line) {
+            (HorizontalAlignment::Justify, true) => match options.last_line_alignment {
+                LastLineAlignment::Start => HorizontalAlignment::Normal,
+                LastLineAlignment::Justify => HorizontalAlignment::Justify,
+                LastLineAlignment::Center => HorizontalAlignment::Center,
+                LastLineAlignment::End => HorizontalAlignment::Reverse,
+            },
+            (alignment, _) => alignment,
         }
     }
 
     fn init_baseline_x(
-        input_transform: &InputTransform,
-        pad: f64,
+        alignment: HorizontalAlignment,
+        content: &Rect,
         is_rtl: bool,
-        h_align: HorizontalAlignment,
         line_length: f64,
     ) -> f64 {
-        match (is_rtl, h_align) {
-            (false, HorizontalAlignment::Normal) => (input_transform.x as f64) + pad,
-            (true, HorizontalAlignment::Normal) => {
-                ((input_transform.x + input_transform.w) as f64) - pad
+        match (is_rtl, alignment) {
+            (false, HorizontalAlignment::Normal | HorizontalAlignment::Justify) => content.x,
+            (true, HorizontalAlignment::Normal | HorizontalAlignment::Justify) => {
+                content.x + content.w
+            }
+            (false, HorizontalAlignment::Center) => content.x + content.w / 2.0 - line_length / 2.0,
+            (true, HorizontalAlignment::Center) => content.x + content.w / 2.0 + line_length / 2.0,
+            (false, HorizontalAlignment::Reverse) => content.x + content.w - line_length,
+            (true, HorizontalAlignment::Reverse) => content.x + line_length,
+        }
+    }
+
+    /// Gives the tabs on the line made of `fragments` of paragraph `source` the advance that gets
+    /// the text after them to their [`LayoutOptions::tab_stops`], and returns how much longer
+    /// that makes the line. `trim` is the leading whitespace the line drops, the glyphs are
+    /// walked like the placement loop in `perform_layout_on_paragraphs` does.
+    fn expand_tabs(
+        fragments: &mut [ShapedFragment],
+        source: &ParagraphSource,
+        trim: f64,
+        options: &LayoutOptions,
+    ) -> f64 {
+        if options.tab_stops.is_empty() {
+            return 0.0;
+        }
+        let char_at = |cluster: usize| {
+            let offset = cluster.checked_sub(source.text_offset)?;
+            source.text.get(offset..)?.chars().next()
+        };
+
+        // Fragment, glyph, pen position from the start of the line, cluster and advance of every
+        // glyph kept on the line.
+        let mut pens = vec![];
+        let mut x = 0.0;
+        for run in VisualRun::of(fragments) {
+            if run.gap_before {
+                x += options.letter_spacing;
+            }
+            let fragment = &fragments[run.fragment];
+            let whitespace_end = fragment.leading_whitespace_end;
+            for i in run.glyphs {
+                let glyph = &fragment.glyphs[i];
+                if run.fragment == 0 && trim > 0.0 && glyph.cluster < whitespace_end {
+                    continue;
+                }
+                pens.push((run.fragment, i, x, glyph.cluster, glyph.advance_x));
+                x += glyph.advance_x;
+            }
+            if run.gap_after {
+                x += options.letter_spacing;
+            }
+        }
+        let line_end = x - fragments
+            .last()
+            .map_or(0.0, |fragment| fragment.trailing_whitespace);
+        let pen_at = |i: usize| pens.get(i).map_or(x, |pen| pen.2);
+        let is_tab = |i: usize| char_at(pens[i].3) == Some('\t');
+
+        let mut added = 0.0;
+        let mut i = 0;
+        while i < pens.len() {
+            if !is_tab(i) {
+                i += 1;
+                continue;
             }
-            (false, HorizontalAlignment::Center) => {
-                (input_transform.x as f64) + (input_transform.w as f64) / 2.0 - line_length / 2.0
+            // Soft tabs are several glyphs of the same cluster.
+            let tab = i;
+            while i < pens.len() && is_tab(i) && pens[i].3 == pens[tab].3 {
+                i += 1;
             }
-            (true, HorizontalAlignment::Center) => {
-                (input_transform.x as f64) + (input_transform.w as f64) / 2.0 + line_length / 2.0
+            let next_tab = (i..pens.len()).find(|&j| is_tab(j)).unwrap_or(pens.len());
+            let text_start = pen_at(i);
+            let text_end = if next_tab < pens.len() {
+                pen_at(next_tab)
+            } else {
+                line_end
+            };
+            let text_width = (text_end - text_start).max(0.0);
+            let tab_start = pens[tab].2 + added;
+            let stop = options
+                .tab_stops
+                .iter()
+                .filter(|stop| stop.position > tab_start)
+                .min_by(|a, b| a.position.total_cmp(&b.position));
+            let Some(stop) = stop else {
+                continue;
+            };
+            let before_stop = match stop.alignment {
+                TabAlignment::Left => 0.0,
+                TabAlignment::Center => text_width / 2.0,
+                TabAlignment::Right => text_width,
+                TabAlignment::Decimal => (i..next_tab)
+                    .find(|&j| char_at(pens[j].3) == Some('.'))
+                    .map_or(text_width, |j| pen_at(j) - text_start),
+            };
+            let advance = pens[tab..i].iter().map(|pen| pen.4).sum::<f64>();
+            // Whatever else is between the tab and the text, like letter spacing, stays.
+            let between = text_start - pens[tab].2 - advance;
+            let new_advance = stop.position - before_stop - tab_start - between;
+            if new_advance < 0.0 {
+                continue;
             }
-            (false, HorizontalAlignment::Reverse) => {
-                let start = (input_transform.x as f64) + pad;
-                let textbox_width = input_transform.w as f64 - 2.0 * pad;
 
-                start + (textbox_width - line_length)
+            for (j, &(fragment, glyph, ..)) in pens[tab..i].iter().enumerate() {
+                let glyph = &mut fragments[fragment].glyphs[glyph];
+                glyph.advance_x = if j == 0 { new_advance } else { 0.0 };
             }
-            (true, HorizontalAlignment::Reverse) => {
-                let start = ((input_transform.x + input_transform.w) as f64) - pad;
-                let textbox_width = input_transform.w as f64 - 2.0 * pad;
+            fragments[pens[tab].0].length += new_advance - advance;
+            added += new_advance - advance;
+        }
+
+        added
+    }
 
-                start - (textbox_width - line_length)
+    /// The part of `content` across line `line` of horizontal text, counting from the top, that
+    /// is left once [`LayoutOptions::exclusions`] are taken out of it.
+    fn line_content(
+        options: &LayoutOptions,
+        content: &Rect,
+        line_height: f64,
+        line: usize,
+    ) -> Rect {
+        let top = content.y + line as f64 * line_height;
+        let (mut left, mut right) = (content.x, content.x + content.w);
+        for exclusion in options.exclusions.iter() {
+            let across = exclusion.y < top + line_height && top < exclusion.y + exclusion.h;
+            let along = exclusion.x < right && left < exclusion.x + exclusion.w;
+            if !(across && along) {
+                continue;
+            }
+            if exclusion.x + exclusion.w / 2.0 < content.x + content.w / 2.0 {
+                left = left.max(exclusion.x + exclusion.w);
+            } else {
+                right = right.min(exclusion.x);
             }
         }
+
+        Rect {
+            x: left,
+            y: top,
+            w: right - left,
+            h: line_height,
+        }
     }
 
     fn perform_layout_on_paragraphs(
         &self,
-        input: usize,
-        input_transform: &InputTransform,
+        options: &LayoutOptions,
         line_height: f64,
-        paragraphs: &[(String, &Font, bool)],
-        h_align: HorizontalAlignment,
-        v_align: VerticalAlignment,
-    ) -> (Vec<String>, Option<Vec<Vec<ShapedFragment>>>) {
-        const PAD: f64 = 12.0;
-        let max_line_length = (input_transform.w as f64 - 2.0 * PAD).max(0.0);
-        let mut result = vec![];
+        paragraphs: &[ParagraphSource],
+        prev_layout: Option<&[Vec<ShapedFragment>]>,
+    ) -> (Layout, Option<Vec<Vec<ShapedFragment>>>) {
+        let content = options.content_box();
+        let max_line_length = |line: usize| match options.writing_mode {
+            WritingMode::HorizontalTb => Self::line_content(options, &content, line_height, line)
+                .w
+                .max(0.0),
+            WritingMode::VerticalRl => content.h.max(0.0),
+        };
+        // Room of line `line` of the text, which is in paragraph `paragraph`.
+        let paragraph_line_length = |paragraph: usize, line: usize| {
+            let room = max_line_length(line);
+            match options
+                .paragraphs_max_width
+                .get(paragraph)
+                .copied()
+                .flatten()
+            {
+                Some(width) => width.max(0.0).min(room),
+                None => room,
+            }
+        };
+        let mut result = Layout {
+            line_height,
+            writing_mode: options.writing_mode,
+            fill_rule: options.fill_rule,
+            ..Default::default()
+        };
         let mut new_layout = None;
 
         let mut total_number_of_lines = 0;
         let mut shaped_paragraphs = Vec::<ParagraphInfo>::with_capacity(paragraphs.len());
 
-        if self.needs_to_redo_layout(input, input_transform.size) {
-            new_layout = Some(vec![]);
-
-            for (text, font, is_rtl) in paragraphs.iter() {
-                let shaped_fragments =
-                    self.shape_static_text(text, &font.face, input_transform, *is_rtl);
-                new_layout.as_mut().unwrap().push(shaped_fragments.clone());
-                let paragraph = ParagraphInfo::new(shaped_fragments, max_line_length, *is_rtl);
+        if let Some(prev_layout) = prev_layout {
+            // Paragraphs left out by `max_glyphs` aren't in the previous layout either.
+            let sources = paragraphs.iter().zip(prev_layout.iter());
+            for (paragraph_index, (source, shaped_fragments)) in sources.enumerate() {
+                let shaped_fragments = shaped_fragments.clone();
+                let first_line = total_number_of_lines;
+                let paragraph = ParagraphInfo::new(
+                    shaped_fragments,
+                    source.text_offset..source.text_offset + source.text.len(),
+                    |line| paragraph_line_length(paragraph_index, first_line + line),
+                    options,
+                    source.is_rtl,
+                    source.hard_break,
+                );
                 total_number_of_lines += paragraph.lines.len();
                 shaped_paragraphs.push(paragraph);
             }
         } else {
-            for (i, (_, _, is_rtl)) in paragraphs.iter().enumerate() {
-                let shaped_fragments = self.prev_layout[i].clone();
-                let paragraph = ParagraphInfo::new(shaped_fragments, max_line_length, *is_rtl);
+            new_layout = Some(vec![]);
+
+            let mut glyph_count = 0;
+            for (paragraph_index, source) in paragraphs.iter().enumerate() {
+                let glyph_budget = options
+                    .max_glyphs
+                    .map(|max| max.saturating_sub(glyph_count));
+                if glyph_budget == Some(0) {
+                    break;
+                }
+                let shaped_fragments = self.shape_static_text(
+                    &source.text,
+                    source.text_offset,
+                    (&source.font_id, source.font.face()),
+                    options,
+                    source.is_rtl,
+                    glyph_budget,
+                );
+                glyph_count += shaped_fragments
+                    .iter()
+                    .map(|fragment| fragment.glyphs.len())
+                    .sum::<usize>();
+                let source_end = source.text_offset + source.text.len();
+                let is_whole = shaped_fragments
+                    .last()
+                    .map_or(source.text.is_empty(), |fragment| {
+                        fragment.source.end == source_end
+                    });
+                new_layout.as_mut().unwrap().push(shaped_fragments.clone());
+                let first_line = total_number_of_lines;
+                let paragraph = ParagraphInfo::new(
+                    shaped_fragments,
+                    source.text_offset..source.text_offset + source.text.len(),
+                    |line| paragraph_line_length(paragraph_index, first_line + line),
+                    options,
+                    source.is_rtl,
+                    source.hard_break,
+                );
                 total_number_of_lines += paragraph.lines.len();
                 shaped_paragraphs.push(paragraph);
+                if !is_whole {
+                    break;
+                }
             }
         }
+        if options.break_diagnostics {
+            result.break_diagnostics = Some(Self::break_diagnostics(
+                paragraphs,
+                &shaped_paragraphs,
+                paragraph_line_length,
+                options.letter_spacing,
+            ));
+        }
 
-        let mut baseline_y = Self::init_baseline_y(
-            input_transform,
-            PAD,
-            line_height,
-            total_number_of_lines,
-            v_align,
-        );
+        // How much the lines with glyphs taller or deeper than their font at `options.size`
+        // need on each side of the baseline. Proportional lines get what their own height
+        // differs by from `line_height` instead, which can be less than nothing.
+        let extra_space = shaped_paragraphs
+            .iter()
+            .zip(paragraphs.iter())
+            .flat_map(|(paragraph, source)| {
+                paragraph
+                    .lines
+                    .iter()
+                    .map(|line| match options.line_height_ratio {
+                        Some(ratio) if options.writing_mode == WritingMode::HorizontalTb => {
+                            let (size, ascent, descent) = if line.size > 0 {
+                                (line.size, line.ascent, line.descent)
+                            } else {
+                                (options.size, source.ascent, source.descent)
+                            };
+                            let half_leading = (ratio * size as f64 - ascent - descent) / 2.0;
+                            (
+                                half_leading + ascent - (line_height - source.descent),
+                                half_leading + descent - source.descent,
+                            )
+                        }
+                        _ => (
+                            (line.ascent - source.ascent).max(0.0),
+                            (line.descent - source.descent).max(0.0),
+                        ),
+                    })
+            })
+            .collect::<Vec<_>>();
+        // Trimmed blocks start at the ascent of the first line instead of a whole line above it.
+        let first_line_height = match paragraphs.first() {
+            Some(source) if options.leading_trim => source.ascent.min(line_height),
+            _ => line_height,
+        };
+        // Line boxes have the descent of the font below the baseline, so the first baseline is as
+        // far below the top of the block as the tallest glyphs of the font need.
+        let first_ascent = match paragraphs.first() {
+            Some(source) => (line_height - source.descent).min(first_line_height),
+            None => first_line_height,
+        };
+        let block_height = line_height * total_number_of_lines as f64 - line_height
+            + first_line_height
+            + extra_space
+                .iter()
+                .map(|(above, below)| above + below)
+                .sum::<f64>();
 
-        for paragraph in shaped_paragraphs.iter_mut() {
-            let is_rtl = paragraph.is_rtl;
+        let available = match options.writing_mode {
+            WritingMode::HorizontalTb => content.h,
+            WritingMode::VerticalRl => content.w,
+        };
+        result.overflow = match options.writing_mode {
+            WritingMode::HorizontalTb => block_height > available,
+            WritingMode::VerticalRl => line_height * total_number_of_lines as f64 > available,
+        };
+        // Fragments cover their paragraph without gaps, so the text is whole unless shaping
+        // stopped before the end of a paragraph.
+        result.truncated = paragraphs.iter().enumerate().any(|(i, source)| {
+            let shaped_end = shaped_paragraphs
+                .get(i)
+                .map_or(source.text_offset, |paragraph| {
+                    paragraph
+                        .shaped_fragments
+                        .last()
+                        .map_or(source.text_offset, |fragment| fragment.source.end)
+                });
+            shaped_end < source.text_offset + source.text.len()
+        });
+        result.notdef_count = shaped_paragraphs
+            .iter()
+            .flat_map(|paragraph| paragraph.shaped_fragments.iter())
+            .flat_map(|fragment| fragment.glyphs.iter())
+            .filter(|glyph| glyph.glyph_id == 0)
+            .count();
+
+        if options.writing_mode == WritingMode::VerticalRl {
+            Self::place_columns(
+                options,
+                &content,
+                line_height,
+                total_number_of_lines,
+                &mut shaped_paragraphs,
+                &mut result,
+            );
+
+            return (result, new_layout);
+        }
+
+        let mut baseline_y = Self::init_baseline_y(options, &content, first_ascent, block_height);
+        let mut extra_space = extra_space.into_iter();
+        let mut line_number = 0;
 
-            for line in paragraph.lines.iter() {
-                let mut baseline_x =
-                    Self::init_baseline_x(input_transform, PAD, is_rtl, h_align, line.line_length);
+        for (paragraph_index, paragraph) in shaped_paragraphs.iter_mut().enumerate() {
+            let is_rtl = paragraph.is_rtl;
 
+            for (line_index, line) in paragraph.lines.iter().enumerate() {
+                let (extra_above, extra_below) = extra_space.next().unwrap();
+                baseline_y += extra_above;
+                let is_last_line = !line.has_next_line || line.hard_break;
+                let alignment = Self::line_alignment(options, paragraph_index, is_last_line);
                 let start = line.first_fragment_index;
                 let end = if line.has_next_line {
                     line.last_fragment_index
                 } else {
                     paragraph.shaped_fragments.len()
                 };
+                let trim = match paragraph.shaped_fragments.get(start) {
+                    Some(fragment) if line_index > 0 => fragment.leading_whitespace,
+                    _ => 0.0,
+                };
+                let tabs = if is_rtl || alignment == HorizontalAlignment::Justify {
+                    0.0
+                } else {
+                    Self::expand_tabs(
+                        &mut paragraph.shaped_fragments[start..end],
+                        &paragraphs[paragraph_index],
+                        trim,
+                        options,
+                    )
+                };
+                let line_content = Self::line_content(options, &content, line_height, line_number);
+                let visible_length = line.visible_length() + tabs;
+                let baseline_x =
+                    Self::init_baseline_x(alignment, &line_content, is_rtl, visible_length);
+                let line_baseline_y = if options.pixel_snap {
+                    baseline_y.round()
+                } else {
+                    baseline_y
+                };
+                let gaps = end.saturating_sub(start + 1);
+                let justification = if alignment == HorizontalAlignment::Justify && gaps > 0 {
+                    let room = paragraph_line_length(paragraph_index, line_number);
+                    ((room - line.visible_length()) / gaps as f64).max(0.0)
+                } else {
+                    0.0
+                };
+                let width = line.line_length + tabs + justification * gaps as f64;
 
-                for fragment in paragraph.shaped_fragments[start..end].iter_mut() {
-                    let new_baseline_x = if is_rtl {
-                        baseline_x - fragment.length
+                let first_glyph = result.glyphs.len();
+                result.lines.push(LayoutLine {
+                    paragraph: paragraph_index,
+                    rtl: paragraph.is_rtl,
+                    x: if is_rtl {
+                        baseline_x - width
                     } else {
                         baseline_x
-                    };
+                    },
+                    y: line_baseline_y,
+                    width,
+                    ascent: paragraphs[paragraph_index].ascent.max(line.ascent),
+                    descent: paragraphs[paragraph_index].descent.max(line.descent),
+                    hard_break: line.hard_break,
+                    glyphs: first_glyph..first_glyph,
+                    source: paragraph.source_of(start..end),
+                    words: paragraph.words_of(start..end),
+                });
 
-                    let offset = DVec2::new(new_baseline_x, baseline_y);
-                    for glyph in fragment.glyphs.iter_mut() {
-                        glyph.translate(offset);
-                        result.push(glyph.svg_path_string.clone());
+                let fragments = &mut paragraph.shaped_fragments[start..end];
+                let spacing = options.letter_spacing + justification;
+                // Right-to-left lines start at the right margin, but every line is placed from
+                // its left edge so that the runs of both directions can follow in visual order.
+                let mut x = if is_rtl {
+                    let length = fragments
+                        .iter()
+                        .map(|fragment| fragment.length)
+                        .sum::<f64>();
+                    baseline_x - (length - trim + spacing * gaps as f64)
+                } else {
+                    baseline_x
+                };
+                let mut placed = fragments
+                    .iter()
+                    .map(|fragment| vec![None; fragment.glyphs.len()])
+                    .collect::<Vec<_>>();
+                for run in VisualRun::of(fragments) {
+                    if run.gap_before {
+                        x += spacing;
+                    }
+                    let fragment = &mut fragments[run.fragment];
+                    let whitespace_end = fragment.leading_whitespace_end;
+                    for i in run.glyphs {
+                        let glyph = &mut fragment.glyphs[i];
+                        let offset = DVec2::new(x - glyph.origin.x, line_baseline_y);
+                        let offset = options.positioning.snap(offset, glyph.origin);
+                        let mut placed_glyph = glyph.place(offset, options.device_pixel_ratio);
+                        if run.fragment == 0 && trim > 0.0 && glyph.cluster < whitespace_end {
+                            placed_glyph.advance = 0.0;
+                        } else {
+                            x += glyph.advance_x;
+                        }
+                        placed[run.fragment][i] = Some(placed_glyph);
+                    }
+                    if run.gap_after {
+                        x += spacing;
+                    }
+                }
+                // Glyphs are kept in the order of their fragments, whatever the order of the runs.
+                result.glyphs.extend(placed.into_iter().flatten().flatten());
+
+                result.lines.last_mut().unwrap().glyphs.end = result.glyphs.len();
+                baseline_y += line_height + extra_below;
+                line_number += 1;
+            }
+        }
+
+        (result, new_layout)
+    }
+
+    /// Vertical counterpart of the placement loop in `perform_layout_on_paragraphs`: every line
+    /// becomes a column and `line_height` is used as the column width.
+    fn place_columns(
+        options: &LayoutOptions,
+        content: &Rect,
+        line_height: f64,
+        num_lines: usize,
+        paragraphs: &mut [ParagraphInfo],
+        result: &mut Layout,
+    ) {
+        let block_width = line_height * num_lines as f64;
+        let right = content.x + content.w;
+        let mut column_x = match options.vertical_alignment {
+            VerticalAlignment::Normal => right,
+            VerticalAlignment::Center => right - (content.w - block_width) / 2.0,
+            VerticalAlignment::Reverse => content.x + block_width,
+        } - line_height / 2.0;
+
+        for (paragraph_index, paragraph) in paragraphs.iter_mut().enumerate() {
+            for (line_index, line) in paragraph.lines.iter().enumerate() {
+                let mut inline_y = match Self::paragraph_alignment(options, paragraph_index) {
+                    HorizontalAlignment::Normal | HorizontalAlignment::Justify => content.y,
+                    HorizontalAlignment::Center => {
+                        content.y + (content.h - line.visible_length()) / 2.0
                     }
+                    HorizontalAlignment::Reverse => content.y + content.h - line.visible_length(),
+                };
+                let line_column_x = if options.pixel_snap {
+                    column_x.round()
+                } else {
+                    column_x
+                };
+                let start = line.first_fragment_index;
+                let end = if line.has_next_line {
+                    line.last_fragment_index
+                } else {
+                    paragraph.shaped_fragments.len()
+                };
+                let first_glyph = result.glyphs.len();
+                result.lines.push(LayoutLine {
+                    paragraph: paragraph_index,
+                    rtl: paragraph.is_rtl,
+                    x: line_column_x,
+                    y: inline_y,
+                    width: line.line_length,
+                    ascent: line_height / 2.0,
+                    descent: line_height / 2.0,
+                    hard_break: line.hard_break,
+                    glyphs: first_glyph..first_glyph,
+                    source: paragraph.source_of(start..end),
+                    words: paragraph.words_of(start..end),
+                });
 
-                    baseline_x = if is_rtl {
-                        new_baseline_x
+                for (i, fragment) in paragraph.shaped_fragments[start..end]
+                    .iter_mut()
+                    .enumerate()
+                {
+                    if i > 0 {
+                        inline_y += options.letter_spacing;
+                    }
+                    let trim = if i == 0 && line_index > 0 {
+                        fragment.leading_whitespace
                     } else {
-                        new_baseline_x + fragment.length
+                        0.0
                     };
+
+                    let offset = DVec2::new(line_column_x, inline_y - trim);
+                    let whitespace_end = fragment.leading_whitespace_end;
+                    for glyph in fragment.glyphs.iter_mut() {
+                        let offset = options.positioning.snap(offset, glyph.origin);
+                        let mut placed = glyph.place(offset, options.device_pixel_ratio);
+                        if trim > 0.0 && glyph.cluster < whitespace_end {
+                            let y = DVec2::new(0.0, inline_y);
+                            placed.y = options.positioning.snap(y, DVec2::ZERO).y;
+                            placed.advance = 0.0;
+                        }
+                        result.glyphs.push(placed);
+                    }
+                    inline_y -= trim;
+
+                    inline_y += fragment.length;
+                }
+
+                result.lines.last_mut().unwrap().glyphs.end = result.glyphs.len();
+                column_x -= line_height;
+            }
+        }
+    }
+
+    /// `font` is the id and the face of the paragraph's font, glyphs it lacks are shaped with
+    /// [`LayoutOptions::script_fonts`].
+    fn shape_static_text(
+        &self,
+        text: &str,
+        text_offset: usize,
+        font: (&str, &hb::Face),
+        options: &LayoutOptions,
+        is_rtl: bool,
+        glyph_budget: Option<usize>,
+    ) -> Vec<ShapedFragment> {
+        let mut result = vec![];
+        let mut glyph_count = 0;
+        let (paragraph_font_id, face) = font;
+        use icu::properties::bidi::BidiClassAdapter;
+        use icu::properties::maps;
+        use icu::segmenter::LineSegmenter;
+        use unicode_bidi::{BidiInfo, Level, ParagraphBidiInfo};
+        let segmenter = LineSegmenter::new_auto();
+        let horizontal = options.writing_mode == WritingMode::HorizontalTb;
+
+        // Numbers and left-to-right words in right-to-left text, and the other way around, get
+        // their own embedding levels. Vertical text is only ever shaped in the paragraph direction.
+        let base_level = if is_rtl { Level::rtl() } else { Level::ltr() };
+        let levels = if horizontal {
+            let adapter = BidiClassAdapter::new(maps::bidi_class());
+            ParagraphBidiInfo::new_with_data_source(&adapter, text, Some(base_level)).levels
+        } else {
+            vec![base_level; text.len()]
+        };
+
+        let language = options
+            .language
+            .as_deref()
+            .and_then(|language| language.parse::<hb::Language>().ok());
+        let case_language = options
+            .language
+            .as_deref()
+            .and_then(|language| language.parse::<icu::locid::LanguageIdentifier>().ok())
+            .unwrap_or_default();
+        let native_zero = options
+            .language
+            .as_deref()
+            .and_then(native_digit_zero)
+            .filter(|zero| has_glyphs_for_digits(face, *zero));
+        // Clusters are offsets in the whole text, just like the ranges. The shaper treats the end
+        // it stores as exclusive, but `Feature::new` takes one off an exclusive end.
+        let features = options
+            .features
+            .iter()
+            .map(|feature| {
+                let tag = hb::ttf_parser::Tag::from_bytes_lossy(feature.tag.as_bytes());
+                hb::Feature::new(tag, feature.value, feature.start..=feature.end)
+            })
+            .collect::<Vec<_>>();
+
+        let segments = Self::break_opportunities(&segmenter, text, text_offset, options);
+        // The separator `segment` ends with, if any, and where the text before it ends.
+        let shaped_end = |segment: usize| {
+            let separator = text[..segment]
+                .chars()
+                .next_back()
+                .filter(|c| is_line_separator(*c));
+            (separator, segment - separator.map_or(0, char::len_utf8))
+        };
+        // Runs shaped together for the fragments in `span`, see [`LayoutOptions::merge_runs`].
+        let mut span = 0..0;
+        let mut span_runs = vec![];
+        let mut prev_segment_index = 0;
+        for (segment_index, &segment) in segments.iter().enumerate() {
+            let mut shaped_glyphs = vec![];
+            let mut baseline = DVec2::ZERO;
+            let (mut ascent, mut descent) = (0.0_f64, 0.0_f64);
+            let mut max_size = 0;
+            // A line separator only ends the line, it isn't drawn.
+            let (separator, shaped_end_index) = shaped_end(segment);
+
+            if prev_segment_index < span.start || shaped_end_index > span.end {
+                let span_end = if options.merge_runs {
+                    segments[segment_index..]
+                        .iter()
+                        .map(|segment| shaped_end(*segment))
+                        .find(|(separator, _)| separator.is_some())
+                        .map_or(text.len(), |(_, end)| end)
+                } else {
+                    shaped_end_index
+                };
+                span = prev_segment_index..span_end;
+                span_runs = self.shape_runs(
+                    text,
+                    text_offset,
+                    span.clone(),
+                    (face, &levels),
+                    &features,
+                    (language.clone(), &case_language, native_zero),
+                    options,
+                );
+            }
+            // The pieces of the span's runs in this fragment.
+            let runs = span_runs
+                .iter()
+                .filter_map(|(run, orientation, size, level, fallback, glyph_buffer)| {
+                    let run = run.start.max(prev_segment_index)..run.end.min(shaped_end_index);
+                    (!run.is_empty()).then_some((
+                        run,
+                        *orientation,
+                        *size,
+                        *level,
+                        fallback,
+                        glyph_buffer,
+                    ))
+                })
+                .collect::<Vec<_>>();
+            // Glyphs are appended in visual order, and so are the runs.
+            let visual_order = if horizontal {
+                let run_levels = runs.iter().map(|run| run.3).collect::<Vec<_>>();
+                BidiInfo::reorder_visual(&run_levels)
+            } else {
+                (0..runs.len()).collect()
+            };
+            let mut fragment_runs = vec![(0..0, base_level); runs.len()];
+
+            for logical_index in visual_order {
+                let (run, orientation, size, level, fallback, glyph_buffer) =
+                    runs[logical_index].clone();
+                let (font_id, face) = match fallback {
+                    Some((font_id, font)) => (font_id.as_str(), font.face()),
+                    None => (paragraph_font_id, face),
+                };
+                let is_rtl = level.is_rtl();
+                let first_glyph = shaped_glyphs.len();
+                let to_px = Self::pixels_per_unit(face, size, options.size_normalization);
+                // Runs of fonts whose alphabetic baseline isn't at their origin are moved so that
+                // it lands on the line's baseline like everybody else's.
+                let mut baseline_shift = 0.0;
+                if horizontal {
+                    baseline_shift = Self::alphabetic_baseline(face) as f64 * to_px;
+                    baseline.y = baseline_shift;
+                }
+                ascent = ascent.max(face.ascender() as f64 * to_px - baseline_shift);
+                max_size = max_size.max(size);
+                descent = descent.max(-face.descender() as f64 * to_px + baseline_shift);
+
+                // The glyphs of the clusters in the fragment, still in visual order.
+                let in_fragment = text_offset + run.start..text_offset + run.end;
+                let (positions, infos): (Vec<_>, Vec<_>) = glyph_buffer
+                    .glyph_positions()
+                    .iter()
+                    .zip(glyph_buffer.glyph_infos())
+                    .filter(|(_, info)| in_fragment.contains(&(info.cluster as usize)))
+                    .map(|(position, info)| (*position, *info))
+                    .unzip();
+                shaped_glyphs.extend(Self::perform_shaping(
+                    (&positions, &infos),
+                    (font_id, face),
+                    (text, text_offset),
+                    options,
+                    size,
+                    is_rtl,
+                    orientation,
+                    &mut baseline,
+                ));
+                fragment_runs[logical_index] = (first_glyph..shaped_glyphs.len(), level);
+            }
+            let segment_text = &text[prev_segment_index..segment];
+            let leading_whitespace =
+                segment_text.len() - segment_text.trim_start_matches(char::is_whitespace).len();
+            let trailing_whitespace = segment_text.trim_end_matches(char::is_whitespace).len();
+            let segment_start = text_offset + prev_segment_index;
+            let mut shaped_fragment = ShapedFragment::new(
+                shaped_glyphs,
+                segment_start..text_offset + segment,
+                (
+                    segment_start + leading_whitespace,
+                    segment_start + trailing_whitespace,
+                ),
+                ascent,
+                descent,
+            );
+            shaped_fragment.mandatory_break = separator.is_some();
+            shaped_fragment.size = max_size;
+            shaped_fragment.runs = fragment_runs;
+
+            // Fragments that don't fit in the budget are left out with everything after them.
+            glyph_count += shaped_fragment.glyphs.len();
+            if glyph_budget.is_some_and(|max| glyph_count > max) {
+                break;
+            }
+            // Don't keep empty segments. They are an often occurence because a line break can always
+            // be inserted before the first letter of a paragraph.
+            if segment > prev_segment_index {
+                result.push(shaped_fragment);
+            }
+            prev_segment_index = segment;
+        }
+
+        result
+    }
+
+    /// Splits `span` where the orientation, size, font or level changes and shapes every run.
+    /// `text_settings` has the language, the language for case transforms and the native digit
+    /// zero.
+    #[allow(clippy::too_many_arguments)]
+    fn shape_runs<'s>(
+        &'s self,
+        text: &str,
+        text_offset: usize,
+        span: std::ops::Range<usize>,
+        (face, levels): (&hb::Face, &[unicode_bidi::Level]),
+        features: &[hb::Feature],
+        text_settings: (
+            Option<hb::Language>,
+            &icu::locid::LanguageIdentifier,
+            Option<char>,
+        ),
+        options: &'s LayoutOptions,
+    ) -> Vec<ShapedRun<'s>> {
+        let (language, case_language, native_zero) = text_settings;
+        let runs = Self::orientation_runs(text, span, options)
+            .into_iter()
+            .flat_map(|(run, orientation)| {
+                Self::size_runs(text, text_offset, run, options)
+                    .into_iter()
+                    .map(move |(run, size)| (run, orientation, size))
+            })
+            .flat_map(|(run, orientation, size)| {
+                self.font_runs(text, run, face, options)
+                    .into_iter()
+                    .map(move |(run, font)| (run, orientation, size, font))
+            })
+            .flat_map(|(run, orientation, size, font)| {
+                Self::level_runs(run, levels)
+                    .into_iter()
+                    .map(move |(run, level)| (run, orientation, size, level, font))
+            })
+            .collect::<Vec<_>>();
+
+        let mut result = Vec::with_capacity(runs.len());
+        for (run, orientation, size, level, fallback) in runs {
+            let face = fallback.map_or(face, |(_, font)| font.face());
+            let dotted_circle = options.dotted_circles == DottedCircles::Always
+                && face.glyph_index(DOTTED_CIRCLE).is_some();
+            let mut buffer = hb::UnicodeBuffer::new();
+            buffer.set_pre_context(&text[..run.start]);
+            for (i, c) in text[run.clone()].char_indices() {
+                let cluster = text_offset + run.start + i;
+                if dotted_circle && is_mark(c) {
+                    let previous = text[..run.start + i].chars().next_back();
+                    if previous.is_none_or(|c| c.is_whitespace() || c.is_control()) {
+                        buffer.add(DOTTED_CIRCLE, cluster as u32);
+                    }
+                }
+                let transform = options
+                    .text_transforms
+                    .iter()
+                    .rev()
+                    .find(|range| (range.start..range.end).contains(&cluster));
+                match (options.soft_tabs, transform) {
+                    // The spaces share the tab's cluster, so they're trimmed like it.
+                    (Some(spaces), _) if c == '\t' => {
+                        (0..spaces).for_each(|_| buffer.add(' ', cluster as u32))
+                    }
+                    // So do all the characters a character's case maps to.
+                    (_, Some(range)) => {
+                        let word_start =
+                            text[..run.start + i]
+                                .chars()
+                                .next_back()
+                                .is_none_or(|previous| {
+                                    !previous.is_alphanumeric() && !matches!(previous, '\'' | '’')
+                                });
+                        let mapped = range.transform.apply(c, word_start, case_language);
+                        for c in mapped.chars() {
+                            buffer.add(substitute_digit(c, native_zero), cluster as u32);
+                        }
+                    }
+                    _ => buffer.add(substitute_digit(c, native_zero), cluster as u32),
+                }
+            }
+            // `add` resets the post-context, so it has to be set after the text.
+            buffer.set_post_context(&text[run.end..]);
+            if let Some(language) = language.clone() {
+                buffer.set_language(language);
+            }
+            buffer.guess_segment_properties();
+            if orientation == GlyphOrientation::Upright {
+                buffer.set_direction(hb::Direction::TopToBottom);
+            } else if level.is_rtl() {
+                buffer.set_direction(hb::Direction::RightToLeft);
+            } else {
+                buffer.set_direction(hb::Direction::LeftToRight);
+            }
+            buffer.set_cluster_level(options.cluster_level.to_buffer_cluster_level());
+            // Default ignorable characters like ZWSP or the soft hyphen would otherwise come
+            // out as empty glyphs, which still get letter spacing.
+            let mut flags = hb::BufferFlags::REMOVE_DEFAULT_IGNORABLES;
+            if options.dotted_circles == DottedCircles::Suppress {
+                flags |= hb::BufferFlags::DO_NOT_INSERT_DOTTED_CIRCLE;
+            }
+            buffer.set_flags(flags);
+
+            self.shaping_calls.set(self.shaping_calls.get() + 1);
+            let glyph_buffer = hb::shape(face, features, buffer);
+            result.push((run, orientation, size, level, fallback, glyph_buffer));
+        }
+
+        result
+    }
+
+    /// Ends of the fragments `text` is split into: the segmenter's break opportunities merged with
+    /// the ones from `options`, which are offsets in the whole source text.
+    fn break_opportunities(
+        segmenter: &icu::segmenter::LineSegmenter,
+        text: &str,
+        text_offset: usize,
+        options: &LayoutOptions,
+    ) -> Vec<usize> {
+        let in_paragraph = |offset: &usize| {
+            offset
+                .checked_sub(text_offset)
+                .filter(|offset| *offset > 0 && *offset < text.len())
+                .filter(|offset| text.is_char_boundary(*offset))
+        };
+        let suppressed = options
+            .suppressed_breaks
+            .iter()
+            .filter_map(in_paragraph)
+            .collect::<Vec<_>>();
+
+        let mut result = segmenter.segment_str(text).collect::<Vec<_>>();
+        result.extend(options.break_opportunities.iter().filter_map(in_paragraph));
+        result.retain(|offset| !suppressed.contains(offset));
+        result.sort_unstable();
+        result.dedup();
+
+        result
+    }
+
+    /// Splits `run` where the font changes. Characters `face` has no glyph for are drawn with the
+    /// font [`LayoutOptions::script_fonts`] gives for their script if it has one. Marks, and
+    /// punctuation `face` lacks, stay with the font of the character before them.
+    fn font_runs<'s>(
+        &'s self,
+        text: &str,
+        run: std::ops::Range<usize>,
+        face: &hb::Face,
+        options: &'s LayoutOptions,
+    ) -> Vec<(std::ops::Range<usize>, FallbackFont<'s>)> {
+        use icu::properties::maps;
+
+        if options.script_fonts.is_empty() {
+            return vec![(run, None)];
+        }
+        let fallback_for = |c: char, previous: FallbackFont<'s>| {
+            let has_glyph = |face: &hb::Face| face.glyph_index(c).is_some();
+            let script = maps::script().get(c);
+            if script == Script::Inherited || (script == Script::Common && !has_glyph(face)) {
+                if let Some(previous) = previous.filter(|(_, font)| has_glyph(font.face())) {
+                    return Some(previous);
                 }
+            }
+            if has_glyph(face) {
+                return None;
+            }
+            let font_id = options.script_fonts.get(&script)?;
+            let Some(font) = self.fonts.get(font_id) else {
+                log!(
+                    "Can't fall back to font {} because it was not found!",
+                    font_id
+                );
+                return None;
+            };
+            Some((font_id, font.as_ref())).filter(|(_, font)| has_glyph(font.face()))
+        };
+
+        let font_id = |font: FallbackFont<'s>| font.map(|(font_id, _)| font_id);
+
+        let mut result = vec![];
+        let mut start = run.start;
+        let mut current = None;
+        for (i, c) in text[run.clone()].char_indices() {
+            let offset = run.start + i;
+            let fallback = fallback_for(c, current);
+            if offset > start && font_id(fallback) != font_id(current) {
+                result.push((start..offset, current));
+                start = offset;
+            }
+            current = fallback;
+        }
+        if start < run.end {
+            result.push((start..run.end, current));
+        }
 
-                baseline_y += line_height;
+        result
+    }
+
+    /// Splits `run` where the embedding level in `levels`, which has one per byte of the text,
+    /// changes.
+    fn level_runs(
+        run: std::ops::Range<usize>,
+        levels: &[unicode_bidi::Level],
+    ) -> Vec<(std::ops::Range<usize>, unicode_bidi::Level)> {
+        let mut result = vec![];
+        let mut start = run.start;
+        for offset in run.clone().skip(1) {
+            if levels[offset] != levels[start] {
+                result.push((start..offset, levels[start]));
+                start = offset;
             }
         }
+        if start < run.end {
+            result.push((start..run.end, levels[start]));
+        }
 
-        (result, new_layout)
+        result
     }
 
-    fn shape_static_text(
-        &self,
+    /// Splits `run` where [`LayoutOptions::size_ranges`] start or end, giving the size of every
+    /// piece. Offsets inside a character move to its end: every character is drawn at the size of
+    /// its first byte.
+    fn size_runs(
         text: &str,
-        face: &hb::Face,
-        input_transform: &InputTransform,
-        is_rtl: bool,
-    ) -> Vec<ShapedFragment> {
-        let mut result = vec![];
-        use icu::segmenter::LineSegmenter;
-        let segmenter = LineSegmenter::new_auto();
+        text_offset: usize,
+        run: std::ops::Range<usize>,
+        options: &LayoutOptions,
+    ) -> Vec<(std::ops::Range<usize>, usize)> {
+        let size_at = |offset: usize| {
+            options
+                .size_ranges
+                .iter()
+                .rev()
+                .find(|range| (range.start..range.end).contains(&(text_offset + offset)))
+                .map_or(options.size, |range| range.size)
+        };
+        let mut boundaries = options
+            .size_ranges
+            .iter()
+            .flat_map(|range| [range.start, range.end])
+            .filter_map(|offset| offset.checked_sub(text_offset))
+            .map(|offset| {
+                (offset..text.len())
+                    .find(|&i| text.is_char_boundary(i))
+                    .unwrap_or(text.len())
+            })
+            .filter(|offset| run.start < *offset && *offset < run.end)
+            .collect::<Vec<_>>();
+        boundaries.push(run.end);
+        boundaries.sort_unstable();
+        boundaries.dedup();
 
-        let mut prev_segment_index = 0;
-        for segment in segmenter.segment_str(text) {
-            let pre_context = &text[0..prev_segment_index];
-            let current_text = &text[prev_segment_index..segment];
-            let post_context = &text[segment..];
+        let mut start = run.start;
+        boundaries
+            .into_iter()
+            .map(|end| {
+                let piece = start..end;
+                start = end;
+                (piece.clone(), size_at(piece.start))
+            })
+            .collect()
+    }
 
-            let mut buffer = hb::UnicodeBuffer::new();
-            buffer.set_pre_context(pre_context);
-            buffer.push_str(current_text);
-            buffer.set_post_context(post_context);
-            buffer.guess_segment_properties();
-            if is_rtl {
-                buffer.set_direction(hb::Direction::RightToLeft);
-            } else {
-                buffer.set_direction(hb::Direction::LeftToRight);
+    /// Splits `segment` into runs shaped with a single orientation. Horizontal text is always a
+    /// single run.
+    fn orientation_runs(
+        text: &str,
+        segment: std::ops::Range<usize>,
+        options: &LayoutOptions,
+    ) -> Vec<(std::ops::Range<usize>, GlyphOrientation)> {
+        use icu::properties::{maps, EastAsianWidth};
+
+        let orientation_of = |c: char| match (options.writing_mode, options.text_orientation) {
+            (WritingMode::HorizontalTb, _) => GlyphOrientation::Horizontal,
+            (WritingMode::VerticalRl, TextOrientation::Upright) => GlyphOrientation::Upright,
+            (WritingMode::VerticalRl, TextOrientation::Sideways) => GlyphOrientation::Sideways,
+            (WritingMode::VerticalRl, TextOrientation::Mixed) => {
+                match maps::east_asian_width().get(c) {
+                    EastAsianWidth::Wide | EastAsianWidth::Fullwidth => GlyphOrientation::Upright,
+                    _ => GlyphOrientation::Sideways,
+                }
             }
-            buffer.set_cluster_level(hb::BufferClusterLevel::MonotoneCharacters);
+        };
 
-            let glyph_buffer = hb::shape(face, &[], buffer);
-            let shaped_glyphs = Self::perform_shaping(&glyph_buffer, face, input_transform);
-            let shaped_fragment = ShapedFragment::new(shaped_glyphs);
+        let mut runs: Vec<(std::ops::Range<usize>, GlyphOrientation)> = vec![];
+        for (i, c) in text[segment.clone()].char_indices() {
+            let start = segment.start + i;
+            let end = start + c.len_utf8();
+            let orientation = orientation_of(c);
 
-            // Don't keep empty segments. They are an often occurence because a line break can always
-            // be inserted before the first letter of a paragraph.
-            if !current_text.is_empty() {
-                result.push(shaped_fragment);
+            match runs.last_mut() {
+                Some((run, run_orientation)) if *run_orientation == orientation => run.end = end,
+                _ => runs.push((start..end, orientation)),
             }
-            prev_segment_index = segment;
         }
 
-        result
+        runs
     }
 
+    /// Turns shaped glyphs into paths, starting at `baseline` and leaving it after the last glyph.
+    /// `source` is the text the clusters are in and its offset in the whole text.
+    #[allow(clippy::too_many_arguments)]
     fn perform_shaping(
-        glyph_buffer: &hb::GlyphBuffer,
-        face: &hb::Face,
-        input_transform: &InputTransform,
+        (positions, infos): (&[hb::GlyphPosition], &[hb::GlyphInfo]),
+        font: (&str, &hb::Face),
+        source: (&str, usize),
+        options: &LayoutOptions,
+        size: usize,
+        is_rtl: bool,
+        orientation: GlyphOrientation,
+        baseline: &mut DVec2,
     ) -> Vec<GlyphPath> {
         let mut result = vec![];
-        let mut baseline = DVec2::new(0.0, 0.0);
+        let (font_id, face) = font;
+        let to_px = Self::pixels_per_unit(face, size, options.size_normalization);
+        let font_transform = Self::from_font_space_to_screen_space(to_px);
+        let orientation_transform = match orientation {
+            GlyphOrientation::Sideways => {
+                // Center the em box instead of sitting on the alphabetic baseline.
+                let central_baseline = (face.ascender() as f64 + face.descender() as f64) / 2.0;
+                let central_baseline = central_baseline * to_px;
 
-        for (glyph, info) in glyph_buffer
-            .glyph_positions()
-            .iter()
-            .zip(glyph_buffer.glyph_infos().iter())
-        {
+                DAffine2::from_translation(DVec2::new(-central_baseline, 0.0))
+                    * DAffine2::from_angle(std::f64::consts::FRAC_PI_2)
+            }
+            _ => DAffine2::IDENTITY,
+        };
+        let reversed = is_rtl && orientation != GlyphOrientation::Upright;
+        let outline_scale =
+            if options.horizontal_scale_outlines && orientation == GlyphOrientation::Horizontal {
+                DAffine2::from_scale(DVec2::new(options.horizontal_scale, 1.0))
+            } else {
+                DAffine2::IDENTITY
+            };
+
+        for (i, (glyph, info)) in positions.iter().zip(infos.iter()).enumerate() {
             let glyph_id = hb::ttf_parser::GlyphId(info.glyph_id.try_into().unwrap());
-            let font_transform = Self::from_font_space_to_screen_space(&face, input_transform.size);
 
             let (advance_x, advance_y, offset_x, offset_y) = (
                 glyph.x_advance,
@@ -476,68 +4915,478 @@ impl<'a> AppState<'a> {
                 glyph.y_offset,
             );
             let advance = DVec2::new(advance_x as f64, advance_y as f64);
-            let advance = font_transform.transform_point2(advance);
+            let mut advance =
+                orientation_transform.transform_vector2(font_transform.transform_vector2(advance));
+            let spacing = if Self::separates_clusters(positions, infos, i, reversed, orientation) {
+                options.letter_spacing + Self::kerning_override(source, infos, i, reversed, options)
+            } else {
+                0.0
+            };
+            let advance_units = if orientation == GlyphOrientation::Upright {
+                -advance_y
+            } else {
+                advance_x
+            };
+            if orientation == GlyphOrientation::Horizontal {
+                advance.x = (advance.x + spacing) * options.horizontal_scale;
+            } else {
+                advance.y += spacing;
+            }
+            if options.round_advances {
+                advance = advance.round();
+            }
+            let inline_advance = if orientation == GlyphOrientation::Horizontal {
+                advance.x
+            } else {
+                advance.y
+            };
 
             let offset = DVec2::new(offset_x as f64, offset_y as f64);
-            let glyph_transform = DAffine2::from_translation(baseline)
+            let screen_offset =
+                (orientation_transform * outline_scale * font_transform).transform_vector2(offset);
+            // The shaper's offsets are in font units with y up like the outline, so they go
+            // before the flip: a positive y_offset lifts marks above their base.
+            let glyph_transform = DAffine2::from_translation(*baseline)
+                * orientation_transform
+                * outline_scale
                 * font_transform
                 * DAffine2::from_translation(offset);
             let mut glyph_path = GlyphPath {
                 svg_path_string: "".into(),
+                outline: "".into(),
                 transform: glyph_transform,
                 cmds: vec![],
-                advance_x: advance.x,
+                advance_x: inline_advance,
+                advance_units,
+                glyph_id: glyph_id.0,
+                cluster: info.cluster as usize,
+                origin: *baseline,
+                offset: screen_offset,
+                reversed,
+                font: font_id.into(),
             };
-            face.outline_glyph(glyph_id, &mut glyph_path);
+            if options.skeleton == Skeleton::Off
+                && face.outline_glyph(glyph_id, &mut glyph_path).is_none()
+                && options.empty_outlines == EmptyOutlines::Placeholder
+            {
+                let start = glyph_path.cluster.saturating_sub(source.1);
+                let whitespace = source.0.get(start..).unwrap_or_default();
+                let width = face.glyph_hor_advance(glyph_id).unwrap_or(0) as f32;
+                if !whitespace.starts_with(char::is_whitespace) && width > 0.0 {
+                    use hb::ttf_parser::OutlineBuilder;
+
+                    let height = face.ascender() as f32;
+                    glyph_path.move_to(0.0, 0.0);
+                    glyph_path.line_to(width, 0.0);
+                    glyph_path.line_to(width, height);
+                    glyph_path.line_to(0.0, height);
+                    glyph_path.close();
+                }
+            }
 
             result.push(glyph_path);
-            baseline += advance;
+            *baseline += advance;
         }
+        Self::enclose_bases(&mut result, source, reversed);
 
         result
     }
 
-    fn from_font_space_to_screen_space(face: &hb::Face, text_size: usize) -> DAffine2 {
-        let units_per_em = face.units_per_em();
-        let (ppem, upem) = (text_size as f64, units_per_em as f64);
+    /// Centers enclosing marks like U+20DD on the outline of their base, the glyph with an
+    /// advance before them in logical order, and grows the ones smaller than it to its size.
+    /// Fonts draw them around a glyph of average size, if they position them at all.
+    fn enclose_bases(glyphs: &mut [GlyphPath], (text, text_offset): (&str, usize), reversed: bool) {
+        use icu::properties::{maps, GeneralCategory};
+
+        // The character the glyph's cluster starts with and the marks after it.
+        let has_enclosing_mark = |glyph: &GlyphPath| {
+            let start = glyph.cluster.saturating_sub(text_offset);
+            let mut chars = text.get(start..).unwrap_or_default().chars();
+            let first = chars.next().into_iter();
+            first
+                .chain(chars.take_while(|c| {
+                    use GeneralCategory::*;
+                    matches!(
+                        maps::general_category().get(*c),
+                        NonspacingMark | EnclosingMark
+                    )
+                }))
+                .any(|c| maps::general_category().get(c) == GeneralCategory::EnclosingMark)
+        };
+        let center = |glyph: &GlyphPath| {
+            let bounds = glyph.bounds(DVec2::ZERO)?;
+            Some((
+                DVec2::new(bounds.x + bounds.w / 2.0, bounds.y + bounds.h / 2.0),
+                bounds,
+            ))
+        };
+
+        for i in 0..glyphs.len() {
+            if glyphs[i].advance_units != 0 || !has_enclosing_mark(&glyphs[i]) {
+                continue;
+            }
+            let base = if reversed {
+                (i + 1..glyphs.len()).find(|j| glyphs[*j].advance_units != 0)
+            } else {
+                (0..i).rev().find(|j| glyphs[*j].advance_units != 0)
+            };
+            let Some(((base_center, base), (mark_center, mark))) = base
+                .and_then(|base| center(&glyphs[base]))
+                .zip(center(&glyphs[i]))
+            else {
+                continue;
+            };
+            if mark.w <= 0.0 || mark.h <= 0.0 {
+                continue;
+            }
+            let scale = 1.0_f64.max(base.w / mark.w).max(base.h / mark.h);
+            let transform = DAffine2::from_translation(base_center)
+                * DAffine2::from_scale(DVec2::splat(scale))
+                * DAffine2::from_translation(-mark_center);
+
+            let mark = &mut glyphs[i];
+            for cmd in mark.cmds.iter_mut() {
+                let points = match cmd {
+                    PathCmd::M(to) | PathCmd::L(to) => vec![to],
+                    PathCmd::Q(p1, p2) => vec![p1, p2],
+                    PathCmd::C(p1, p2, p3) => vec![p1, p2, p3],
+                    PathCmd::Z => vec![],
+                };
+                points
+                    .into_iter()
+                    .for_each(|point| *point = transform.transform_point2(*point));
+            }
+            mark.transform = transform * mark.transform;
+        }
+    }
+
+    /// [`LayoutOptions::kerning_overrides`] for the characters on each side of the gap after
+    /// glyph `i`, in visual order, which lies between two clusters.
+    fn kerning_override(
+        (text, text_offset): (&str, usize),
+        infos: &[hb::GlyphInfo],
+        i: usize,
+        reversed: bool,
+        options: &LayoutOptions,
+    ) -> f64 {
+        if options.kerning_overrides.is_empty() {
+            return 0.0;
+        }
+
+        let (first, second) = if reversed { (i + 1, i) } else { (i, i + 1) };
+        let cluster = |glyph: usize| (infos[glyph].cluster as usize).saturating_sub(text_offset);
+        let (first, second) = (cluster(first), cluster(second));
+        // The last character of the first cluster and the first one of the second.
+        let left = text
+            .get(first..second)
+            .and_then(|text| text.chars().next_back());
+        let right = text.get(second..).and_then(|text| text.chars().next());
+        match (left, right) {
+            (Some(left), Some(right)) => options
+                .kerning_overrides
+                .get(&(left, right))
+                .copied()
+                .unwrap_or(0.0),
+            _ => 0.0,
+        }
+    }
+
+    /// Whether the gap after glyph `i` (in visual order) lies between two clusters. Marks have no
+    /// advance and stay glued to their base, which precedes them in left-to-right buffers and
+    /// follows them in right-to-left ones.
+    fn separates_clusters(
+        positions: &[hb::GlyphPosition],
+        infos: &[hb::GlyphInfo],
+        i: usize,
+        reversed: bool,
+        orientation: GlyphOrientation,
+    ) -> bool {
+        if i + 1 >= infos.len() || infos[i].cluster == infos[i + 1].cluster {
+            return false;
+        }
+
+        let has_advance = |position: &hb::GlyphPosition| match orientation {
+            GlyphOrientation::Upright => position.y_advance != 0,
+            _ => position.x_advance != 0,
+        };
+        if reversed {
+            has_advance(&positions[i])
+        } else {
+            has_advance(&positions[i + 1])
+        }
+    }
+
+    fn from_font_space_to_screen_space(to_px: f64) -> DAffine2 {
+        DAffine2::from_scale(DVec2::new(to_px, -to_px))
+    }
+
+    /// Height of the alphabetic (`romn`) baseline above the origin in font units, from the
+    /// horizontal axis of the `BASE` table for the default script, or the first one. Fonts
+    /// without the table have their alphabetic baseline at the origin.
+    fn alphabetic_baseline(face: &hb::Face) -> i16 {
+        use hb::ttf_parser::Tag;
+
+        let Some(base) = face.raw_face().table(Tag::from_bytes(b"BASE")) else {
+            return 0;
+        };
+        let u16_at = |offset: usize| {
+            base.get(offset..offset + 2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+        };
+        // Offsets are relative to the start of the table holding them, zero ones are missing.
+        let offset_at = |table: usize, offset: usize| {
+            u16_at(table + offset)
+                .filter(|offset| *offset != 0)
+                .map(|offset| table + offset as usize)
+        };
+        let tag_at = |offset: usize| base.get(offset..offset + 4);
+
+        let coordinate = || {
+            let axis = offset_at(0, 4)?;
+            let tags = offset_at(axis, 0)?;
+            let scripts = offset_at(axis, 2)?;
+            let romn = (0..u16_at(tags)? as usize)
+                .position(|i| tag_at(tags + 2 + 4 * i) == Some(b"romn"))?;
+            let script_count = u16_at(scripts)? as usize;
+            let record = (0..script_count)
+                .find(|i| tag_at(scripts + 2 + 6 * i) == Some(b"DFLT"))
+                .or((script_count > 0).then_some(0))?;
+            let script = offset_at(scripts, 2 + 6 * record + 4)?;
+            let values = offset_at(script, 0)?;
+            if romn >= u16_at(values + 2)? as usize {
+                return None;
+            }
+            let coordinate = offset_at(values, 4 + 2 * romn)?;
+            // Every format of base coordinates starts with the plain value.
+            u16_at(coordinate + 2).map(|value| value as i16)
+        };
+
+        coordinate().unwrap_or(0)
+    }
+
+    /// Pixels per font unit of `face` drawn at `text_size`.
+    fn pixels_per_unit(face: &hb::Face, text_size: usize, normalization: SizeNormalization) -> f64 {
+        let (ppem, upem) = (text_size as f64, face.units_per_em() as f64);
         // `ppem` gives us the mapping between font units and screen pixels.
         // ppem stands for pixels per em.
         let to_px = ppem / upem;
+        let (metric, target, reference) = match normalization {
+            SizeNormalization::None => return to_px,
+            SizeNormalization::CapHeight => (face.capital_height(), CAP_HEIGHT, 'H'),
+            SizeNormalization::XHeight => (face.x_height(), X_HEIGHT, 'x'),
+        };
 
-        DAffine2::from_scale(DVec2::new(to_px, -to_px))
+        match Self::letter_height(face, metric, reference) {
+            Some(metric) => target * ppem / metric as f64,
+            None => to_px,
+        }
+    }
+
+    /// Font metric `metric`, like the cap height, in font units. Old OS/2 tables don't have it,
+    /// so it's measured on the letter `reference` instead.
+    fn letter_height(face: &hb::Face, metric: Option<i16>, reference: char) -> Option<i16> {
+        metric
+            .filter(|metric| *metric > 0)
+            .or_else(|| {
+                let glyph = face.glyph_index(reference)?;
+                face.glyph_bounding_box(glyph).map(|bounds| bounds.y_max)
+            })
+            .filter(|metric| *metric > 0)
     }
 }
 
-#[allow(static_mut_refs)]
-fn app_state() -> &'static mut AppState<'static> {
-    static mut SINGLETON: MaybeUninit<AppState> = MaybeUninit::uninit();
-    static ONCE: Once = Once::new();
+/// Runs `f` with the state of the calling thread. The WASM module only ever has one thread, and
+/// keeping the state per thread lets the native API be used from several threads (e.g. parallel
+/// tests) without sharing it. `f` must not call back into a function that needs the state.
+fn with_state<R>(f: impl FnOnce(&mut AppState) -> R) -> R {
+    thread_local! {
+        static STATE: RefCell<AppState> = RefCell::new(AppState::new());
+    }
 
-    unsafe {
-        ONCE.call_once(|| {
-            let singleton = AppState::new();
-            SINGLETON.write(singleton);
-        });
+    STATE.with_borrow_mut(f)
+}
+
+/// The font registered as `font_id` on the calling thread.
+fn registered_font(font_id: &str) -> Option<Rc<Font>> {
+    with_state(|state| state.fonts.get(font_id).cloned())
+}
+
+/// `paragraph` without the separator ending it, which bidi analysis keeps in the paragraph's
+/// range.
+fn strip_paragraph_separator(paragraph: &str) -> &str {
+    if let Some(stripped) = paragraph.strip_suffix("\r\n") {
+        return stripped;
+    }
+    match paragraph.chars().next_back() {
+        Some(c @ ('\n' | '\r' | '\u{1C}'..='\u{1E}' | '\u{85}' | '\u{2029}')) => {
+            &paragraph[..paragraph.len() - c.len_utf8()]
+        }
+        _ => paragraph,
+    }
+}
+
+/// Characters forcing a line break without ending the paragraph.
+fn is_line_separator(c: char) -> bool {
+    matches!(c, '\u{0B}' | '\u{0C}' | '\u{2028}')
+}
 
-        SINGLETON.assume_init_mut()
+/// Zero digit of the numbering system `language` writes numbers with, for the languages where
+/// that isn't the ASCII one.
+fn native_digit_zero(language: &str) -> Option<char> {
+    let primary_subtag = language.split(['-', '_']).next()?.to_ascii_lowercase();
+
+    match primary_subtag.as_str() {
+        "ar" => Some('\u{0660}'),
+        "fa" | "ur" => Some('\u{06F0}'),
+        _ => None,
+    }
+}
+
+fn has_glyphs_for_digits(face: &hb::Face, zero: char) -> bool {
+    (0..10).all(|digit| {
+        char::from_u32(zero as u32 + digit).is_some_and(|c| face.glyph_index(c).is_some())
+    })
+}
+
+/// Combining marks, which attach to the character before them.
+fn is_mark(c: char) -> bool {
+    use icu::properties::{maps, GeneralCategory};
+
+    matches!(
+        maps::general_category().get(c),
+        GeneralCategory::NonspacingMark
+            | GeneralCategory::SpacingMark
+            | GeneralCategory::EnclosingMark
+    )
+}
+
+fn substitute_digit(c: char, native_zero: Option<char>) -> char {
+    match (c.to_digit(10), native_zero) {
+        (Some(digit), Some(zero)) if c.is_ascii_digit() => {
+            char::from_u32(zero as u32 + digit).unwrap_or(c)
+        }
+        _ => c,
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GlyphOrientation {
+    Horizontal,
+    /// Shaped top to bottom with the font's vertical metrics.
+    Upright,
+    /// Shaped horizontally and rotated 90° clockwise.
+    Sideways,
+}
+
 #[derive(Clone)]
 struct ShapedFragment {
     glyphs: Vec<GlyphPath>,
+    /// Source bytes of the fragment, whitespace and line separators included.
+    source: std::ops::Range<usize>,
     length: f64,
+    /// Glyphs of clusters before this source offset are whitespace the fragment starts with.
+    leading_whitespace_end: usize,
+    /// Total advance of the leading whitespace, which is dropped when the fragment starts a
+    /// wrapped line.
+    leading_whitespace: f64,
+    /// Total advance of the whitespace the fragment ends with, which hangs past the margin when
+    /// the fragment ends a line that isn't aligned to its start.
+    trailing_whitespace: f64,
+    /// Largest ascent and descent of the sizes the fragment is drawn at.
+    ascent: f64,
+    descent: f64,
+    /// Whether the fragment ends with a line separator, after which the line has to break.
+    mandatory_break: bool,
+    /// Largest size the fragment is drawn at, 0 for fragments without text.
+    size: usize,
+    /// Ranges of `glyphs` shaped in a single direction, in logical order, with their embedding
+    /// levels. The glyphs themselves are stored in visual order.
+    runs: Vec<(std::ops::Range<usize>, unicode_bidi::Level)>,
 }
 
 impl ShapedFragment {
-    fn new(glyphs: Vec<GlyphPath>) -> Self {
+    /// `whitespace` has the source offsets where the leading whitespace ends and where the
+    /// trailing whitespace starts.
+    fn new(
+        glyphs: Vec<GlyphPath>,
+        source: std::ops::Range<usize>,
+        whitespace: (usize, usize),
+        ascent: f64,
+        descent: f64,
+    ) -> Self {
+        let (leading_whitespace_end, trailing_whitespace_start) = whitespace;
         let mut length = 0.0;
+        let mut leading_whitespace = 0.0;
+        let mut trailing_whitespace = 0.0;
 
         for glyph in glyphs.iter() {
             length += glyph.advance_x;
+            if glyph.cluster < leading_whitespace_end {
+                leading_whitespace += glyph.advance_x;
+            }
+            if glyph.cluster >= trailing_whitespace_start {
+                trailing_whitespace += glyph.advance_x;
+            }
+        }
+        let runs = vec![(0..glyphs.len(), unicode_bidi::Level::ltr())];
+
+        Self {
+            glyphs,
+            source,
+            length,
+            leading_whitespace_end,
+            leading_whitespace,
+            trailing_whitespace,
+            ascent,
+            descent,
+            mandatory_break: false,
+            size: 0,
+            runs,
         }
+    }
+}
+
+/// A run of glyphs from one of the fragments sharing a line.
+struct VisualRun {
+    fragment: usize,
+    glyphs: std::ops::Range<usize>,
+    /// Whether the gap between the fragment and the next one is on the left of the run, or on
+    /// its right. It's on the side the fragment's text ends.
+    gap_before: bool,
+    gap_after: bool,
+}
+
+impl VisualRun {
+    /// Runs of `fragments`, which are in logical order, ordered left to right.
+    fn of(fragments: &[ShapedFragment]) -> Vec<Self> {
+        let last = fragments.len().saturating_sub(1);
+        let (runs, levels): (Vec<_>, Vec<_>) = fragments
+            .iter()
+            .enumerate()
+            .flat_map(|(i, fragment)| {
+                let last_run = fragment.runs.len().saturating_sub(1);
+                fragment
+                    .runs
+                    .iter()
+                    .enumerate()
+                    .map(move |(j, (glyphs, level))| {
+                        let has_gap = i < last && j == last_run;
+                        let run = VisualRun {
+                            fragment: i,
+                            glyphs: glyphs.clone(),
+                            gap_before: has_gap && level.is_rtl(),
+                            gap_after: has_gap && level.is_ltr(),
+                        };
+                        (run, *level)
+                    })
+            })
+            .unzip();
+        let mut runs = runs.into_iter().map(Some).collect::<Vec<_>>();
 
-        Self { glyphs, length }
+        unicode_bidi::BidiInfo::reorder_visual(&levels)
+            .into_iter()
+            .filter_map(|logical_index| runs[logical_index].take())
+            .collect()
     }
 }
 
@@ -550,16 +5399,196 @@ enum PathCmd {
     Z,
 }
 
+impl PathCmd {
+    /// Commands of an SVG path written like glyph paths and outlines are: a command letter
+    /// followed by its comma separated points for every command, e.g. `M1 2 Q3 4,5 6 Z `.
+    fn parse(path: &str) -> Vec<PathCmd> {
+        let starts = path
+            .match_indices(|c: char| c.is_ascii_alphabetic())
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let mut result = Vec::with_capacity(starts.len());
+        for (i, start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(path.len());
+            let (command, points) = path[*start..end].split_at(1);
+            let points = points
+                .split(',')
+                .filter_map(|point| {
+                    let mut coordinates = point.split_whitespace().map(str::parse::<f64>);
+                    match (coordinates.next(), coordinates.next()) {
+                        (Some(Ok(x)), Some(Ok(y))) => Some(DVec2::new(x, y)),
+                        _ => None,
+                    }
+                })
+                .collect::<Vec<_>>();
+            result.push(match (command, points.as_slice()) {
+                ("M", [to]) => PathCmd::M(*to),
+                ("L", [to]) => PathCmd::L(*to),
+                ("Q", [p1, p2]) => PathCmd::Q(*p1, *p2),
+                ("C", [p1, p2, p3]) => PathCmd::C(*p1, *p2, *p3),
+                ("Z", []) => PathCmd::Z,
+                _ => {
+                    log!("Can't parse path command {}!", &path[*start..end]);
+                    continue;
+                }
+            });
+        }
+
+        result
+    }
+
+    /// End and control points of the command.
+    fn points(&self) -> Vec<DVec2> {
+        match *self {
+            PathCmd::M(to) | PathCmd::L(to) => vec![to],
+            PathCmd::Q(p1, p2) => vec![p1, p2],
+            PathCmd::C(p1, p2, p3) => vec![p1, p2, p3],
+            PathCmd::Z => vec![],
+        }
+    }
+
+    /// The command with `f` applied to every point.
+    fn map(&self, mut f: impl FnMut(DVec2) -> DVec2) -> PathCmd {
+        match *self {
+            PathCmd::M(to) => PathCmd::M(f(to)),
+            PathCmd::L(to) => PathCmd::L(f(to)),
+            PathCmd::Q(p1, p2) => PathCmd::Q(f(p1), f(p2)),
+            PathCmd::C(p1, p2, p3) => PathCmd::C(f(p1), f(p2), f(p3)),
+            PathCmd::Z => PathCmd::Z,
+        }
+    }
+
+    fn write_svg(&self, path: &mut String) {
+        *path += &match self {
+            PathCmd::M(to) => format!("M{} {} ", to.x, to.y),
+            PathCmd::L(to) => format!("L{} {} ", to.x, to.y),
+            PathCmd::Q(p1, p2) => format!("Q{} {},{} {} ", p1.x, p1.y, p2.x, p2.y),
+            PathCmd::C(p1, p2, p3) => {
+                format!("C{} {},{} {},{} {} ", p1.x, p1.y, p2.x, p2.y, p3.x, p3.y)
+            }
+            PathCmd::Z => "Z ".into(),
+        };
+    }
+
+    /// `cmds` with every curve replaced by lines between points on it, as many as it takes for
+    /// the lines to stay within `tolerance` of the curve.
+    fn flatten(cmds: &[PathCmd], tolerance: f64) -> Vec<PathCmd> {
+        // A segment spanning `1 / n` of a curve strays at most an eighth of the largest second
+        // derivative over `n²` from it.
+        let segments = |second_derivative: f64| {
+            let n = (second_derivative / (8.0 * tolerance.max(MIN_FLATTEN_TOLERANCE)))
+                .sqrt()
+                .ceil();
+            (n as usize).max(1)
+        };
+        let mut result = Vec::with_capacity(cmds.len());
+        let (mut start, mut current) = (DVec2::ZERO, DVec2::ZERO);
+        for cmd in cmds {
+            match *cmd {
+                PathCmd::M(to) => {
+                    (start, current) = (to, to);
+                    result.push(PathCmd::M(to));
+                }
+                PathCmd::L(to) => {
+                    current = to;
+                    result.push(PathCmd::L(to));
+                }
+                PathCmd::Q(p1, p2) => {
+                    let p0 = current;
+                    let n = segments(2.0 * (p0 - 2.0 * p1 + p2).length());
+                    result.extend((1..=n).map(|i| {
+                        let t = i as f64 / n as f64;
+                        PathCmd::L(p0.lerp(p1, t).lerp(p1.lerp(p2, t), t))
+                    }));
+                    current = p2;
+                }
+                PathCmd::C(p1, p2, p3) => {
+                    let p0 = current;
+                    let bend = (p0 - 2.0 * p1 + p2)
+                        .length()
+                        .max((p1 - 2.0 * p2 + p3).length());
+                    let n = segments(6.0 * bend);
+                    result.extend((1..=n).map(|i| {
+                        let t = i as f64 / n as f64;
+                        let (a, b, c) = (p0.lerp(p1, t), p1.lerp(p2, t), p2.lerp(p3, t));
+                        PathCmd::L(a.lerp(b, t).lerp(b.lerp(c, t), t))
+                    }));
+                    current = p3;
+                }
+                PathCmd::Z => {
+                    current = start;
+                    result.push(PathCmd::Z);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// `path` with its curves replaced by lines no further than `tolerance` from them, see
+/// [`LayoutOptions::flatten_tolerance`].
+fn flatten_path(path: &str, tolerance: f64) -> String {
+    let mut result = String::with_capacity(path.len());
+    for cmd in PathCmd::flatten(&PathCmd::parse(path), tolerance) {
+        cmd.write_svg(&mut result);
+    }
+
+    result
+}
+
+/// `text` with every number in it written with `decimals` decimals, see
+/// [`LayoutOptions::path_precision`].
+fn round_numbers(text: &str, decimals: usize) -> String {
+    let is_number = |c: char| c == '-' || c == '.' || c.is_ascii_digit();
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(is_number) {
+        result += &rest[..start];
+        let end = rest[start..]
+            .find(|c| !is_number(c))
+            .map_or(rest.len(), |end| start + end);
+        match rest[start..end].parse::<f64>() {
+            Ok(number) => {
+                let rounded = format!("{number:.decimals$}");
+                // Without the sign of values rounded to zero.
+                match rounded.strip_prefix('-') {
+                    Some(zero) if zero.parse::<f64>() == Ok(0.0) => result += zero,
+                    _ => result += &rounded,
+                }
+            }
+            Err(_) => result += &rest[start..end],
+        }
+        rest = &rest[end..];
+    }
+    result += rest;
+
+    result
+}
+
 #[derive(Debug, Clone)]
 struct GlyphPath {
     svg_path_string: String,
+    /// The outline in font units.
+    outline: String,
     transform: DAffine2,
     cmds: Vec<PathCmd>,
+    /// Advance along the line, so downwards in vertical text.
     advance_x: f64,
+    /// The same advance in font units, before scaling and letter spacing.
+    advance_units: i32,
+    glyph_id: u16,
+    cluster: usize,
+    /// Pen position inside the fragment.
+    origin: DVec2,
+    offset: DVec2,
+    reversed: bool,
+    font: FontId,
 }
 
 impl hb::ttf_parser::OutlineBuilder for GlyphPath {
     fn move_to(&mut self, x: f32, y: f32) {
+        self.outline += &format!("M{} {} ", x, y);
         let to = DVec2::new(x as f64, y as f64);
         let to = self.transform.transform_point2(to);
         self.svg_path_string += &format!("M{} {} ", to.x, to.y);
@@ -567,6 +5596,7 @@ impl hb::ttf_parser::OutlineBuilder for GlyphPath {
     }
 
     fn line_to(&mut self, x: f32, y: f32) {
+        self.outline += &format!("L{} {} ", x, y);
         let to = DVec2::new(x as f64, y as f64);
         let to = self.transform.transform_point2(to);
         self.svg_path_string += &format!("L{} {} ", to.x, to.y);
@@ -574,6 +5604,7 @@ impl hb::ttf_parser::OutlineBuilder for GlyphPath {
     }
 
     fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.outline += &format!("Q{} {},{} {} ", x1, y1, x, y);
         let p1 = DVec2::new(x1 as f64, y1 as f64);
         let p2 = DVec2::new(x as f64, y as f64);
 
@@ -585,6 +5616,7 @@ impl hb::ttf_parser::OutlineBuilder for GlyphPath {
     }
 
     fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.outline += &format!("C{} {},{} {},{} {} ", x1, y1, x2, y2, x, y);
         let p1 = DVec2::new(x1 as f64, y1 as f64);
         let p1 = self.transform.transform_point2(p1);
         let p2 = DVec2::new(x2 as f64, y2 as f64);
@@ -597,39 +5629,66 @@ impl hb::ttf_parser::OutlineBuilder for GlyphPath {
     }
 
     fn close(&mut self) {
+        self.outline += "Z ";
         self.svg_path_string += "Z ";
         self.cmds.push(PathCmd::Z);
     }
 }
 
 impl GlyphPath {
-    fn translate(&mut self, offset: DVec2) {
-        self.svg_path_string.clear();
-        self.cmds.iter().for_each(|cmd| match cmd {
-            PathCmd::M(to) => {
-                let to = *to + offset;
-                self.svg_path_string += &format!("M{} {} ", to.x, to.y);
-            }
-            PathCmd::L(to) => {
-                let to = *to + offset;
-                self.svg_path_string += &format!("L{} {} ", to.x, to.y);
-            }
-            PathCmd::Q(p1, p2) => {
-                let p1 = *p1 + offset;
-                let p2 = *p2 + offset;
-                self.svg_path_string += &format!("Q{} {},{} {} ", p1.x, p1.y, p2.x, p2.y);
-            }
-            PathCmd::C(p1, p2, p3) => {
-                let p1 = *p1 + offset;
-                let p2 = *p2 + offset;
-                let p3 = *p3 + offset;
-                self.svg_path_string +=
-                    &format!("C{} {},{} {},{} {} ", p1.x, p1.y, p2.x, p2.y, p3.x, p3.y);
-            }
-            PathCmd::Z => {
-                self.svg_path_string += "Z ";
-            }
+    /// Moves the glyph from its fragment to `offset` in the text box. Only the SVG path is
+    /// multiplied by `scale`, everything else stays in logical pixels.
+    fn place(&mut self, offset: DVec2, scale: f64) -> LayoutGlyph {
+        self.translate(offset, scale);
+        let position = offset + self.origin;
+        let bounds = self.bounds(offset).unwrap_or(Rect {
+            x: position.x,
+            y: position.y,
+            ..Default::default()
+        });
+
+        LayoutGlyph {
+            glyph_id: self.glyph_id,
+            cluster: self.cluster,
+            x: position.x,
+            y: position.y,
+            offset: self.offset,
+            advance: self.advance_x,
+            advance_units: self.advance_units,
+            rtl: self.reversed,
+            transform: DAffine2::from_translation(offset) * self.transform,
+            bounds,
+            path: self.svg_path_string.clone(),
+            outline: self.outline.clone(),
+            color: None,
+            opacity: 1.0,
+            font: self.font.clone(),
+        }
+    }
+
+    /// Control points of a curve lie around it, so their box contains the outline.
+    fn bounds(&self, offset: DVec2) -> Option<Rect> {
+        let mut points = self.cmds.iter().flat_map(PathCmd::points);
+        let first = points.next()?;
+        let (min, max) = points.fold((first, first), |(min, max), point| {
+            (min.min(point), max.max(point))
         });
+        let size = max - min;
+
+        Some(Rect {
+            x: min.x + offset.x,
+            y: min.y + offset.y,
+            w: size.x,
+            h: size.y,
+        })
+    }
+
+    fn translate(&mut self, offset: DVec2, scale: f64) {
+        self.svg_path_string.clear();
+        for cmd in self.cmds.iter() {
+            cmd.map(|point| (point + offset) * scale)
+                .write_svg(&mut self.svg_path_string);
+        }
     }
 }
 
@@ -639,17 +5698,200 @@ struct LineInfo {
     last_fragment_index: usize,
     line_length: f64,
     has_next_line: bool,
+    hard_break: bool,
+    /// Advance of the whitespace at the end of the line, not counted when aligning it.
+    trailing_whitespace: f64,
+    /// Largest ascent and descent of the fragments on the line.
+    ascent: f64,
+    descent: f64,
+    /// Largest size of the fragments on the line, 0 for empty lines.
+    size: usize,
+}
+
+impl LineInfo {
+    /// Length the line is aligned by, without the whitespace hanging at its end.
+    fn visible_length(&self) -> f64 {
+        self.line_length - self.trailing_whitespace
+    }
 }
 
 #[derive(Clone)]
 struct ParagraphInfo {
     shaped_fragments: Vec<ShapedFragment>,
+    /// Source bytes of the paragraph without its separator.
+    source: std::ops::Range<usize>,
     lines: Vec<LineInfo>,
     is_rtl: bool,
 }
 
 impl ParagraphInfo {
-    fn new(shaped_fragments: Vec<ShapedFragment>, max_line_length: f64, is_rtl: bool) -> Self {
+    /// `max_line_length` gives the room there is for each line of the paragraph and `hard_break`
+    /// tells whether the paragraph ends in a mandatory break. Fragments sharing a line are
+    /// [`LayoutOptions::letter_spacing`] apart.
+    ///
+    /// Whitespace a wrapped line starts with doesn't count towards its length, see
+    /// [`ShapedFragment::leading_whitespace`].
+    fn new(
+        shaped_fragments: Vec<ShapedFragment>,
+        source: std::ops::Range<usize>,
+        max_line_length: impl Fn(usize) -> f64,
+        options: &LayoutOptions,
+        is_rtl: bool,
+        hard_break: bool,
+    ) -> Self {
+        let fragment_spacing = options.letter_spacing;
+        let break_lines = |breaks_before: &dyn Fn(usize, f64, usize) -> bool| {
+            Self::break_lines(
+                &shaped_fragments,
+                fragment_spacing,
+                hard_break,
+                breaks_before,
+            )
+        };
+        let greedy = break_lines(&|_, length, line| length > max_line_length(line));
+        let balanced = match options.line_breaking {
+            LineBreaking::Greedy => None,
+            LineBreaking::Balance => Self::balanced_breaks(
+                &shaped_fragments,
+                greedy.len(),
+                &max_line_length,
+                fragment_spacing,
+            ),
+        };
+        let lines = match balanced {
+            Some(breaks) => break_lines(&|i, _, _| breaks.contains(&i)),
+            None => greedy,
+        };
+        let orphan_break =
+            Self::orphan_break(&shaped_fragments, &lines, options.min_last_line_words);
+        let lines = match orphan_break {
+            Some(forced) => {
+                // Only the line before the last one changes, the others keep their breaks.
+                let starts = lines[1..lines.len() - 1]
+                    .iter()
+                    .map(|line| line.first_fragment_index)
+                    .collect::<Vec<_>>();
+                let pulled = break_lines(&|i, length, line| {
+                    i == forced || starts.contains(&i) || length > max_line_length(line)
+                });
+                // Words only move down if that doesn't take another line.
+                if pulled.len() == lines.len() {
+                    pulled
+                } else {
+                    lines
+                }
+            }
+            None => lines,
+        };
+
+        Self {
+            shaped_fragments,
+            source,
+            lines,
+            is_rtl,
+        }
+    }
+
+    /// Fragment the line before the last one of `lines` has to end at for the last one to have
+    /// `min_words` fragments, if it has fewer, was wrapped and the line before keeps some.
+    fn orphan_break(
+        fragments: &[ShapedFragment],
+        lines: &[LineInfo],
+        min_words: usize,
+    ) -> Option<usize> {
+        let [.., previous, last] = lines else {
+            return None;
+        };
+        let words = fragments.len() - last.first_fragment_index;
+        if words >= min_words || fragments[last.first_fragment_index - 1].mandatory_break {
+            return None;
+        }
+
+        let forced = last.first_fragment_index.checked_sub(min_words - words)?;
+        (forced > previous.first_fragment_index).then_some(forced)
+    }
+
+    /// Fragments lines start at, the first one aside, for `line_count` lines as even as possible:
+    /// the sum of the squares of their lengths is the smallest. Every line fits in its
+    /// `max_line_length` unless it has a single fragment, like with greedy breaking. `None` if the
+    /// paragraph isn't balanced, see [`LineBreaking::Balance`].
+    fn balanced_breaks(
+        fragments: &[ShapedFragment],
+        line_count: usize,
+        max_line_length: impl Fn(usize) -> f64,
+        fragment_spacing: f64,
+    ) -> Option<Vec<usize>> {
+        let inner_break = fragments
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|fragment| fragment.mandatory_break);
+        if !(2..=BALANCE_MAX_LINES).contains(&line_count) || inner_break {
+            return None;
+        }
+
+        let n = fragments.len();
+        let mut prefix = vec![0.0; n + 1];
+        for (i, fragment) in fragments.iter().enumerate() {
+            prefix[i + 1] = prefix[i] + fragment.length;
+        }
+        // Squared visible length of the line with fragments `start..end`, if they fit on it.
+        let cost = |start: usize, end: usize, line: usize| {
+            let leading = if start > 0 {
+                fragments[start].leading_whitespace
+            } else {
+                0.0
+            };
+            let spacing = fragment_spacing * (end - start - 1) as f64;
+            let length = prefix[end] - prefix[start] + spacing - leading;
+            if end - start > 1 && length > max_line_length(line) {
+                return None;
+            }
+            let visible = length - fragments[end - 1].trailing_whitespace;
+            Some(visible * visible)
+        };
+
+        // `best[lines][end]` is the cost of the first `end` fragments on `lines` lines and where
+        // the last of those lines starts.
+        let mut best = vec![vec![None::<(f64, usize)>; n + 1]; line_count + 1];
+        best[0][0] = Some((0.0, 0));
+        for lines in 1..=line_count {
+            for end in lines..=n {
+                for start in lines - 1..end {
+                    let Some((before, _)) = best[lines - 1][start] else {
+                        continue;
+                    };
+                    let Some(line) = cost(start, end, lines - 1) else {
+                        continue;
+                    };
+                    if best[lines][end].is_none_or(|(total, _)| before + line < total) {
+                        best[lines][end] = Some((before + line, start));
+                    }
+                }
+            }
+        }
+
+        let mut breaks = vec![];
+        let mut end = n;
+        for lines in (1..=line_count).rev() {
+            let (_, start) = best[lines][end]?;
+            breaks.push(start);
+            end = start;
+        }
+        breaks.pop();
+
+        Some(breaks)
+    }
+
+    /// Splits the paragraph into lines, starting a new one before fragment `i` when
+    /// `breaks_before(i, length, line)` says so, with `length` the length of line `line` so far
+    /// together with that fragment.
+    fn break_lines(
+        shaped_fragments: &[ShapedFragment],
+        fragment_spacing: f64,
+        hard_break: bool,
+        breaks_before: impl Fn(usize, f64, usize) -> bool,
+    ) -> Vec<LineInfo> {
         let mut lines = vec![];
 
         lines.push(LineInfo {
@@ -657,37 +5899,98 @@ impl ParagraphInfo {
             last_fragment_index: 0,
             line_length: 0.0,
             has_next_line: false,
+            hard_break: false,
+            trailing_whitespace: 0.0,
+            ascent: 0.0,
+            descent: 0.0,
+            size: 0,
         });
 
         let mut current_line_length = 0.0;
 
         for (i, fragment) in shaped_fragments.iter().enumerate() {
-            current_line_length += fragment.length;
+            let line_start = lines.last().unwrap().first_fragment_index;
+            let spacing = if i == line_start {
+                0.0
+            } else {
+                fragment_spacing
+            };
+            current_line_length += spacing + fragment.length;
 
-            if current_line_length > max_line_length {
+            if i == line_start && i > 0 {
+                // First fragment after a mandatory break.
+                current_line_length -= fragment.leading_whitespace;
+                lines.last_mut().unwrap().line_length = current_line_length;
+            } else if breaks_before(i, current_line_length, lines.len() - 1) {
                 current_line_length = fragment.length;
 
-                if i > 0 {
+                if i > line_start {
+                    current_line_length -= fragment.leading_whitespace;
                     lines.last_mut().unwrap().last_fragment_index = i;
                     lines.last_mut().unwrap().has_next_line = true;
                     lines.push(LineInfo {
                         first_fragment_index: i,
                         last_fragment_index: i,
-                        line_length: fragment.length,
+                        line_length: current_line_length,
                         has_next_line: false,
+                        hard_break: false,
+                        trailing_whitespace: 0.0,
+                        ascent: 0.0,
+                        descent: 0.0,
+                        size: 0,
                     });
                 } else {
                     lines.last_mut().unwrap().line_length = current_line_length;
                 }
             } else {
-                lines.last_mut().unwrap().line_length += fragment.length;
+                lines.last_mut().unwrap().line_length += spacing + fragment.length;
+            }
+
+            let line = lines.last_mut().unwrap();
+            line.trailing_whitespace = fragment.trailing_whitespace;
+            line.ascent = line.ascent.max(fragment.ascent);
+            line.descent = line.descent.max(fragment.descent);
+            line.size = line.size.max(fragment.size);
+
+            line.hard_break = fragment.mandatory_break;
+            if fragment.mandatory_break && i + 1 < shaped_fragments.len() {
+                line.last_fragment_index = i + 1;
+                line.has_next_line = true;
+                lines.push(LineInfo {
+                    first_fragment_index: i + 1,
+                    last_fragment_index: i + 1,
+                    line_length: 0.0,
+                    has_next_line: false,
+                    hard_break: false,
+                    trailing_whitespace: 0.0,
+                    ascent: 0.0,
+                    descent: 0.0,
+                    size: 0,
+                });
+                current_line_length = 0.0;
             }
         }
 
-        Self {
-            shaped_fragments,
-            lines,
-            is_rtl,
+        lines.last_mut().unwrap().hard_break |= hard_break;
+
+        lines
+    }
+
+    /// Source bytes of each of the fragments in `fragments`, see [`LayoutLine::words`].
+    fn words_of(&self, fragments: std::ops::Range<usize>) -> Vec<std::ops::Range<usize>> {
+        self.shaped_fragments[fragments]
+            .iter()
+            .map(|fragment| fragment.source.clone())
+            .collect()
+    }
+
+    /// Source bytes of the fragments in `fragments`, an empty range where the paragraph is if
+    /// there are none.
+    fn source_of(&self, fragments: std::ops::Range<usize>) -> std::ops::Range<usize> {
+        let fragments = &self.shaped_fragments[fragments];
+        match (fragments.first(), fragments.last()) {
+            (Some(first), Some(last)) => first.source.start..last.source.end,
+            _ => self.source.start..self.source.start,
         }
     }
 }
@@ -0,0 +1,201 @@
+use rustybuzz as hb;
+use wasm_paths::{
+    layout, needs_reordering, validate_layout, LayoutGlyph, LayoutOptions, ReorderingAnomaly,
+};
+
+const PAD: f64 = 12.0;
+
+#[test]
+fn rtl_glyphs_match_harfbuzz_positions() {
+    let text = "שלום";
+    let size = 32;
+    let options = LayoutOptions {
+        fallback_font: "noto".into(),
+        size,
+        ..Default::default()
+    };
+    let glyphs = layout(text, &options).glyphs;
+
+    let mut face = hb::Face::from_slice(
+        include_bytes!("../fonts/NotoSansHebrew-VariableFont_wdth,wght.ttf"),
+        0,
+    )
+    .unwrap();
+    face.set_variation(hb::ttf_parser::Tag::from_bytes(b"wght"), 400.0);
+    let mut buffer = hb::UnicodeBuffer::new();
+    buffer.push_str(text);
+    buffer.set_direction(hb::Direction::RightToLeft);
+    let reference = hb::shape(&face, &[], buffer);
+    let to_px = size as f64 / face.units_per_em() as f64;
+
+    // The line is flush with the right margin and harfbuzz gives glyphs in visual order.
+    let total_advance = reference
+        .glyph_positions()
+        .iter()
+        .map(|position| position.x_advance as f64 * to_px)
+        .sum::<f64>();
+    let mut pen = (options.x + options.w) as f64 - PAD - total_advance;
+    assert_eq!(glyphs.len(), reference.len());
+    for ((glyph, info), position) in glyphs
+        .iter()
+        .zip(reference.glyph_infos())
+        .zip(reference.glyph_positions())
+    {
+        assert_eq!(glyph.glyph_id as u32, info.glyph_id);
+        assert!((glyph.x - pen).abs() < 1e-9);
+        pen += position.x_advance as f64 * to_px;
+    }
+}
+
+#[test]
+fn glyphs_report_buffer_direction() {
+    let rtl = |text: &str, options: &LayoutOptions| {
+        layout(text, options)
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.rtl)
+            .collect::<Vec<_>>()
+    };
+    let options = LayoutOptions {
+        fallback_font: "noto".into(),
+        ..Default::default()
+    };
+
+    assert_eq!(rtl("שלום עולם", &options), vec![true; 9]);
+    assert_eq!(rtl("hello", &options), vec![false; 5]);
+
+    // Every paragraph is shaped in its own direction.
+    let mixed = rtl("שלום\nhi", &options);
+    assert_eq!(mixed, [true, true, true, true, false, false]);
+}
+
+#[test]
+fn numbers_in_rtl_text_read_left_to_right() {
+    let text = "עברית 123 עברית";
+    let options = LayoutOptions {
+        fallback_font: "noto".into(),
+        ..Default::default()
+    };
+    let (first_word_end, digits_start) = (text.find(' ').unwrap(), text.find('1').unwrap());
+    let second_word_start = text.rfind(' ').unwrap() + 1;
+
+    let glyphs = layout(text, &options).glyphs;
+    let in_range = |start: usize, end: usize| {
+        glyphs
+            .iter()
+            .filter(|glyph| (start..end).contains(&glyph.cluster))
+            .collect::<Vec<&LayoutGlyph>>()
+    };
+    let left = |glyphs: &[&LayoutGlyph]| glyphs.iter().map(|g| g.x).fold(f64::MAX, f64::min);
+    let right = |glyphs: &[&LayoutGlyph]| {
+        glyphs
+            .iter()
+            .map(|glyph| glyph.x + glyph.advance)
+            .fold(f64::MIN, f64::max)
+    };
+
+    let digits = in_range(digits_start, digits_start + 3);
+    assert_eq!(digits.len(), 3);
+    assert!(digits.iter().all(|glyph| !glyph.rtl));
+    // One, two, three from left to right.
+    assert!(digits.windows(2).all(|pair| pair[0].x < pair[1].x));
+    // The first word is on the right of the number and the second one on its left.
+    let first_word = in_range(0, first_word_end);
+    let second_word = in_range(second_word_start, text.len());
+    assert!(right(&digits) < left(&first_word));
+    assert!(right(&second_word) < left(&digits));
+    assert!(first_word
+        .iter()
+        .chain(second_word.iter())
+        .all(|glyph| glyph.rtl));
+    let right_margin = (options.x + options.w) as f64 - PAD;
+    assert!((right(&first_word) - right_margin).abs() < 1e-9);
+}
+
+#[test]
+fn only_mixed_directions_need_reordering() {
+    let latin = layout(
+        "Plain left to right text.\nAnother paragraph.",
+        &LayoutOptions::default(),
+    );
+    assert!(!latin.needs_reordering);
+    let hebrew = LayoutOptions::new().fallback_font("noto");
+    assert!(!layout("שלום עולם", &hebrew).needs_reordering);
+    // Each paragraph runs in a single direction, but not the same one.
+    assert!(layout("Latin\nשלום עולם", &hebrew).needs_reordering);
+
+    // The French, Hebrew and French demo input.
+    assert!(needs_reordering(3));
+    assert!(!needs_reordering(2));
+}
+
+#[test]
+fn base_level_sets_the_direction_of_neutral_text() {
+    let text = "(!?)";
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let glyphs = |options: &LayoutOptions| layout(text, options).glyphs;
+
+    // Without a strong character the paragraph is left-to-right unless told otherwise.
+    let default = glyphs(&options);
+    let ltr = glyphs(&options.clone().base_level(0));
+    let rtl = glyphs(&options.clone().base_level(1));
+    assert_eq!(ltr, default);
+    assert!(ltr.iter().all(|glyph| !glyph.rtl));
+    assert!(rtl.iter().all(|glyph| glyph.rtl));
+    // The punctuation runs the other way, so the first character is on the right.
+    let first = |glyphs: &[LayoutGlyph]| glyphs.iter().find(|glyph| glyph.cluster == 0).unwrap().x;
+    let last = |glyphs: &[LayoutGlyph]| glyphs.iter().find(|glyph| glyph.cluster == 3).unwrap().x;
+    assert!(first(&ltr) < last(&ltr));
+    assert!(first(&rtl) > last(&rtl));
+
+    // Even levels are left-to-right too and levels too deep to embed are ignored.
+    assert_eq!(glyphs(&options.clone().base_level(2)), ltr);
+    assert_eq!(glyphs(&options.clone().base_level(200)), ltr);
+}
+
+#[test]
+fn mixed_direction_demo_input_is_displayed_in_order() {
+    // The French, Hebrew and French demo input, however it wraps.
+    for (w, size) in [(600, 16), (300, 16), (450, 24), (200, 32)] {
+        assert_eq!(validate_layout(0, 0, w, 800, size, 3), Vec::<String>::new());
+    }
+    let text = "abc שלום עולם 123 def";
+    let mixed = layout(
+        text,
+        &LayoutOptions::new()
+            .fallback_font("noto")
+            .bounds(0, 0, 90, 400),
+    );
+    assert!(mixed.lines.len() > 1);
+    assert_eq!(mixed.reordering_anomalies(), []);
+
+    // Swapping two glyphs of a word in either direction is caught.
+    let hebrew = text.find('ש').unwrap();
+    let rtl = mixed
+        .glyphs
+        .iter()
+        .position(|glyph| glyph.cluster == hebrew)
+        .unwrap();
+    for i in [rtl - 1, 0] {
+        let mut broken = mixed.clone();
+        let x = broken.glyphs[i].x;
+        broken.glyphs[i].x = broken.glyphs[i + 1].x;
+        broken.glyphs[i + 1].x = x;
+        let anomalies = broken.reordering_anomalies();
+        assert!(!anomalies.is_empty());
+        let line = broken
+            .lines
+            .iter()
+            .position(|line| line.glyphs.contains(&i));
+        for ReorderingAnomaly {
+            line: anomaly_line,
+            glyphs,
+        } in anomalies
+        {
+            assert_eq!(Some(anomaly_line), line);
+            assert!([glyphs.0, glyphs.1]
+                .iter()
+                .any(|glyph| [i, i + 1].contains(glyph)));
+        }
+    }
+}
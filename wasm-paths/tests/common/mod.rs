@@ -0,0 +1,21 @@
+//! Fonts the tests need that the library doesn't ship with. Layout state is per thread, so they
+//! are registered by every test that uses them.
+#![allow(dead_code)]
+
+use wasm_paths::register_font;
+
+/// DejaVu Sans as `dejavu`, which has Arabic with its own digits and a dotted circle.
+pub fn register_dejavu() {
+    assert!(register_font(
+        "dejavu",
+        include_bytes!("../fonts/DejaVuSans.ttf").to_vec()
+    ));
+}
+
+/// DejaVu Sans Mono as `mono`, whose glyphs all have the same advance.
+pub fn register_mono() {
+    assert!(register_font(
+        "mono",
+        include_bytes!("../fonts/DejaVuSansMono.ttf").to_vec()
+    ));
+}
@@ -0,0 +1,414 @@
+use wasm_paths::{
+    alias_font, font_features, font_glyph_coverage, get_paths, layout, register_font,
+    size_for_cap_height, validate_font, validate_input, LayoutOptions, Script, SizeNormalization,
+    StyledRun, Warning,
+};
+
+mod common;
+
+#[test]
+fn aliased_font_shapes_identically() {
+    assert!(alias_font("roboto", "system-ui"));
+    assert!(!alias_font("missing", "also-missing"));
+
+    let shape = |font: &str| {
+        let options = LayoutOptions {
+            fallback_font: font.into(),
+            ..Default::default()
+        };
+        layout("Aliased fonts share their face.", &options)
+            .glyphs
+            .into_iter()
+            .map(|glyph| (glyph.glyph_id, glyph.path))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(shape("system-ui"), shape("roboto"));
+    assert_ne!(shape("system-ui"), shape("pt"));
+}
+
+#[test]
+fn aliasing_over_a_font_in_use_lays_out_again() {
+    let paths = || get_paths(0, 0, 800, 600, 16, 2);
+    let before = paths();
+    assert_eq!(paths(), before);
+
+    assert!(alias_font("roboto", "pt"));
+    let after = paths();
+    assert_ne!(after, before);
+    // Fonts are per thread, so one that aliases before its first layout shows what to expect.
+    let fresh = std::thread::spawn(move || {
+        assert!(alias_font("roboto", "pt"));
+        paths()
+    });
+    assert_eq!(fresh.join().unwrap(), after);
+}
+
+#[test]
+fn missing_glyphs_are_counted() {
+    let hebrew_in = |font: &str| {
+        let options = LayoutOptions {
+            fallback_font: font.into(),
+            ..Default::default()
+        };
+        layout("שלום", &options).notdef_count
+    };
+
+    assert_eq!(hebrew_in("roboto"), 4);
+    assert_eq!(hebrew_in("noto"), 0);
+}
+
+#[test]
+fn font_features_lists_layout_tables() {
+    let roboto = font_features("roboto");
+    for tag in ["kern", "onum", "smcp", "ss01", "tnum"] {
+        assert!(roboto.contains(&tag.to_string()), "roboto is missing {tag}");
+    }
+    assert!(roboto.windows(2).all(|pair| pair[0] < pair[1]));
+
+    assert!(!font_features("pt").contains(&"smcp".to_string()));
+    assert!(font_features("no-such-font").is_empty());
+}
+
+#[test]
+fn cap_height_normalization_matches_fonts() {
+    common::register_dejavu();
+    let cap_height = |font: &str, normalization| {
+        let options = LayoutOptions::new()
+            .size(32)
+            .fallback_font(font)
+            .size_normalization(normalization);
+        layout("H", &options).glyphs[0].bounds.h
+    };
+
+    // DejaVu Sans has taller capitals than PT Serif for the same em size.
+    let (pt, dejavu) = (
+        cap_height("pt", SizeNormalization::None),
+        cap_height("dejavu", SizeNormalization::None),
+    );
+    assert!(dejavu - pt > 0.5);
+
+    for font in ["pt", "dejavu", "roboto"] {
+        let normalized = cap_height(font, SizeNormalization::CapHeight);
+        assert!((normalized - 0.7 * 32.0).abs() < 1e-9);
+    }
+}
+
+/// A font with only the tables a face can't be parsed without: `head`, `hhea` and `maxp`.
+/// A font file with `tables`, without checksums.
+fn sfnt(tables: &[(&[u8], &[u8])]) -> Vec<u8> {
+    let mut font = vec![
+        0x00,
+        0x01,
+        0x00,
+        0x00,
+        0x00,
+        tables.len() as u8,
+        0,
+        0,
+        0,
+        0,
+        0,
+        0,
+    ];
+    let mut offset = 12 + 16 * tables.len();
+    for (tag, data) in tables.iter() {
+        font.extend_from_slice(tag);
+        font.extend_from_slice(&[0; 4]);
+        font.extend_from_slice(&(offset as u32).to_be_bytes());
+        font.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+    for (_, data) in tables.iter() {
+        font.extend_from_slice(data);
+    }
+
+    font
+}
+
+fn tableless_font() -> Vec<u8> {
+    let mut head = vec![0; 54];
+    head[18..20].copy_from_slice(&1000_u16.to_be_bytes());
+    let hhea = vec![0; 36];
+    let maxp = [0x00, 0x00, 0x50, 0x00, 0x00, 0x01];
+
+    sfnt(&[(b"head", &head), (b"hhea", &hhea), (b"maxp", &maxp)])
+}
+
+/// `font` with the table `tag` added.
+fn with_table(font: &[u8], tag: &[u8], data: &[u8]) -> Vec<u8> {
+    let at = |offset: usize| u32::from_be_bytes(font[offset..offset + 4].try_into().unwrap());
+    let count = u16::from_be_bytes([font[4], font[5]]) as usize;
+    let mut tables = (0..count)
+        .map(|i| {
+            let record = 12 + 16 * i;
+            let (offset, length) = (at(record + 8) as usize, at(record + 12) as usize);
+            (&font[record..record + 4], &font[offset..offset + length])
+        })
+        .collect::<Vec<_>>();
+    tables.push((tag, data));
+    // Table records are looked up by binary search.
+    tables.sort_by_key(|(tag, _)| *tag);
+
+    sfnt(&tables)
+}
+
+#[test]
+fn validation_tells_text_fonts_apart() {
+    let uploaded = include_bytes!("fonts/DejaVuSans.ttf").to_vec();
+    assert!(register_font("uploaded", uploaded));
+    let validation = validate_font("uploaded");
+    assert!(validation.has_cmap && validation.has_outlines);
+    assert!(validation.number_of_glyphs > 1000);
+    assert_eq!(validation.units_per_em, 2048);
+    assert!(validation.is_usable());
+    let options = LayoutOptions::new().fallback_font("uploaded");
+    assert_eq!(layout("Uploaded", &options).notdef_count, 0);
+
+    assert!(register_font("empty", tableless_font()));
+    let validation = validate_font("empty");
+    assert!(!validation.has_cmap && !validation.has_outlines);
+    assert_eq!(
+        (validation.number_of_glyphs, validation.units_per_em),
+        (1, 1000)
+    );
+    assert!(!validation.is_usable());
+
+    assert!(!register_font("garbage", b"not a font".to_vec()));
+    assert!(!validate_font("garbage").is_usable());
+}
+
+#[test]
+fn registering_a_font_again_replaces_it() {
+    let glyphs = |font: &str| validate_font(font).number_of_glyphs;
+    let dejavu = include_bytes!("fonts/DejaVuSans.ttf");
+    let pt = include_bytes!("../fonts/PTSerif-Regular.ttf");
+
+    assert!(register_font("replaced", dejavu.to_vec()));
+    let before = glyphs("replaced");
+    assert!(register_font("replaced", dejavu.to_vec()));
+    assert_eq!(glyphs("replaced"), before);
+
+    // An alias keeps using the replaced font.
+    assert!(alias_font("replaced", "kept"));
+    assert!(register_font("replaced", pt.to_vec()));
+    assert_eq!(glyphs("replaced"), glyphs("pt"));
+    assert_ne!(glyphs("replaced"), before);
+    assert_eq!(glyphs("kept"), before);
+    let options = LayoutOptions::new().fallback_font("kept");
+    assert_eq!(layout("Kept", &options).notdef_count, 0);
+
+    // Data that isn't a font leaves the registered one alone.
+    assert!(!register_font("replaced", vec![0; 16]));
+    assert_eq!(glyphs("replaced"), glyphs("pt"));
+}
+
+#[test]
+fn scripts_fall_back_to_their_fonts() {
+    let text = "Latin עברית 한글";
+    let hebrew = text.find('ע').unwrap()..text.rfind(' ').unwrap();
+    let hangul = text.find('한').unwrap()..text.len();
+    let plain = LayoutOptions::new().fallback_font("pt");
+    let options = plain
+        .clone()
+        .script_font(Script::Latin, "roboto")
+        .script_font(Script::Hebrew, "noto")
+        .script_font(Script::Hangul, "seoul");
+    assert!(layout(text, &plain).notdef_count > 0);
+
+    let result = layout(text, &options);
+    assert_eq!(result.notdef_count, 0);
+    for glyph in result.glyphs.iter() {
+        // PT Serif has Latin letters and spaces, so Roboto is never needed.
+        let expected = if hebrew.contains(&glyph.cluster) {
+            "noto"
+        } else if hangul.contains(&glyph.cluster) {
+            "seoul"
+        } else {
+            "pt"
+        };
+        assert_eq!(glyph.font, expected);
+    }
+    // The fallback glyphs are the ones the fonts themselves shape.
+    let ids = |text: &str, font: &str| {
+        let mut ids = layout(text, &LayoutOptions::new().fallback_font(font))
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.glyph_id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    let fallback_ids = |range: &std::ops::Range<usize>| {
+        let mut ids = result
+            .glyphs
+            .iter()
+            .filter(|glyph| range.contains(&glyph.cluster))
+            .map(|glyph| glyph.glyph_id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        ids
+    };
+    assert_eq!(fallback_ids(&hebrew), ids(&text[hebrew.clone()], "noto"));
+    assert_eq!(fallback_ids(&hangul), ids(&text[hangul.clone()], "seoul"));
+}
+
+#[test]
+fn runs_share_the_alphabetic_baseline() {
+    const SHIFT: i16 = 150;
+    // A horizontal axis with only the `romn` baseline, given for the default script.
+    let mut base = vec![0, 1, 0, 0, 0, 8, 0, 0];
+    base.extend_from_slice(&[0, 4, 0, 10]);
+    base.extend_from_slice(&[0, 1, b'r', b'o', b'm', b'n']);
+    base.extend_from_slice(&[0, 1, b'D', b'F', b'L', b'T', 0, 8]);
+    base.extend_from_slice(&[0, 6, 0, 0, 0, 0]);
+    base.extend_from_slice(&[0, 0, 0, 1, 0, 6]);
+    base.extend_from_slice(&[0, 1]);
+    base.extend_from_slice(&SHIFT.to_be_bytes());
+    let noto = include_bytes!("../fonts/NotoSansHebrew-VariableFont_wdth,wght.ttf");
+    assert!(register_font(
+        "raised-noto",
+        with_table(noto, b"BASE", &base)
+    ));
+    // Registered the same way so that both are at the default weight.
+    assert!(register_font("plain-noto", noto.to_vec()));
+
+    let text = "Latin עברית";
+    let hebrew = text.find('ע').unwrap();
+    let options = |font: &str| {
+        LayoutOptions::new()
+            .size(30)
+            .script_font(Script::Hebrew, font)
+    };
+    let plain = layout(text, &options("plain-noto"));
+    let result = layout(text, &options("raised-noto"));
+    // Noto Sans Hebrew has 1000 units per em.
+    let shift = SHIFT as f64 * 30.0 / 1000.0;
+
+    let (line, plain_line) = (&result.lines[0], &plain.lines[0]);
+    assert!(line.descent > plain_line.descent);
+    for (glyph, plain) in result.glyphs.iter().zip(plain.glyphs.iter()) {
+        if glyph.cluster < hebrew {
+            assert_eq!(glyph.y, line.y);
+            continue;
+        }
+        // The font's own baseline is `SHIFT` units up, so its glyphs come down by as much.
+        assert_eq!(glyph.font, "raised-noto");
+        assert!((glyph.y - shift - line.y).abs() < 1e-9);
+        let plain_top = plain.bounds.y - plain_line.y;
+        assert!((glyph.bounds.y - line.y - shift - plain_top).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn styled_runs_attribute_fonts_in_logical_order() {
+    let text = "Roboto first, then שלום in Noto\nand PT Serif.";
+    let (hebrew, second) = (text.find('ש').unwrap(), text.find('\n').unwrap() + 1);
+    let in_noto = text.find(" in").unwrap();
+    let colored = text.find("PT").unwrap()..text.find(" Serif").unwrap();
+    let options = LayoutOptions::new()
+        .paragraphs_fonts(vec!["roboto".into(), "pt".into()])
+        .script_font(Script::Hebrew, "noto")
+        .color(colored.start, colored.end, "red");
+
+    let runs = layout(text, &options).styled_runs(text);
+    let run = |source: std::ops::Range<usize>, font: &str, color: Option<&str>| StyledRun {
+        source,
+        font: font.into(),
+        color: color.map(String::from),
+    };
+    assert_eq!(
+        runs,
+        [
+            run(0..hebrew, "roboto", None),
+            run(hebrew..in_noto, "noto", None),
+            run(in_noto..second, "roboto", None),
+            run(second..colored.start, "pt", None),
+            run(colored.clone(), "pt", Some("red")),
+            run(colored.end..text.len(), "pt", None),
+        ]
+    );
+}
+
+#[test]
+fn glyph_coverage_maps_glyphs_back_to_code_points() {
+    let coverage = font_glyph_coverage("roboto");
+    let a = layout("A", &LayoutOptions::new().fallback_font("roboto")).glyphs[0].glyph_id;
+    assert_eq!(coverage[a as usize], (a, vec![0x41]));
+    assert!(coverage
+        .iter()
+        .enumerate()
+        .all(|(i, (glyph_id, _))| i == *glyph_id as usize));
+    // The notdef glyph has no code point, the "ffi" ligature only its presentation form.
+    assert!(coverage[0].1.is_empty());
+    let ffi = layout("ffi", &LayoutOptions::new().fallback_font("roboto")).glyphs[0].glyph_id;
+    assert_eq!(coverage[ffi as usize].1, [0xfb03]);
+
+    assert!(font_glyph_coverage("missing").is_empty());
+}
+
+#[test]
+fn sizes_for_a_cap_height_give_capitals_that_tall() {
+    common::register_dejavu();
+    common::register_mono();
+    let cap_height = |font: &str, size: usize| {
+        let options = LayoutOptions::new().fallback_font(font).size(size);
+        layout("H", &options).glyphs[0].bounds.h
+    };
+
+    for font in ["pt", "dejavu", "roboto", "mono"] {
+        for target in [10.0, 24.5, 72.0] {
+            let size = size_for_cap_height(font, target);
+            // As close as a whole size gets.
+            let error = |size: usize| (cap_height(font, size) - target).abs();
+            assert!(
+                error(size) <= error(size - 1) && error(size) <= error(size + 1),
+                "{font}"
+            );
+            assert!(
+                error(size) < 0.5,
+                "{font}: {} at {size}",
+                cap_height(font, size)
+            );
+        }
+    }
+    assert_eq!(size_for_cap_height("roboto", 0.0), 1);
+}
+
+#[test]
+fn validation_reports_missing_fonts_and_uncovered_scripts() {
+    let text = "Hello 한국어 and ሰላም ሰላም";
+    let options = LayoutOptions::new()
+        .fallback_font("missing")
+        .script_font(Script::Ethiopian, "also-missing");
+    assert_eq!(
+        validate_input(text.as_bytes(), &options),
+        vec![
+            Warning::UnknownFont("also-missing".into()),
+            Warning::UnknownFont("missing".into()),
+            Warning::UncoveredScript {
+                script: Script::Hangul,
+                offset: text.find('한').unwrap(),
+            },
+            Warning::UncoveredScript {
+                script: Script::Ethiopian,
+                offset: text.find('ሰ').unwrap(),
+            },
+        ]
+    );
+
+    // A font for the script covers it.
+    let options = LayoutOptions::new().script_font(Script::Hangul, "seoul");
+    assert_eq!(validate_input("한국어".as_bytes(), &options), vec![]);
+
+    let options = LayoutOptions::new()
+        .feature(0, 1, "kern", 0)
+        .feature(0, 1, "kerning", 0);
+    assert_eq!(
+        validate_input(b"ab\xffc", &options),
+        vec![
+            Warning::InvalidUtf8(2),
+            Warning::InvalidFeatureTag("kerning".into())
+        ]
+    );
+}
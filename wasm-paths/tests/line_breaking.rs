@@ -0,0 +1,643 @@
+use wasm_paths::{
+    fit_to_box, layout, line_break_offsets, max_line_width, paragraph_ranges, preferred_height,
+    HorizontalAlignment, LayoutLine, LayoutOptions, LineBreaking, Rect,
+};
+
+mod common;
+
+#[test]
+fn lines_report_hard_and_soft_breaks() {
+    let options = LayoutOptions {
+        w: 69,
+        ..Default::default()
+    };
+    let lines = layout("a\nbbbb bbbb", &options).lines;
+
+    let hard_breaks = lines.iter().map(|line| line.hard_break).collect::<Vec<_>>();
+    assert_eq!(hard_breaks, [true, false, false]);
+    assert_eq!(lines[0].paragraph, 0);
+    assert!(lines[1..].iter().all(|line| line.paragraph == 1));
+}
+
+#[test]
+fn preferred_height_fits_all_lines() {
+    let text = "A paragraph long enough to wrap a few times in a narrow box.\nAnd another one.";
+    let (w, size) = (150, 20);
+    let height = preferred_height(text, "roboto", w, size);
+    let options = LayoutOptions {
+        w,
+        h: height.ceil() as i32,
+        size,
+        fallback_font: "roboto".into(),
+        ..Default::default()
+    };
+
+    let result = layout(text, &options);
+    assert!(result.lines.len() > 2);
+    assert!(!result.overflow);
+
+    let shorter = LayoutOptions {
+        h: options.h - 1,
+        ..options
+    };
+    assert!(layout(text, &shorter).overflow);
+}
+
+#[test]
+fn continuation_lines_start_at_margin() {
+    const PAD: f64 = 12.0;
+    let options = LayoutOptions {
+        w: 90,
+        ..Default::default()
+    };
+
+    for text in [
+        "wrapped    after several spaces",
+        "wrapped\t\tafter tabs",
+        "wrapped \u{3000}after an ideographic space",
+        "wrapped\u{2028}  after a line separator",
+    ] {
+        let result = layout(text, &options);
+        assert!(result.lines.len() > 1);
+        for line in result.lines.iter().skip(1) {
+            let first_visible = result.glyphs[line.glyphs.clone()]
+                .iter()
+                .find(|glyph| !text[glyph.cluster..].starts_with(char::is_whitespace))
+                .unwrap();
+            assert_eq!(first_visible.x, options.x as f64 + PAD);
+        }
+    }
+}
+
+#[test]
+fn fit_to_box_grows_and_shrinks() {
+    const PAD: f64 = 12.0;
+    let (min, max, w, h) = (8, 64, 300, 80);
+    let fits = |text: &str, size| {
+        let options = LayoutOptions {
+            w,
+            h,
+            size,
+            fallback_font: "roboto".into(),
+            ..Default::default()
+        };
+        let result = layout(text, &options);
+        !result.overflow
+            && result
+                .lines
+                .iter()
+                .all(|line| line.width <= w as f64 - 2.0 * PAD)
+    };
+
+    let (size, paths) = fit_to_box("Heading", "roboto", min, max, w, h);
+    assert!(size > 32);
+    assert!(!paths.is_empty());
+    assert!(size == max || !fits("Heading", size + 1));
+
+    let long = "A much longer piece of text that has to wrap onto many lines and only fits \
+                the box once it has been shrunk well below the usual size for body copy.";
+    let (size, _) = fit_to_box(long, "roboto", min, max, w, h);
+    assert!(size < 16);
+    assert!(fits(long, size));
+    assert!(!fits(long, size + 1));
+}
+
+#[test]
+fn caller_break_opportunities() {
+    let text = "unbreakablelongword";
+    let options = LayoutOptions {
+        w: 100,
+        ..Default::default()
+    };
+    assert_eq!(layout(text, &options).lines.len(), 1);
+
+    let injected = layout(
+        text,
+        &LayoutOptions {
+            break_opportunities: vec![11],
+            ..options.clone()
+        },
+    );
+    assert_eq!(injected.lines.len(), 2);
+    assert_eq!(injected.glyphs[injected.lines[1].glyphs.start].cluster, 11);
+
+    let text = "two words";
+    assert_eq!(
+        layout(text, &options.clone().bounds(0, 0, 60, 400))
+            .lines
+            .len(),
+        2
+    );
+    let suppressed = LayoutOptions {
+        suppressed_breaks: vec![4],
+        ..options.bounds(0, 0, 60, 400)
+    };
+    assert_eq!(layout(text, &suppressed).lines.len(), 1);
+}
+
+#[test]
+fn unicode_separators_break_lines_and_paragraphs() {
+    common::register_dejavu();
+    let options = LayoutOptions {
+        fallback_font: "dejavu".into(),
+        ..Default::default()
+    };
+
+    let text = "abc\u{2029}שלום";
+    let result = layout(text, &options);
+    assert_eq!(result.glyphs.len(), 7);
+    assert_eq!(result.lines.len(), 2);
+    assert!(result.lines[0].hard_break);
+    assert_eq!(result.lines[1].paragraph, 1);
+    assert!(!result.glyphs[..3].iter().any(|glyph| glyph.rtl));
+    assert!(result.glyphs[3..].iter().all(|glyph| glyph.rtl));
+
+    // A line separator breaks the line no matter how wide the box is, the paragraph goes on.
+    let text = "one\u{2028}two";
+    let result = layout(text, &options);
+    assert_eq!(result.glyphs.len(), 6);
+    assert_eq!(result.notdef_count, 0);
+    let breaks = result
+        .lines
+        .iter()
+        .map(|line| (line.paragraph, line.hard_break))
+        .collect::<Vec<_>>();
+    assert_eq!(breaks, [(0, true), (0, false)]);
+    let second = &result.glyphs[result.lines[1].glyphs.clone()];
+    assert_eq!(second[0].cluster, text.find('t').unwrap());
+    assert_eq!(second[0].x, result.glyphs[0].x);
+}
+
+#[test]
+fn zero_width_space_breaks_without_a_glyph() {
+    let text = "ab\u{200B}cd";
+    let options = LayoutOptions {
+        w: 49,
+        fallback_font: "roboto".into(),
+        letter_spacing: 1.0,
+        ..Default::default()
+    };
+    let wide = LayoutOptions {
+        w: 600,
+        ..options.clone()
+    };
+
+    let result = layout(text, &wide);
+    assert_eq!(result.glyphs.len(), 4);
+    assert!(!result.glyphs.iter().any(|glyph| glyph.cluster == 2));
+    let b = &result.glyphs[1];
+    assert!((result.glyphs[2].x - (b.x + b.advance + 1.0)).abs() < 1e-9);
+
+    let result = layout(text, &options);
+    assert_eq!(result.lines.len(), 2);
+    let second = &result.glyphs[result.lines[1].glyphs.clone()];
+    assert_eq!(second[0].cluster, text.find('c').unwrap());
+}
+
+#[test]
+fn empty_paragraphs_keep_their_line() {
+    let text = "a\n\nb";
+    let result = layout(text, &LayoutOptions::default());
+    assert_eq!(result.lines.len(), 3);
+    let blank = &result.lines[1];
+    assert_eq!(blank.paragraph, 1);
+    assert!(blank.glyphs.is_empty());
+    assert!(blank.hard_break);
+
+    let (a, b) = (&result.glyphs[0], &result.glyphs[1]);
+    assert_eq!(b.cluster, text.find('b').unwrap());
+    assert!((b.y - a.y - 2.0 * result.line_height).abs() < 1e-9);
+    let single = layout("a\nb", &LayoutOptions::default());
+    assert!((b.y - single.glyphs[1].y - single.line_height).abs() < 1e-9);
+}
+
+#[test]
+fn soft_tabs_are_spaces_wide() {
+    const PAD: f64 = 12.0;
+    let options = LayoutOptions::new().soft_tabs(4);
+    let space = layout(" ", &options).glyphs[0].advance;
+
+    let glyphs = layout("a\tb", &options).glyphs;
+    assert_eq!(glyphs.iter().filter(|glyph| glyph.cluster == 1).count(), 4);
+    let (a, b) = (&glyphs[0], glyphs.last().unwrap());
+    assert!((b.x - a.x - a.advance - 4.0 * space).abs() < 1e-9);
+
+    // Like the tab, the spaces hang past the margin at the end of a wrapped line.
+    let text = "wrapped\tafter";
+    let options = LayoutOptions {
+        horizontal_alignment: HorizontalAlignment::Reverse,
+        ..options.bounds(0, 0, 90, 400)
+    };
+    let result = layout(text, &options);
+    assert_eq!(result.lines.len(), 2);
+    let d = &result.glyphs[text.find('\t').unwrap() - 1];
+    assert!((d.x + d.advance - (90.0 - PAD)).abs() < 1e-9);
+}
+
+#[test]
+fn indices_map_to_lines() {
+    let text = "First line\nThe quick brown fox jumps over the lazy dog.";
+    let options = LayoutOptions {
+        w: 150,
+        ..Default::default()
+    };
+    let result = layout(text, &options);
+    assert!(result.lines.len() > 3);
+
+    assert_eq!(result.index_to_line(0), Some((0, 0)));
+    // The end of a paragraph is still on its last line.
+    assert_eq!(result.index_to_line(text.find('\n').unwrap()), Some((0, 0)));
+    let second = text.find("The").unwrap();
+    assert_eq!(result.index_to_line(second), Some((1, 1)));
+    let lazy = text.find("lazy").unwrap();
+    let (paragraph, line) = result.index_to_line(lazy).unwrap();
+    assert_eq!(paragraph, 1);
+    assert!(line > 1);
+    assert!(result.glyphs[result.lines[line].glyphs.clone()]
+        .iter()
+        .any(|glyph| glyph.cluster == lazy));
+    let last = result.lines.len() - 1;
+    assert_eq!(result.index_to_line(text.len()), Some((1, last)));
+
+    // Moving between lines keeps the caret at the margin.
+    assert_eq!(result.move_vertically(second, -1), Some(0));
+    assert_eq!(
+        result.move_vertically(second, 1),
+        Some(result.lines[2].source.start)
+    );
+    assert_eq!(result.move_vertically(0, -1), None);
+    assert_eq!(result.move_vertically(second, last as isize), None);
+    let above = result.move_vertically(lazy, -1).unwrap();
+    assert_eq!(result.index_to_line(above), Some((1, line - 1)));
+    assert_eq!(result.move_vertically(above, 1), Some(lazy));
+}
+
+#[test]
+fn glyph_budget_truncates_long_text() {
+    const BUDGET: usize = 500;
+    let options = LayoutOptions::default().max_glyphs(BUDGET);
+    let enormous = "Lorem ipsum dolor sit amet. ".repeat(10_000);
+
+    let result = layout(&enormous, &options);
+    assert!(result.truncated);
+    assert!(result.glyphs.len() <= BUDGET);
+    assert!(result.glyphs.len() > BUDGET - 20);
+    let laid_out = result.lines.last().unwrap().source.end;
+    assert!(laid_out < 1000);
+
+    // Later paragraphs are left out entirely.
+    let paragraphs = "Short paragraph.\n".repeat(2_000);
+    let result = layout(&paragraphs, &options);
+    assert!(result.truncated);
+    assert!(result.glyphs.len() <= BUDGET);
+    assert!(result.lines.len() < 40);
+
+    // Text within the budget is whole.
+    let result = layout("Lorem ipsum dolor sit amet.", &options);
+    assert!(!result.truncated);
+    assert!(!layout(&enormous[..1000], &LayoutOptions::default()).truncated);
+}
+
+#[test]
+fn lines_flow_around_exclusions() {
+    let text = "Text next to an image floated into the top left corner of the box flows around it \
+                and only takes the whole width again once it gets past the bottom of the image.";
+    let image = Rect {
+        x: 0.0,
+        y: 0.0,
+        w: 150.0,
+        h: 60.0,
+    };
+    let options = LayoutOptions::new().bounds(0, 0, 400, 600);
+    let plain = layout(text, &options);
+    let result = layout(text, &options.clone().exclusion(image));
+    let padding = options.insets.left;
+
+    let beside = result
+        .lines
+        .iter()
+        .take_while(|line| line.y - result.line_height < image.y + image.h)
+        .count();
+    assert!(beside >= 2);
+    assert!(result.lines.len() > plain.lines.len());
+    for (i, line) in result.lines.iter().enumerate() {
+        let first = &result.glyphs[line.glyphs.start];
+        if i < beside {
+            assert_eq!(first.x, image.x + image.w);
+            assert!(line.width <= 400.0 - padding - (image.x + image.w));
+        } else {
+            assert_eq!(first.x, padding);
+        }
+    }
+    assert!(result.lines[beside].width > result.lines[0].width);
+}
+
+#[test]
+fn line_break_offsets_follow_whitespace() {
+    let text = "Break offsets land right after the spaces lines wrap at.";
+    let offsets = line_break_offsets(text, "roboto", 150, 20);
+    assert!(offsets.len() > 2);
+    assert_eq!(offsets[0], 0);
+    for &offset in &offsets[1..] {
+        assert!(text[..offset].ends_with(' '));
+        assert!(!text[offset..].starts_with(' '));
+    }
+
+    let options = LayoutOptions {
+        w: 150,
+        size: 20,
+        fallback_font: "roboto".into(),
+        ..Default::default()
+    };
+    let starts = layout(text, &options)
+        .lines
+        .iter()
+        .map(|line| line.source.start)
+        .collect::<Vec<_>>();
+    assert_eq!(offsets, starts);
+}
+
+#[test]
+fn balanced_headings_have_even_lines() {
+    let text = "Balancing wrapped text";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .size(24)
+        .bounds(0, 0, 250, 300);
+    let spread = |line_breaking| {
+        let lines = layout(text, &options.clone().line_breaking(line_breaking)).lines;
+        let widths = lines.iter().map(|line| line.width);
+        let (min, max) = widths.fold((f64::MAX, f64::MIN), |(min, max), width| {
+            (min.min(width), max.max(width))
+        });
+        (lines.len(), max - min, lines[1].source.clone())
+    };
+
+    let (greedy_lines, greedy_spread, greedy_second) = spread(LineBreaking::Greedy);
+    let (balanced_lines, balanced_spread, balanced_second) = spread(LineBreaking::Balance);
+    assert_eq!((greedy_lines, balanced_lines), (2, 2));
+    assert!(balanced_spread < greedy_spread);
+    assert_eq!(&text[greedy_second], "text");
+    assert_eq!(&text[balanced_second], "wrapped text");
+}
+
+#[test]
+fn break_diagnostics_list_fragments_and_line_starts() {
+    let text = "Diagnose where these words wrap\u{2028}and why";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 200, 300);
+    assert!(layout(text, &options).break_diagnostics.is_none());
+    let result = layout(text, &options.break_diagnostics(true));
+    let json = result.break_diagnostics.unwrap();
+    let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+    let paragraphs = report["paragraphs"].as_array().unwrap();
+    assert_eq!(paragraphs.len(), 1);
+    let fragments = paragraphs[0]["fragments"].as_array().unwrap();
+    let texts = fragments
+        .iter()
+        .map(|fragment| fragment["text"].as_str().unwrap());
+    assert_eq!(
+        texts.collect::<Vec<_>>(),
+        [
+            "Diagnose ",
+            "where ",
+            "these ",
+            "words ",
+            "wrap\u{2028}",
+            "and ",
+            "why"
+        ]
+    );
+    let mut x = 0.0;
+    for fragment in fragments {
+        assert_eq!(fragment["x"].as_f64().unwrap(), x);
+        x += fragment["width"].as_f64().unwrap();
+    }
+
+    let lines = paragraphs[0]["lines"].as_array().unwrap();
+    assert_eq!(lines.len(), result.lines.len());
+    let reasons = lines.iter().map(|line| line["reason"].as_str().unwrap());
+    assert_eq!(
+        reasons.collect::<Vec<_>>(),
+        ["start", "overflow", "mandatory_break"]
+    );
+    assert_eq!(lines[2]["first_fragment"], 5);
+    assert!(lines
+        .iter()
+        .all(|line| line["max_length"].as_f64() == Some(176.0)));
+}
+
+#[test]
+fn orphan_control_pulls_words_onto_the_last_line() {
+    let text = "The quick brown fox jumps over the lazy dog";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 320, 300);
+    let last_line = |min_words| {
+        let lines = layout(text, &options.clone().min_last_line_words(min_words)).lines;
+        assert_eq!(lines.len(), 2);
+        text[lines[1].source.clone()].to_string()
+    };
+
+    assert_eq!(last_line(0), "dog");
+    assert_eq!(last_line(2), "lazy dog");
+    assert_eq!(last_line(3), "the lazy dog");
+    // Paragraphs that fit on one line have nothing to pull.
+    let lines = layout("Short", &options.clone().min_last_line_words(2)).lines;
+    assert_eq!(lines.len(), 1);
+    // Nor do ones with fewer words than asked for.
+    let narrow = LayoutOptions {
+        w: 30,
+        ..Default::default()
+    };
+    let lines = layout("a b", &narrow.clone().min_last_line_words(3)).lines;
+    assert_eq!(lines, layout("a b", &narrow).lines);
+    assert_eq!(lines.len(), 2);
+}
+
+#[test]
+fn orphan_control_applies_to_balanced_lines() {
+    let text = "Lines of words end with incomprehensibilities";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 320, 300)
+        .line_breaking(LineBreaking::Balance);
+    let lines = |min_words| {
+        let result = layout(text, &options.clone().min_last_line_words(min_words));
+        result
+            .lines
+            .iter()
+            .map(|line| text[line.source.clone()].to_string())
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        lines(0),
+        ["Lines of words end with ", "incomprehensibilities"]
+    );
+    assert_eq!(
+        lines(2),
+        ["Lines of words end ", "with incomprehensibilities"]
+    );
+}
+
+#[test]
+fn no_break_spaces_never_wrap() {
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let space = layout("10 000", &options).glyphs[2].advance;
+    // An ordinary space does wrap at these widths.
+    assert_eq!(
+        layout("10 000", &options.clone().bounds(0, 0, 30, 300))
+            .lines
+            .len(),
+        2
+    );
+    for (text, narrow) in [("10\u{a0}000", false), ("10\u{202f}000", true)] {
+        let numbers = [text; 3].join(" ");
+        for w in (30..200).step_by(10) {
+            let lines = layout(&numbers, &options.clone().bounds(0, 0, w, 300)).lines;
+            for line in &lines {
+                // Only the ordinary spaces between the numbers are break opportunities.
+                let source = numbers[line.source.clone()].trim_end_matches(' ');
+                assert!(
+                    source.split(' ').all(|group| group == text),
+                    "{w}: {source:?}"
+                );
+            }
+        }
+
+        // Still drawn as spaces, a narrow one for U+202F.
+        let glyphs = layout(text, &options).glyphs;
+        assert_eq!(glyphs.len(), 6);
+        assert!(glyphs[2].path.is_empty());
+        assert_eq!(glyphs[2].advance < space, narrow);
+        assert!(glyphs[2].advance > 0.0);
+    }
+}
+
+#[test]
+fn trailing_empty_paragraphs_can_be_dropped() {
+    let text = "Fin.\n\n";
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let kept = layout(text, &options);
+    assert_eq!(kept.lines.len(), 2);
+    assert_eq!(kept.lines[1].paragraph, 1);
+    assert!(kept.lines[1].glyphs.is_empty());
+
+    let dropped = layout(text, &options.clone().drop_trailing_empty_paragraphs(true));
+    assert_eq!(dropped.lines, kept.lines[..1]);
+    assert_eq!(dropped.glyphs, kept.glyphs);
+    // Every one of them, but not the empty paragraphs before the last text.
+    let text = "\n\nFin.\n\n\n";
+    let dropped = layout(text, &options.clone().drop_trailing_empty_paragraphs(true));
+    assert_eq!(dropped.lines.len(), 3);
+    assert_eq!(layout(text, &options).lines.len(), 5);
+}
+
+#[test]
+fn max_line_width_is_the_longest_rendered_line() {
+    let text = "A short one\nThen a much longer paragraph that wraps\nEnd";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 200, 400);
+    let lines = layout(text, &options).lines;
+    assert!(lines.len() > 3);
+    let longest = lines.iter().map(|line| line.width).fold(0.0, f64::max);
+    assert_eq!(max_line_width(text, "roboto", 200, 16), longest);
+    assert!(longest <= 200.0 - options.insets.left - options.insets.right);
+
+    // Unconstrained it's the longest paragraph on a single line.
+    let paragraph = "Then a much longer paragraph that wraps";
+    let unwrapped = layout(paragraph, &options.bounds(0, 0, 2000, 400)).lines;
+    assert_eq!(unwrapped.len(), 1);
+    assert_eq!(max_line_width(text, "roboto", 0, 16), unwrapped[0].width);
+}
+
+#[test]
+fn paragraph_ranges_end_with_their_separator() {
+    // Shaped like the French demo input.
+    let text = "Nul ne sera soumis.\n\nToute personne a droit.\nFin.\n\n";
+    let ranges = paragraph_ranges(text);
+    let paragraphs = ranges
+        .iter()
+        .map(|(start, end)| &text[*start..*end])
+        .collect::<Vec<_>>();
+    assert_eq!(
+        paragraphs,
+        [
+            "Nul ne sera soumis.\n",
+            "\n",
+            "Toute personne a droit.\n",
+            "Fin.\n",
+            "\n"
+        ]
+    );
+    // They're the paragraphs of the layout.
+    let lines = layout(text, &LayoutOptions::default()).lines;
+    assert_eq!(lines.last().unwrap().paragraph + 1, ranges.len());
+    for line in lines.iter() {
+        let (start, end) = ranges[line.paragraph];
+        assert!(start <= line.source.start && line.source.end <= end);
+    }
+
+    assert_eq!(paragraph_ranges("a\u{2029}b"), [(0, 4), (4, 5)]);
+    assert_eq!(paragraph_ranges(""), []);
+}
+
+#[test]
+fn visible_indices_come_from_the_lines_in_the_clip() {
+    let text = "One line\nTwo lines\nThree lines\nFour lines\nFive lines";
+    let options = LayoutOptions::new().bounds(0, 0, 300, 400);
+    let result = layout(text, &options);
+    let lines = &result.lines;
+    assert_eq!(lines.len(), 5);
+
+    // A window from just above the second baseline to just below the fourth.
+    let clip = Rect {
+        x: 0.0,
+        y: lines[1].y - 1.0,
+        w: 300.0,
+        h: lines[3].y - lines[1].y + 2.0,
+    };
+    let first = result.first_visible_index(&clip).unwrap();
+    let last = result.last_visible_index(&clip).unwrap();
+    assert_eq!(first, text.find("Two").unwrap());
+    assert_eq!(last, text.find("\nFive").unwrap());
+    assert_eq!(text[first..last].lines().count(), 3);
+
+    // Nothing is visible below the text.
+    let below = Rect {
+        y: lines[4].y + 1.0,
+        ..clip
+    };
+    assert_eq!(result.first_visible_index(&below), None);
+    assert_eq!(result.last_visible_index(&below), None);
+}
+
+#[test]
+fn paragraph_max_width_wraps_one_paragraph_earlier() {
+    let text = "Some words to wrap around\nSome words to wrap around";
+    let options = LayoutOptions::new()
+        .bounds(0, 0, 400, 400)
+        .horizontal_alignment(HorizontalAlignment::Center)
+        .paragraphs_max_width(vec![None, Some(120.0)]);
+    let result = layout(text, &options);
+    let lines = |paragraph| {
+        let lines = result
+            .lines
+            .iter()
+            .filter(move |line| line.paragraph == paragraph);
+        lines.collect::<Vec<_>>()
+    };
+    assert_eq!(lines(0).len(), 1);
+    assert!(lines(1).len() > 1);
+    assert!(lines(1).iter().all(|line| line.width <= 120.0));
+
+    // The narrower paragraph is still centered across the whole box, which shows on its last
+    // line as it has no whitespace hanging at its end.
+    let center = |line: &LayoutLine| line.x + line.width / 2.0;
+    let last = lines(1).pop().unwrap();
+    assert!((center(last) - center(lines(0)[0])).abs() < 1.0);
+}
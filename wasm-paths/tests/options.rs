@@ -0,0 +1,50 @@
+use wasm_paths::{layout, HorizontalAlignment, LayoutOptions, VerticalAlignment, WritingMode};
+
+#[test]
+fn builder_defaults_match_previous_behavior() {
+    let options = LayoutOptions::new();
+    assert_eq!(options, LayoutOptions::default());
+    assert_eq!(
+        (options.x, options.y, options.w, options.h),
+        (0, 0, 600, 400)
+    );
+    assert_eq!(options.size, 16);
+    assert_eq!(options.fallback_font, "pt");
+    assert!(options.paragraphs_fonts.is_empty());
+    assert_eq!(options.horizontal_alignment, HorizontalAlignment::Normal);
+    assert_eq!(options.vertical_alignment, VerticalAlignment::Normal);
+    assert_eq!(options.writing_mode, WritingMode::HorizontalTb);
+    assert_eq!(options.language, None);
+    assert_eq!(options.letter_spacing, 0.0);
+    assert!(!options.pixel_snap);
+    assert_eq!(options.clip, None);
+
+    let built = LayoutOptions::new()
+        .bounds(10, 20, 300, 200)
+        .size(24)
+        .fallback_font("roboto")
+        .horizontal_alignment(HorizontalAlignment::Center)
+        .letter_spacing(1.5);
+    let literal = LayoutOptions {
+        x: 10,
+        y: 20,
+        w: 300,
+        h: 200,
+        size: 24,
+        fallback_font: "roboto".into(),
+        horizontal_alignment: HorizontalAlignment::Center,
+        letter_spacing: 1.5,
+        ..Default::default()
+    };
+    assert_eq!(built, literal);
+
+    let text = "Same options, same layout.";
+    let paths = |options: &LayoutOptions| {
+        layout(text, options)
+            .glyphs
+            .into_iter()
+            .map(|glyph| glyph.path)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(paths(&built), paths(&literal));
+}
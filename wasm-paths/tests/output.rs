@@ -0,0 +1,879 @@
+use glam::DVec2;
+use wasm_paths::{
+    layout, suggest_text_color, DecorationLine, EmptyOutlines, FadeAxis, FillRule, Highlight,
+    LayoutGlyph, LayoutOptions, PaintLayer, Rect, Skeleton, SvgOptions,
+};
+
+mod common;
+
+#[test]
+fn highlight_covers_marked_word() {
+    let text = "Highlight this word please";
+    let start = text.find("this").unwrap();
+    let end = start + "this".len();
+    let options = LayoutOptions {
+        highlights: vec![Highlight {
+            start,
+            end,
+            color: "yellow".into(),
+        }],
+        ..Default::default()
+    };
+    let result = layout(text, &options);
+
+    assert_eq!(result.highlights.len(), 1);
+    let highlight = &result.highlights[0];
+    assert_eq!(highlight.color, "yellow");
+
+    let marked = result
+        .glyphs
+        .iter()
+        .filter(|glyph| (start..end).contains(&glyph.cluster))
+        .collect::<Vec<_>>();
+    let left = marked.iter().map(|glyph| glyph.x).fold(f64::MAX, f64::min);
+    let right = marked
+        .iter()
+        .map(|glyph| glyph.x + glyph.advance)
+        .fold(f64::MIN, f64::max);
+    assert!((highlight.rect.x - left).abs() < 1e-9);
+    assert!((highlight.rect.x + highlight.rect.w - right).abs() < 1e-9);
+
+    let line = &result.lines[0];
+    assert!(highlight.rect.y < line.y && line.y < highlight.rect.y + highlight.rect.h);
+}
+
+#[test]
+fn clip_culls_glyphs_outside_it() {
+    let text = (0..20)
+        .map(|i| format!("line{i}"))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let options = LayoutOptions {
+        h: 2000,
+        ..Default::default()
+    };
+    let full = layout(&text, &options);
+    assert_eq!(full.lines.len(), 20);
+
+    // Nothing in the text descends, so a clip starting just under a baseline leaves that line
+    // out and keeps the next one.
+    let top = full.lines[7].y + 1.0;
+    let bottom = full.lines[11].y + 1.0;
+    let clip = Rect {
+        x: 0.0,
+        y: top,
+        w: options.w as f64,
+        h: bottom - top,
+    };
+    let clipped = layout(
+        &text,
+        &LayoutOptions {
+            clip: Some(clip),
+            ..options.clone()
+        },
+    );
+
+    let ids = |glyphs: &[LayoutGlyph]| {
+        glyphs
+            .iter()
+            .map(|glyph| (glyph.cluster, glyph.glyph_id))
+            .collect::<Vec<_>>()
+    };
+    let visible = full.lines[8].glyphs.start..full.lines[11].glyphs.end;
+    assert_eq!(ids(&clipped.glyphs), ids(&full.glyphs[visible]));
+    assert_eq!(clipped.lines.len(), 20);
+    assert!(clipped.lines[0].glyphs.is_empty());
+}
+
+#[test]
+fn device_pixel_ratio_scales_paths_only() {
+    let numbers = |path: &str| {
+        path.split(|c: char| c.is_ascii_alphabetic() || c == ',' || c == ' ')
+            .filter(|number| !number.is_empty())
+            .map(|number| number.parse::<f64>().unwrap())
+            .collect::<Vec<_>>()
+    };
+    let text = "Sharp text";
+    let options = LayoutOptions {
+        x: 30,
+        y: 40,
+        ..Default::default()
+    };
+    let logical = layout(text, &options);
+    let scaled = layout(
+        text,
+        &LayoutOptions {
+            device_pixel_ratio: 2.0,
+            ..options
+        },
+    );
+
+    assert_eq!(logical.glyphs.len(), scaled.glyphs.len());
+    for (logical, scaled) in logical.glyphs.iter().zip(scaled.glyphs.iter()) {
+        assert_eq!(logical.advance, scaled.advance);
+        assert_eq!((logical.x, logical.y), (scaled.x, scaled.y));
+
+        let expected = numbers(&logical.path);
+        let actual = numbers(&scaled.path);
+        assert_eq!(expected.len(), actual.len());
+        for (expected, actual) in expected.iter().zip(actual.iter()) {
+            assert!((expected * 2.0 - actual).abs() < 1e-9);
+        }
+    }
+}
+
+#[test]
+fn svg_groups_glyphs_by_paragraph() {
+    let text = "First paragraph\nsecond\nand the third one";
+    let result = layout(text, &LayoutOptions::default());
+    let svg = result.to_svg(&SvgOptions {
+        group_paragraphs: true,
+        ..Default::default()
+    });
+
+    let groups = svg
+        .split("<g data-paragraph-index=\"")
+        .skip(1)
+        .collect::<Vec<_>>();
+    assert_eq!(groups.len(), 3);
+    for (i, group) in groups.iter().enumerate() {
+        assert!(group.starts_with(&format!("{i}\"")));
+        let group = &group[..group.find("</g>").unwrap()];
+        let drawn = result
+            .lines
+            .iter()
+            .filter(|line| line.paragraph == i)
+            .flat_map(|line| result.glyphs[line.glyphs.clone()].iter())
+            .filter(|glyph| !glyph.path.is_empty())
+            .count();
+        assert_eq!(group.matches("<path").count(), drawn);
+    }
+
+    let flat = result.to_svg(&SvgOptions::default());
+    assert!(!flat.contains("data-paragraph-index"));
+    assert_eq!(flat.matches("<path").count(), svg.matches("<path").count());
+}
+
+#[test]
+fn svg_defs_share_repeated_outlines() {
+    let result = layout("aaa b", &LayoutOptions::default());
+    let svg = result.to_svg(&SvgOptions {
+        use_defs: true,
+        ..Default::default()
+    });
+
+    assert_eq!(svg.matches("<path id=").count(), 2);
+    assert_eq!(svg.matches("<use href=\"#glyph-0\"").count(), 3);
+    assert_eq!(svg.matches("<use href=\"#glyph-1\"").count(), 1);
+    assert!(!svg.contains("<path d="));
+
+    // The transform maps the outline onto the path drawn without defs.
+    let first_point = |path: &str| {
+        let mut numbers = path[1..]
+            .split([' ', 'L', 'Q', 'C'])
+            .map(|n| n.parse::<f64>());
+        DVec2::new(
+            numbers.next().unwrap().unwrap(),
+            numbers.next().unwrap().unwrap(),
+        )
+    };
+    let glyph = &result.glyphs[0];
+    let mapped = glyph
+        .transform
+        .transform_point2(first_point(&glyph.outline));
+    assert!((mapped - first_point(&glyph.path)).length() < 1e-9);
+}
+
+#[test]
+fn svg_defs_draw_glyphs_where_their_paths_are() {
+    let options = LayoutOptions::new()
+        .device_pixel_ratio(2.0)
+        .path_precision(2)
+        .flatten_tolerance(0.1);
+    let result = layout("a bab", &options);
+    let svg_options = SvgOptions {
+        device_pixel_ratio: 2.0,
+        path_precision: Some(2),
+        flatten_tolerance: Some(0.1),
+        ..Default::default()
+    };
+    let plain = result.to_svg(&svg_options);
+    let shared = result.to_svg(&SvgOptions {
+        use_defs: true,
+        ..svg_options
+    });
+
+    let attributes = |svg: &str, prefix: &str| {
+        svg.match_indices(prefix)
+            .map(|(i, _)| {
+                let value = &svg[i + prefix.len()..];
+                value[..value.find('"').unwrap()].to_string()
+            })
+            .collect::<Vec<_>>()
+    };
+    let numbers = |text: &str| {
+        text.split(|c: char| !(c == '-' || c == '.' || c.is_ascii_digit()))
+            .filter(|number| !number.is_empty())
+            .map(|number| number.parse::<f64>().unwrap())
+            .collect::<Vec<_>>()
+    };
+    let paths = attributes(&plain, "<path d=\"");
+    let defs = attributes(&shared, "\" d=\"");
+    let uses = attributes(&shared, "<use href=\"#glyph-");
+    let transforms = attributes(&shared, "transform=\"matrix(");
+    assert_eq!(paths.len(), 4);
+    assert_eq!(uses.len(), paths.len());
+
+    for ((path, def), transform) in paths.iter().zip(uses.iter()).zip(transforms.iter()) {
+        let [a, b, c, d, e, f] = numbers(transform)[..] else {
+            panic!("{transform} isn't a matrix");
+        };
+        let outline = numbers(&defs[def.parse::<usize>().unwrap()]);
+        let path = numbers(path);
+        assert_eq!(outline.len(), path.len());
+        for (point, expected) in outline.chunks(2).zip(path.chunks(2)) {
+            let (x, y) = (point[0], point[1]);
+            assert!((a * x + c * y + e - expected[0]).abs() < 0.02);
+            assert!((b * x + d * y + f - expected[1]).abs() < 0.02);
+        }
+    }
+}
+
+#[test]
+fn token_colors_follow_clusters() {
+    let text = "let value = 42;";
+    let keyword = 0..text.find(' ').unwrap();
+    let name = keyword.end..text.find('=').unwrap();
+    let options = LayoutOptions::new()
+        .color(keyword.start, keyword.end, "purple")
+        .color(name.start, name.end, "teal");
+
+    let result = layout(text, &options);
+    for glyph in result.glyphs.iter() {
+        let expected = if keyword.contains(&glyph.cluster) {
+            Some("purple")
+        } else if name.contains(&glyph.cluster) {
+            Some("teal")
+        } else {
+            None
+        };
+        assert_eq!(glyph.color.as_deref(), expected);
+    }
+
+    let svg = result.to_svg(&SvgOptions::default());
+    assert_eq!(svg.matches("fill=\"purple\"").count(), 3);
+    let drawn_name = result
+        .glyphs
+        .iter()
+        .filter(|glyph| name.contains(&glyph.cluster) && !glyph.path.is_empty())
+        .count();
+    assert_eq!(svg.matches("fill=\"teal\"").count(), drawn_name);
+}
+
+#[test]
+fn svg_escapes_colors_in_attributes() {
+    let evil = "red\"><script>alert(1)</script><x a=\"&";
+    let options = LayoutOptions::new()
+        .color(0, 1, evil)
+        .highlight(0, 1, evil)
+        .decoration(0, 1, DecorationLine::Underline, evil);
+    let svg = layout("ab", &options).to_svg(&SvgOptions {
+        fill: evil.into(),
+        ..Default::default()
+    });
+
+    assert!(!svg.contains("<script>"));
+    let escaped = "fill=\"red&quot;>&lt;script>alert(1)&lt;/script>&lt;x a=&quot;&amp;\"";
+    assert_eq!(svg.matches(escaped).count(), 4);
+}
+
+#[test]
+fn fade_ramps_opacity_with_position() {
+    let (transparent, opaque) = (0.0, 120.0);
+    let options = LayoutOptions::new().fade(FadeAxis::X, transparent, opaque);
+    let center = |glyph: &LayoutGlyph| glyph.bounds.x + glyph.bounds.w / 2.0;
+
+    let result = layout("Fading in from the left edge of the box", &options);
+    let (faded, opaque_glyphs): (Vec<&LayoutGlyph>, Vec<_>) = result
+        .glyphs
+        .iter()
+        .filter(|glyph| !glyph.path.is_empty())
+        .partition(|glyph| center(glyph) < opaque);
+    assert!(faded.len() > 3);
+    for glyph in faded.iter() {
+        let expected = (center(glyph) - transparent) / (opaque - transparent);
+        assert!(glyph.opacity > 0.0 && glyph.opacity < 1.0);
+        assert!((glyph.opacity - expected).abs() < 1e-9);
+    }
+    assert!(faded
+        .windows(2)
+        .all(|pair| pair[0].opacity < pair[1].opacity));
+    assert!(opaque_glyphs.iter().all(|glyph| glyph.opacity == 1.0));
+
+    let svg = result.to_svg(&SvgOptions::default());
+    assert_eq!(svg.matches("fill-opacity=").count(), faded.len());
+}
+
+#[test]
+fn monospaced_cells_form_a_grid() {
+    common::register_mono();
+    const SPACING: f64 = 1.25;
+    let text = "fn main() { wrap(\"a grid of cells\"); }";
+    let options = LayoutOptions {
+        fallback_font: "mono".into(),
+        w: 200,
+        ..Default::default()
+    };
+    let spaced = LayoutOptions {
+        letter_spacing: SPACING,
+        ..options.clone()
+    };
+
+    for options in [options, spaced] {
+        let result = layout(text, &options);
+        assert!(result.lines.len() > 1);
+        let cells = result.cells();
+        assert_eq!(cells.len(), result.glyphs.len());
+        let width = cells[0].w;
+        for line in result.lines.iter() {
+            let cells = &cells[line.glyphs.clone()];
+            let (last, cells_before) = cells.split_last().unwrap();
+            // Hanging whitespace keeps its cell, only the last one has no spacing after it.
+            assert!(cells_before
+                .iter()
+                .all(|cell| (cell.w - width).abs() < 1e-9));
+            assert!((last.w + options.letter_spacing - width).abs() < 1e-9);
+            assert!(cells
+                .windows(2)
+                .all(|pair| (pair[0].x + pair[0].w - pair[1].x).abs() < 1e-9));
+            assert!(cells.iter().all(|cell| cell.y == line.y - line.ascent));
+            assert!(cells
+                .iter()
+                .all(|cell| cell.h == line.ascent + line.descent));
+        }
+    }
+}
+
+#[test]
+fn skeleton_boxes_match_lines_and_words() {
+    let text = "Placeholder boxes stand in for text that is still loading, one per line or word.";
+    let options = LayoutOptions::new().bounds(0, 0, 240, 400);
+    let real = layout(text, &options);
+    assert!(real.skeleton.is_empty());
+
+    let lines = layout(text, &options.clone().skeleton(Skeleton::Lines));
+    assert!(lines.glyphs.iter().all(|glyph| glyph.path.is_empty()));
+    assert_eq!(lines.skeleton.len(), real.lines.len());
+    for (rect, line) in lines.skeleton.iter().zip(real.lines.iter()) {
+        assert_eq!((rect.x, rect.w), (line.x, line.width));
+        assert_eq!(
+            (rect.y, rect.h),
+            (line.y - line.ascent, line.ascent + line.descent)
+        );
+    }
+
+    let words = layout(text, &options.skeleton(Skeleton::Words)).skeleton;
+    assert_eq!(words.len(), text.split_whitespace().count());
+    let mut start = 0;
+    for (rect, word) in words.iter().zip(text.split_whitespace()) {
+        start += text[start..].find(word).unwrap();
+        assert_eq!(real.selection_rects(start, start + word.len()), [*rect]);
+        start += word.len();
+    }
+}
+
+#[test]
+fn highlights_are_painted_before_glyphs() {
+    let text = "marked and underlined and struck";
+    let (marked, underlined) = (0..6, 11..21);
+    let options = LayoutOptions::new()
+        .highlight(marked.start, marked.end, "yellow")
+        .decoration(
+            underlined.start,
+            underlined.end,
+            DecorationLine::Underline,
+            "blue",
+        )
+        .decoration(26, text.len(), DecorationLine::LineThrough, "red");
+
+    let result = layout(text, &options);
+    let layers = result.paint_layers();
+    let kinds = layers
+        .iter()
+        .map(|layer| match layer {
+            PaintLayer::Highlights(_) => "highlights",
+            PaintLayer::Decorations(rects) if rects[0].line == DecorationLine::Underline => {
+                "underlines"
+            }
+            PaintLayer::Decorations(_) => "line-throughs",
+            PaintLayer::Glyphs(_) => "glyphs",
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        ["highlights", "underlines", "glyphs", "line-throughs"]
+    );
+    let PaintLayer::Glyphs(glyphs) = &layers[2] else {
+        unreachable!()
+    };
+    assert_eq!(glyphs.len(), result.glyphs.len());
+
+    // The underline is under the baseline and as wide as what it decorates.
+    let line = &result.lines[0];
+    let underline = &result.decorations[0];
+    assert_eq!(
+        underline.rect.x,
+        result.selection_rects(underlined.start, underlined.end)[0].x
+    );
+    assert_eq!(
+        underline.rect.w,
+        result.selection_rects(underlined.start, underlined.end)[0].w
+    );
+    assert!(underline.rect.y > line.y && underline.rect.h > 0.0);
+    let line_through = &result.decorations[1];
+    assert!(line_through.rect.y + line_through.rect.h < line.y);
+
+    let svg = result.to_svg(&SvgOptions::default());
+    let position = |needle: &str| svg.find(needle).unwrap();
+    assert!(position("fill=\"yellow\"") < position("fill=\"blue\""));
+    assert!(position("fill=\"blue\"") < position("<path"));
+    assert!(svg.rfind("<path").unwrap() < position("fill=\"red\""));
+}
+
+#[test]
+fn character_positions_increase_along_ltr_text() {
+    // Roboto draws "ffi" and "fi" as ligatures, so some clusters have several characters.
+    let text = "official fine print";
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let result = layout(text, &options);
+    assert_eq!(result.glyphs.len(), text.chars().count() - 3);
+
+    let positions = result
+        .char_positions(text)
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .unwrap();
+    assert_eq!(positions.len(), text.chars().count());
+    assert_eq!(positions[0], result.glyphs[0].x);
+    assert!(positions.windows(2).all(|pair| pair[0] < pair[1]));
+
+    let ligature = &result.glyphs[1];
+    assert_eq!(ligature.cluster, 1);
+    for (i, x) in positions[1..4].iter().enumerate() {
+        assert!((x - ligature.x - i as f64 * ligature.advance / 3.0).abs() < 1e-9);
+    }
+    assert_eq!(positions[4], result.glyphs[2].x);
+
+    // The paragraph separator isn't drawn.
+    let two_lines = "first\nsecond";
+    let positions = layout(two_lines, &options).char_positions(two_lines);
+    assert!(positions
+        .iter()
+        .enumerate()
+        .all(|(i, x)| x.is_some() == (i != 5)));
+}
+
+#[test]
+fn diff_reports_edited_and_shifted_glyphs() {
+    let before = "The quick brown fox\nThe next paragraph stays put.";
+    let after = before.replacen("quick", "slow", 1);
+    let options = LayoutOptions::default();
+    let old = layout(before, &options);
+    let new = layout(&after, &options);
+
+    assert!(new.diff(&new).is_empty());
+    let diff = new.diff(&old);
+    let word = before.find("quick").unwrap();
+    let shifted = before.find(" brown").unwrap()..before.find('\n').unwrap();
+    assert_eq!(diff.removed.len(), "quick".len());
+    assert!(diff
+        .removed
+        .iter()
+        .all(|i| (word..shifted.start).contains(&old.glyphs[*i].cluster)));
+    assert_eq!(diff.added.len(), "slow".len());
+    assert!(diff.added.iter().all(|i| new.glyphs[*i].cluster >= word));
+    // Only the rest of the edited line moves, the next paragraph is where it was.
+    assert_eq!(diff.moved.len(), shifted.len());
+    for (i, j) in diff.moved.iter() {
+        assert!(shifted.contains(&old.glyphs[*i].cluster));
+        assert_eq!(old.glyphs[*i].y, new.glyphs[*j].y);
+    }
+
+    // A decomposed `é` is drawn with the same glyph as the precomposed one, the glyphs after it
+    // only have their clusters shifted by the extra byte.
+    let composed = "café au lait";
+    let decomposed = composed.replace('é', "e\u{301}");
+    let old = layout(composed, &options);
+    let new = layout(&decomposed, &options);
+    assert_eq!(old.glyphs.len(), new.glyphs.len());
+    assert!(new.diff(&old).is_empty());
+
+    // The same glyph at the same position, but smaller, is redrawn.
+    let original = layout(before, &options);
+    let period = before.len() - 1;
+    let smaller = layout(before, &options.clone().size_range(period, before.len(), 8));
+    let diff = smaller.diff(&original);
+    let last = original.glyphs.len() - 1;
+    assert!(diff.added.is_empty() && diff.removed.is_empty());
+    assert_eq!(diff.moved, vec![(last, last)]);
+}
+
+#[test]
+fn glyph_paths_carry_their_cluster() {
+    let text = "tap a glyph";
+    let result = layout(text, &LayoutOptions::default());
+    let svg = result.to_svg(&SvgOptions {
+        glyph_data: true,
+        ..Default::default()
+    });
+
+    let paths = svg.split("<path ").skip(1).collect::<Vec<_>>();
+    let drawn = result
+        .glyphs
+        .iter()
+        .filter(|glyph| !glyph.path.is_empty())
+        .collect::<Vec<_>>();
+    assert_eq!(paths.len(), drawn.len());
+    for (path, glyph) in paths.iter().zip(drawn) {
+        let expected = format!(
+            " data-cluster=\"{}\" data-glyph-id=\"{}\">",
+            glyph.cluster, glyph.glyph_id
+        );
+        assert!(path.contains(&expected));
+    }
+    assert!(!result
+        .to_svg(&SvgOptions::default())
+        .contains("data-cluster"));
+}
+
+#[test]
+fn repeated_dashes_collapse_into_one_run() {
+    let text = "a———————b";
+    let result = layout(text, &LayoutOptions::new().fallback_font("roboto"));
+    let runs = result.repeat_runs();
+    assert_eq!(runs.len(), 3);
+    assert_eq!(
+        (runs[0].glyph, runs[0].count, runs[0].step),
+        (0, 1, DVec2::ZERO)
+    );
+    assert_eq!((runs[1].glyph, runs[1].count), (1, 7));
+    assert_eq!((runs[2].glyph, runs[2].count), (8, 1));
+
+    let dashes = &result.glyphs[1..8];
+    assert!((runs[1].step - DVec2::new(dashes[0].advance, 0.0)).length() < 1e-9);
+    for (k, dash) in dashes.iter().enumerate() {
+        let tiled = DVec2::new(dashes[0].x, dashes[0].y) + runs[1].step * k as f64;
+        assert!((tiled - DVec2::new(dash.x, dash.y)).length() < 1e-9);
+    }
+}
+
+#[test]
+fn text_color_suggestions_contrast_with_the_background() {
+    for dark in ["#000", "#1e1e1e", "#003366", "rgb(128, 0, 0)", "#40404080"] {
+        assert_eq!(suggest_text_color(dark.into()), "white", "{dark}");
+    }
+    for light in [
+        "#fff",
+        "#ffffff",
+        "#ffeb3b",
+        "rgb(200, 200, 200)",
+        " #BFE4FF ",
+    ] {
+        assert_eq!(suggest_text_color(light.into()), "black", "{light}");
+    }
+    // Mid gray has more contrast with black than with white.
+    assert_eq!(suggest_text_color("#808080".into()), "black");
+    assert_eq!(suggest_text_color("not a color".into()), "black");
+}
+
+#[test]
+fn line_texts_add_up_to_the_paragraphs() {
+    let text = "Every line of this paragraph is read out on its own.\nA second paragraph here";
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 140, 400);
+    let layout = layout(text, &options);
+    let lines = layout.line_texts(text);
+    assert_eq!(lines.len(), layout.lines.len());
+    assert!(lines.len() > 3);
+    assert!(lines
+        .iter()
+        .all(|line| !line.is_empty() && line.trim() == *line));
+
+    // Only the whitespace the lines were broken at is missing.
+    for (paragraph, expected) in text.split('\n').enumerate() {
+        let paragraph_lines = layout
+            .lines
+            .iter()
+            .zip(lines.iter())
+            .filter(|(line, _)| line.paragraph == paragraph)
+            .map(|(_, text)| *text)
+            .collect::<Vec<_>>();
+        assert!(paragraph_lines.len() > 1);
+        assert_eq!(paragraph_lines.join(" "), expected);
+    }
+}
+
+#[test]
+fn svg_glyphs_get_the_fill_rule() {
+    let result = layout("O", &LayoutOptions::default());
+    let svg = result.to_svg(&SvgOptions::default());
+    assert!(svg.contains("<g fill=\"black\" fill-rule=\"nonzero\">"));
+
+    // Glyphs drawn from defs inherit it from the group too.
+    let result = layout("O", &LayoutOptions::new().fill_rule(FillRule::EvenOdd));
+    let svg = result.to_svg(&SvgOptions {
+        use_defs: true,
+        ..Default::default()
+    });
+    assert_eq!(svg.matches("fill-rule=").count(), 1);
+    let group = &svg[svg
+        .find("<g fill=\"black\" fill-rule=\"evenodd\">")
+        .unwrap()..];
+    assert!(group.contains("<use href=\"#glyph-0\""));
+}
+
+#[test]
+fn text_lines_carry_their_baselines() {
+    common::register_dejavu();
+    let text = "Lines of real text instead of paths\nשלום עולם וגם עוד מילים";
+    let options = LayoutOptions::new()
+        .fallback_font("dejavu")
+        .bounds(0, 0, 160, 400);
+    let result = layout(text, &options);
+    let lines = result.text_lines(text);
+    assert_eq!(lines.len(), result.lines.len());
+
+    for (paragraph, expected) in text.split('\n').enumerate() {
+        let paragraph_lines = result
+            .lines
+            .iter()
+            .zip(lines.iter())
+            .filter(|(line, _)| line.paragraph == paragraph)
+            .map(|(_, text_line)| text_line)
+            .collect::<Vec<_>>();
+        assert!(paragraph_lines.len() > 1);
+        let joined = paragraph_lines
+            .iter()
+            .map(|line| line.text)
+            .collect::<Vec<_>>();
+        assert_eq!(joined.join(" "), expected);
+        assert!(paragraph_lines
+            .iter()
+            .all(|line| line.rtl == (paragraph == 1)));
+    }
+    // The glyphs sit on the baselines, one line height apart.
+    for (line, text_line) in result.lines.iter().zip(lines.iter()) {
+        assert!(result.glyphs[line.glyphs.clone()]
+            .iter()
+            .all(|glyph| glyph.y == text_line.y));
+        assert_eq!((text_line.x, text_line.width), (line.x, line.width));
+    }
+    for pair in lines.windows(2) {
+        assert!((pair[1].y - pair[0].y - result.line_height).abs() < 1e-9);
+    }
+    // Right-to-left lines end on the right.
+    let right = 160.0 - options.insets.right;
+    let last = lines.last().unwrap();
+    assert!((last.x + last.width - right).abs() < 1e-9);
+}
+
+#[test]
+fn fixed_precision_svg_matches_the_golden_file() {
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .bounds(0, 0, 60, 40)
+        .path_precision(2);
+    let svg = layout("Hi.", &options).to_svg(&SvgOptions {
+        width: 60.0,
+        height: 40.0,
+        ..Default::default()
+    });
+    assert_eq!(svg, include_str!("golden/precision.svg"));
+
+    // Paths moved after layout are rounded as well.
+    let y_up = layout("Hi.", &options.clone().y_down(false).path_precision(0));
+    assert!(y_up.glyphs.iter().all(|glyph| !glyph.path.contains('.')));
+    assert!(y_up.glyphs.iter().all(|glyph| glyph.path.contains('-')));
+}
+
+#[test]
+fn skipped_empty_outlines_leave_no_empty_paths() {
+    let text = "ab cd\nef";
+    let options = LayoutOptions::new().bounds(0, 0, 400, 200);
+    let kept = layout(text, &options);
+    assert_eq!(
+        kept.glyphs
+            .iter()
+            .filter(|glyph| glyph.path.is_empty())
+            .count(),
+        1
+    );
+
+    let skipped = layout(text, &options.clone().empty_outlines(EmptyOutlines::Skip));
+    assert_eq!(skipped.glyphs.len(), kept.glyphs.len() - 1);
+    assert!(skipped.glyphs.iter().all(|glyph| !glyph.path.is_empty()));
+    assert!(skipped
+        .glyphs
+        .iter()
+        .all(|glyph| glyph.cluster != text.find(' ').unwrap()));
+    // The lines still end where they did.
+    assert_eq!(skipped.lines.len(), kept.lines.len());
+    for (skipped, kept) in skipped.lines.iter().zip(kept.lines.iter()) {
+        assert_eq!((skipped.x, skipped.width), (kept.x, kept.width));
+    }
+
+    // Whitespace never gets a placeholder box.
+    let boxed = layout(text, &options.empty_outlines(EmptyOutlines::Placeholder));
+    assert_eq!(
+        boxed
+            .glyphs
+            .iter()
+            .filter(|glyph| glyph.path.is_empty())
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn word_boxes_tile_their_lines() {
+    let text = "Double click any word of this sentence to select it";
+    let options = LayoutOptions::new().bounds(0, 0, 180, 200);
+    let result = layout(text, &options);
+    assert!(result.lines.len() > 1);
+
+    let words = result.word_boxes();
+    let expected = text.split_inclusive(' ').collect::<Vec<_>>();
+    let sources = words
+        .iter()
+        .map(|word| &text[word.start..word.end])
+        .collect::<Vec<_>>();
+    assert_eq!(sources, expected);
+
+    for line in result.lines.iter() {
+        let on_line = words
+            .iter()
+            .filter(|word| line.source.contains(&word.start))
+            .collect::<Vec<_>>();
+        assert_eq!(on_line.first().unwrap().start, line.source.start);
+        assert_eq!(on_line.last().unwrap().end, line.source.end);
+        assert!((on_line[0].rect.x - line.x).abs() < 1e-9);
+        for pair in on_line.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert!((pair[0].rect.x + pair[0].rect.w - pair[1].rect.x).abs() < 1e-9);
+            assert_eq!(pair[0].rect.y, pair[1].rect.y);
+        }
+    }
+}
+
+#[test]
+fn flattened_paths_stay_within_the_tolerance() {
+    // Closed polylines of the path, one for every contour.
+    fn polylines(path: &str) -> Vec<Vec<DVec2>> {
+        let mut result: Vec<Vec<DVec2>> = vec![];
+        for command in path.split_inclusive(['M', 'L', 'Z']).map(str::trim) {
+            let point = command
+                .trim_end_matches(['M', 'L', 'Z'])
+                .split_whitespace()
+                .map(|n| n.parse::<f64>().unwrap())
+                .collect::<Vec<_>>();
+            if let [x, y] = point[..] {
+                result.last_mut().unwrap().push(DVec2::new(x, y));
+            }
+            match command.chars().last() {
+                Some('M') => result.push(vec![]),
+                Some('Z') => {
+                    let contour = result.last_mut().unwrap();
+                    contour.push(contour[0]);
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+    let distance_to_segment = |p: DVec2, a: DVec2, b: DVec2| {
+        let t = ((p - a).dot(b - a) / (b - a).length_squared()).clamp(0.0, 1.0);
+        p.distance(a.lerp(b, t))
+    };
+
+    let options = LayoutOptions::new().size(64);
+    let curved = layout("O", &options).glyphs[0].path.clone();
+    assert!(curved.contains('Q') || curved.contains('C'));
+    let coarse = layout("O", &options.clone().flatten_tolerance(0.25)).glyphs[0]
+        .path
+        .clone();
+    assert!(coarse
+        .chars()
+        .filter(|c| c.is_ascii_alphabetic())
+        .all(|c| "MLZ".contains(c)));
+
+    // Points of a much finer flattening are as good as on the curve.
+    let fine = layout("O", &options.clone().flatten_tolerance(0.001)).glyphs[0]
+        .path
+        .clone();
+    let (coarse, fine) = (polylines(&coarse), polylines(&fine));
+    assert_eq!(coarse.len(), 2);
+    assert_eq!(fine.len(), coarse.len());
+    for (coarse, fine) in coarse.iter().zip(fine.iter()) {
+        assert!(fine.len() > coarse.len());
+        for point in fine {
+            let distance = coarse
+                .windows(2)
+                .map(|segment| distance_to_segment(*point, segment[0], segment[1]))
+                .fold(f64::MAX, f64::min);
+            assert!(distance <= 0.25 + 1e-6, "{point} is {distance} away");
+        }
+    }
+
+    // Tolerances too fine to draw are as fine as it gets.
+    let finest = layout("O", &options.clone().flatten_tolerance(0.0)).glyphs[0]
+        .path
+        .clone();
+    assert_eq!(polylines(&finest), fine);
+    let negative = layout("O", &options.flatten_tolerance(-1.0)).glyphs[0]
+        .path
+        .clone();
+    assert_eq!(polylines(&negative), fine);
+}
+
+#[test]
+fn local_glyphs_start_at_the_origin() {
+    // Every point of a path, the numbers come in x y pairs.
+    fn points(path: &str) -> Vec<DVec2> {
+        let numbers = path
+            .split(|c: char| c.is_ascii_alphabetic() || c == ',' || c.is_whitespace())
+            .filter(|n| !n.is_empty())
+            .map(|n| n.parse::<f64>().unwrap())
+            .collect::<Vec<_>>();
+        numbers
+            .chunks(2)
+            .map(|xy| DVec2::new(xy[0], xy[1]))
+            .collect()
+    }
+
+    let options = LayoutOptions::new()
+        .bounds(10, 20, 300, 100)
+        .device_pixel_ratio(2.0);
+    let result = layout("Atlas of glyphs", &options);
+    let local = result.local_glyphs(2.0);
+    // The spaces have nothing to draw.
+    assert_eq!(local.len(), result.glyphs.len() - 2);
+
+    for glyph in local.iter() {
+        let laid_out = &result.glyphs[glyph.glyph];
+        let local_points = points(&glyph.local_path);
+        let min = local_points
+            .iter()
+            .fold(DVec2::MAX, |min, point| min.min(*point));
+        assert!(min.abs().max_element() < 1e-9);
+
+        let place = DVec2::new(glyph.place_x, glyph.place_y);
+        for (local, laid_out) in local_points.iter().zip(points(&laid_out.path)) {
+            assert!((*local + place - laid_out).abs().max_element() < 1e-9);
+        }
+        let pen = DVec2::new(laid_out.x, laid_out.y) * 2.0;
+        assert!((glyph.atlas_origin + place - pen).abs().max_element() < 1e-9);
+        // The corner is the top left one of the bounds, in device pixels.
+        assert!((place - DVec2::new(laid_out.bounds.x, laid_out.bounds.y) * 2.0).length() < 1e-9);
+    }
+}
@@ -0,0 +1,659 @@
+use glam::{DAffine2, DVec2};
+use wasm_paths::{
+    layout, layout_with_transform, HorizontalAlignment, LastLineAlignment, LayoutGlyph,
+    LayoutOptions, Positioning, Ruby, SizedRange, TabAlignment, TextOrientation, VerticalAlignment,
+    WritingMode,
+};
+
+mod common;
+
+const PAD: f64 = 12.0;
+
+fn left_edge(glyphs: &[LayoutGlyph]) -> f64 {
+    glyphs.iter().map(|glyph| glyph.x).fold(f64::MAX, f64::min)
+}
+
+fn right_edge(glyphs: &[LayoutGlyph]) -> f64 {
+    glyphs
+        .iter()
+        .map(|glyph| glyph.x + glyph.advance)
+        .fold(f64::MIN, f64::max)
+}
+
+#[test]
+fn letter_spacing_is_not_trailing() {
+    const SPACING: f64 = 3.0;
+    let ltr = LayoutOptions {
+        fallback_font: "roboto".into(),
+        horizontal_alignment: HorizontalAlignment::Reverse,
+        ..Default::default()
+    };
+    let tracked_ltr = LayoutOptions {
+        letter_spacing: SPACING,
+        ..ltr.clone()
+    };
+
+    // Two fragments, seven clusters and so six gaps.
+    let text = "abc def";
+    let plain = layout(text, &ltr).glyphs;
+    let tracked = layout(text, &tracked_ltr).glyphs;
+    let width = |glyphs: &[LayoutGlyph]| right_edge(glyphs) - left_edge(glyphs);
+    assert!((width(&tracked) - width(&plain) - 6.0 * SPACING).abs() < 1e-9);
+    // Right aligned text stays flush with the margin.
+    let right_margin = (ltr.x + ltr.w) as f64 - PAD;
+    assert!((right_edge(&tracked) - right_margin).abs() < 1e-9);
+
+    let rtl = LayoutOptions {
+        fallback_font: "noto".into(),
+        ..Default::default()
+    };
+    let tracked_rtl = LayoutOptions {
+        letter_spacing: SPACING,
+        ..rtl.clone()
+    };
+    let text = "שלום עולם";
+    let plain = layout(text, &rtl).glyphs;
+    let tracked = layout(text, &tracked_rtl).glyphs;
+    // The line start is on the right, nothing is inserted before it.
+    assert!((right_edge(&tracked) - right_margin).abs() < 1e-9);
+    assert!((width(&tracked) - width(&plain) - 8.0 * SPACING).abs() < 1e-9);
+}
+
+#[test]
+fn vertical_mixed_orientation_rotates_latin() {
+    let text = "한글abc";
+    let latin_start = text.find('a').unwrap();
+    let options = LayoutOptions {
+        fallback_font: "seoul".into(),
+        writing_mode: WritingMode::VerticalRl,
+        text_orientation: TextOrientation::Mixed,
+        ..Default::default()
+    };
+    let is_rotated = |glyph: &LayoutGlyph| glyph.transform.matrix2.x_axis.y.abs() > 1e-9;
+
+    let glyphs = layout(text, &options).glyphs;
+    assert_eq!(glyphs.len(), 5);
+    for glyph in glyphs.iter() {
+        assert_eq!(is_rotated(glyph), glyph.cluster >= latin_start);
+    }
+    // Everything is in a single column, stacked from the top.
+    assert!(glyphs.iter().all(|glyph| glyph.x == glyphs[0].x));
+    assert!(glyphs.windows(2).all(|pair| pair[0].y < pair[1].y));
+
+    let upright = LayoutOptions {
+        text_orientation: TextOrientation::Upright,
+        ..options
+    };
+    assert!(!layout(text, &upright).glyphs.iter().any(is_rotated));
+}
+
+#[test]
+fn pixel_snap_rounds_baselines() {
+    let text = "Baselines of small text land on whole pixels once they are snapped.";
+    let options = LayoutOptions {
+        y: 3,
+        w: 200,
+        size: 17,
+        ..Default::default()
+    };
+    let snapped = LayoutOptions {
+        pixel_snap: true,
+        ..options.clone()
+    };
+
+    let glyphs = layout(text, &options).glyphs;
+    assert!(glyphs.iter().any(|glyph| glyph.y.fract() != 0.0));
+
+    let result = layout(text, &snapped);
+    assert!(result.lines.len() > 1);
+    assert!(result.lines.iter().all(|line| line.y.fract() == 0.0));
+    assert!(result.glyphs.iter().all(|glyph| glyph.y.fract() == 0.0));
+}
+
+#[test]
+fn ruby_is_centered_above_its_base() {
+    let text = "日本語";
+    let base_end = text.find('語').unwrap();
+    let options = LayoutOptions {
+        fallback_font: "seoul".into(),
+        size: 32,
+        ..Default::default()
+    };
+    let annotated = LayoutOptions {
+        ruby: vec![Ruby {
+            start: 0,
+            end: base_end,
+            text: "にほん".into(),
+        }],
+        ..options.clone()
+    };
+
+    let plain = layout(text, &options);
+    let result = layout(text, &annotated);
+    assert_eq!(result.ruby_glyphs.len(), 3);
+    assert!(result.line_height > plain.line_height);
+
+    let base = result
+        .glyphs
+        .iter()
+        .filter(|glyph| glyph.cluster < base_end)
+        .collect::<Vec<_>>();
+    let base_center = (base[0].x + base[1].x + base[1].advance) / 2.0;
+    let ruby = &result.ruby_glyphs;
+    let ruby_center = (ruby[0].x + ruby[2].x + ruby[2].advance) / 2.0;
+    assert!((ruby_center - base_center).abs() < 1e-9);
+
+    let line = &result.lines[0];
+    for glyph in ruby.iter() {
+        assert!(glyph.advance < base[0].advance);
+        assert!(glyph.bounds.y + glyph.bounds.h <= line.y - line.ascent);
+        assert!(glyph.bounds.y >= options.y as f64 + PAD);
+    }
+}
+
+#[test]
+fn tall_runs_push_their_line_down() {
+    let text = "first line\nsmall BIG";
+    let big = text.find("BIG").unwrap();
+    let options = LayoutOptions {
+        size_ranges: vec![SizedRange {
+            start: big,
+            end: text.len(),
+            size: 40,
+        }],
+        ..Default::default()
+    };
+
+    let plain = layout(text, &LayoutOptions::default());
+    let result = layout(text, &options);
+    assert_eq!(result.lines.len(), 2);
+    let (first, second) = (&result.lines[0], &result.lines[1]);
+    assert_eq!(first.y, plain.lines[0].y);
+    assert!(second.ascent > plain.lines[1].ascent);
+    assert!(second.y - first.y > plain.lines[1].y - plain.lines[0].y);
+
+    let big_glyphs = result.glyphs[second.glyphs.clone()]
+        .iter()
+        .filter(|glyph| glyph.cluster >= big)
+        .collect::<Vec<_>>();
+    let small = &result.glyphs[second.glyphs.start];
+    assert!(big_glyphs[0].advance > small.advance * 2.0);
+    for glyph in big_glyphs.iter() {
+        // Same baseline as the small run and clear of the line above.
+        assert_eq!(glyph.y, small.y);
+        assert!(glyph.bounds.y >= first.y + first.descent);
+    }
+}
+
+#[test]
+fn size_ranges_inside_a_character_size_whole_characters() {
+    // 2 and 6 are inside `é` and `ô`, which take the size of the byte they start at.
+    let text = "héllô";
+    let options = LayoutOptions::new().size_range(2, 6, 40);
+    let plain = layout(text, &LayoutOptions::default());
+    let result = layout(text, &options);
+
+    let bigger = result
+        .glyphs
+        .iter()
+        .zip(plain.glyphs.iter())
+        .map(|(glyph, plain)| (glyph.cluster, glyph.advance > plain.advance * 2.0))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        bigger,
+        [(0, false), (1, false), (3, true), (4, true), (5, true)]
+    );
+}
+
+#[test]
+fn right_aligned_lines_are_flush_with_the_margin() {
+    let text = "Every wrapped line of a right aligned paragraph ends at the margin.";
+    let options = LayoutOptions {
+        w: 180,
+        horizontal_alignment: HorizontalAlignment::Reverse,
+        ..Default::default()
+    };
+    let right_margin = (options.x + options.w) as f64 - PAD;
+
+    let result = layout(text, &options);
+    assert!(result.lines.len() > 2);
+    let mut left_edges = vec![];
+    for line in result.lines.iter() {
+        let visible = result.glyphs[line.glyphs.clone()]
+            .iter()
+            .filter(|glyph| !text[glyph.cluster..].starts_with(' '))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert!((right_edge(&visible) - right_margin).abs() < 1e-9);
+        left_edges.push(left_edge(&visible));
+    }
+    assert!(left_edges.windows(2).any(|pair| pair[0] != pair[1]));
+}
+
+#[test]
+fn justify_all_stretches_the_last_line() {
+    let text =
+        "Justified text fills every wrapped line. With justify-all the last line is no exception.";
+    let justified = LayoutOptions {
+        w: 200,
+        horizontal_alignment: HorizontalAlignment::Justify,
+        ..Default::default()
+    };
+    let justify_all = LayoutOptions {
+        last_line_alignment: LastLineAlignment::Justify,
+        ..justified.clone()
+    };
+    let (left_margin, right_margin) = (PAD, justified.w as f64 - PAD);
+    let visible = |result: &wasm_paths::Layout, line: usize| {
+        result.glyphs[result.lines[line].glyphs.clone()]
+            .iter()
+            .filter(|glyph| !text[glyph.cluster..].starts_with(' '))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let result = layout(text, &justified);
+    let last = result.lines.len() - 1;
+    assert!(last > 1);
+    for line in 0..last {
+        assert_eq!(left_edge(&visible(&result, line)), left_margin);
+        assert!((right_edge(&visible(&result, line)) - right_margin).abs() < 1e-9);
+    }
+    assert!(right_edge(&visible(&result, last)) < right_margin - 1.0);
+
+    let result = layout(text, &justify_all);
+    assert_eq!(left_edge(&visible(&result, last)), left_margin);
+    assert!((right_edge(&visible(&result, last)) - right_margin).abs() < 1e-9);
+}
+
+#[test]
+fn transform_hook_moves_each_glyph() {
+    const STEP: f64 = 2.5;
+    let text = "wavy text";
+    let options = LayoutOptions::default();
+    let wave = |i: usize, transform: DAffine2| {
+        DAffine2::from_translation(DVec2::new(0.0, i as f64 * STEP)) * transform
+    };
+
+    let plain = layout(text, &options).glyphs;
+    let moved = layout_with_transform(text, &options, wave).glyphs;
+    assert_eq!(moved.len(), plain.len());
+    for (i, (plain, moved)) in plain.iter().zip(moved.iter()).enumerate() {
+        let shift = i as f64 * STEP;
+        assert!((moved.x - plain.x).abs() < 1e-9);
+        assert!((moved.y - plain.y - shift).abs() < 1e-9);
+        assert!((moved.bounds.x - plain.bounds.x).abs() < 1e-9);
+        assert!((moved.bounds.y - plain.bounds.y - shift).abs() < 1e-9);
+        assert!((moved.bounds.h - plain.bounds.h).abs() < 1e-9);
+        assert_eq!(moved.transform, wave(i, plain.transform));
+    }
+    assert_ne!(moved[1].path, plain[1].path);
+}
+
+#[test]
+fn leading_trim_starts_at_the_ascent() {
+    let text = "Hug the top";
+    let options = LayoutOptions::default();
+    let trimmed = LayoutOptions {
+        leading_trim: true,
+        ..options.clone()
+    };
+    let cap_top = |result: &wasm_paths::Layout| result.glyphs[0].bounds.y - PAD;
+
+    let plain = layout(text, &options);
+    let result = layout(text, &trimmed);
+    let line = &result.lines[0];
+    assert!((line.y - line.ascent - PAD).abs() < 1e-9);
+    // Top aligned text starts at the ascent anyway.
+    assert!((cap_top(&plain) - cap_top(&result)).abs() < 1e-9);
+
+    // The block is shorter by as much as the first baseline moved up.
+    let two_lines = "Hug\nthe top";
+    let h = (PAD * 2.0 + line.ascent + result.line_height).ceil() as i32;
+    assert!(layout(two_lines, &options.clone().bounds(0, 0, 600, h)).overflow);
+    assert!(!layout(two_lines, &trimmed.bounds(0, 0, 600, h)).overflow);
+}
+
+#[test]
+fn insets_move_each_edge_on_their_own() {
+    let text = "Asymmetric margins keep the text further from one edge than from the other one.";
+    let (left, right) = (40.0, 5.0);
+    let options = LayoutOptions::new()
+        .bounds(0, 0, 300, 400)
+        .insets(30.0, right, 12.0, left);
+    let margins = |result: &wasm_paths::Layout| {
+        let visible = result
+            .glyphs
+            .iter()
+            .filter(|glyph| !text[glyph.cluster..].starts_with(' '))
+            .cloned()
+            .collect::<Vec<_>>();
+        (left_edge(&visible), right_edge(&visible))
+    };
+
+    let result = layout(text, &options);
+    assert!(result.lines.len() > 1);
+    assert_eq!(margins(&result).0, left);
+    assert!(margins(&result).1 <= 300.0 - right);
+    assert!(result
+        .lines
+        .iter()
+        .all(|line| line.width <= 300.0 - left - right + 1e-9));
+    assert!((result.lines[0].y - result.lines[0].ascent - 30.0).abs() < 1e-9);
+
+    let flush_right = options.horizontal_alignment(HorizontalAlignment::Reverse);
+    let result = layout(text, &flush_right);
+    assert!((margins(&result).1 - (300.0 - right)).abs() < 1e-9);
+    assert!(margins(&result).0 >= left);
+}
+
+#[test]
+fn rounded_advances_keep_glyphs_on_whole_pixels() {
+    let text = "Small text with rounded advances is spaced as evenly as hinted text is.";
+    let options = LayoutOptions::new().bounds(0, 0, 200, 400).size(11);
+
+    let glyphs = layout(text, &options).glyphs;
+    assert!(glyphs.iter().any(|glyph| glyph.x.fract() != 0.0));
+
+    let result = layout(text, &options.round_advances(true));
+    assert!(result.lines.len() > 1);
+    assert!(result.glyphs.iter().all(|glyph| glyph.x.fract() == 0.0));
+    assert!(result
+        .glyphs
+        .iter()
+        .all(|glyph| glyph.advance.fract() == 0.0));
+    // Lines are broken with the same advances they are drawn with.
+    for line in result.lines.iter() {
+        let width = result.glyphs[line.glyphs.clone()]
+            .iter()
+            .map(|glyph| glyph.advance)
+            .sum::<f64>();
+        assert_eq!(line.width, width);
+    }
+}
+
+#[test]
+fn positioning_quantizes_pen_positions() {
+    let text = "Glyphs land on whole pixels along, across or neither way at all.";
+    let options = LayoutOptions::new().bounds(0, 3, 200, 400).size(17);
+    let snapped = |positioning| {
+        let glyphs = layout(text, &options.clone().positioning(positioning)).glyphs;
+        (
+            glyphs.iter().all(|glyph| glyph.x.fract() == 0.0),
+            glyphs.iter().all(|glyph| glyph.y.fract() == 0.0),
+        )
+    };
+
+    assert_eq!(snapped(Positioning::Subpixel), (false, false));
+    assert_eq!(snapped(Positioning::SnapX), (true, false));
+    assert_eq!(snapped(Positioning::SnapXy), (true, true));
+
+    // The pen isn't rounded, so the line is as long as without snapping.
+    let plain = layout(text, &options);
+    let result = layout(text, &options.clone().positioning(Positioning::SnapXy));
+    assert_eq!(result.lines.len(), plain.lines.len());
+    for (glyph, plain) in result.glyphs.iter().zip(plain.glyphs.iter()) {
+        assert!((glyph.x - plain.x).abs() <= 0.5 && (glyph.y - plain.y).abs() <= 0.5);
+    }
+}
+
+#[test]
+fn justified_rtl_lines_are_flush_on_both_sides() {
+    let text =
+        "איש לא יהיה נתון למעצר, מעצר שרירותי או גירוש. לכל אדם הזכות לשוויון מלא למשפט הוגן.";
+    let options = LayoutOptions::new()
+        .fallback_font("noto")
+        .bounds(0, 0, 220, 400)
+        .horizontal_alignment(HorizontalAlignment::Justify);
+    let (left_margin, right_margin) = (PAD, 220.0 - PAD);
+    let visible = |result: &wasm_paths::Layout, line: usize| {
+        result.glyphs[result.lines[line].glyphs.clone()]
+            .iter()
+            .filter(|glyph| !text[glyph.cluster..].starts_with(' '))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let words = |glyphs: &[LayoutGlyph]| {
+        let mut glyphs = glyphs.to_vec();
+        glyphs.sort_by(|a, b| a.x.total_cmp(&b.x));
+        // Gaps between neighbouring glyphs wider than a space are between words.
+        glyphs
+            .windows(2)
+            .map(|pair| pair[1].x - (pair[0].x + pair[0].advance))
+            .filter(|gap| *gap > 1e-9)
+            .collect::<Vec<_>>()
+    };
+
+    let plain = layout(
+        text,
+        &options
+            .clone()
+            .horizontal_alignment(HorizontalAlignment::Normal),
+    );
+    let result = layout(text, &options);
+    let last = result.lines.len() - 1;
+    assert!(last > 1);
+    for line in 0..last {
+        let glyphs = visible(&result, line);
+        assert!((right_edge(&glyphs) - right_margin).abs() < 1e-9);
+        assert!((left_edge(&glyphs) - left_margin).abs() < 1e-9);
+        // The space the line ends with hangs past its end, on the left.
+        let trailing = result.glyphs[result.lines[line].glyphs.clone()]
+            .iter()
+            .max_by_key(|glyph| glyph.cluster)
+            .unwrap();
+        assert!(text[trailing.cluster..].starts_with(' '));
+        assert!(trailing.x + trailing.advance <= left_margin + 1e-9);
+        // Every gap between two words grows by the same amount.
+        let (gaps, plain_gaps) = (words(&glyphs), words(&visible(&plain, line)));
+        assert_eq!(gaps.len(), plain_gaps.len());
+        let stretch = gaps[0] - plain_gaps[0];
+        assert!(stretch > 0.0);
+        for (gap, plain_gap) in gaps.iter().zip(plain_gaps.iter()) {
+            assert!((gap - plain_gap - stretch).abs() < 1e-9);
+        }
+    }
+    // The last line keeps to the start, which is on the right.
+    let glyphs = visible(&result, last);
+    assert!((right_edge(&glyphs) - right_margin).abs() < 1e-9);
+    assert!(left_edge(&glyphs) > left_margin + 1.0);
+}
+
+#[test]
+fn y_up_layouts_negate_every_y() {
+    let text = "Mirrored about\nthe x axis";
+    let down = layout(text, &LayoutOptions::new());
+    let up = layout(text, &LayoutOptions::new().y_down(false));
+    assert_eq!(down.glyphs.len(), up.glyphs.len());
+    for (down, up) in down.glyphs.iter().zip(up.glyphs.iter()) {
+        assert_eq!((up.x, up.y), (down.x, -down.y));
+        assert!((up.bounds.y + up.bounds.h + down.bounds.y).abs() < 1e-9);
+        assert!((up.bounds.h - down.bounds.h).abs() < 1e-9);
+    }
+    // The second line is further from the x axis, below the first one on screen either way.
+    assert!(up.lines[1].y < up.lines[0].y);
+    for (down, up) in down.lines.iter().zip(up.lines.iter()) {
+        assert_eq!(up.y, -down.y);
+    }
+    let (down, up) = (down.selection_rects(0, 8)[0], up.selection_rects(0, 8)[0]);
+    assert!((up.y + up.h + down.y).abs() < 1e-9);
+}
+
+#[test]
+fn paragraphs_can_be_aligned_on_their_own() {
+    let text = "Heading\nBody text aligned to the start.\nLast one.";
+    let options = LayoutOptions::new()
+        .horizontal_alignment(HorizontalAlignment::Reverse)
+        .paragraphs_alignment(vec![
+            HorizontalAlignment::Center,
+            HorizontalAlignment::Normal,
+        ]);
+    let content = options.x as f64 + 12.0;
+    let right = (options.x + options.w) as f64 - 12.0;
+    let lines = layout(text, &options).lines;
+    assert_eq!(lines.len(), 3);
+
+    let center = lines[0].x + lines[0].width / 2.0;
+    assert!((center - (content + right) / 2.0).abs() < 1e-9);
+    assert_eq!(lines[1].x, content);
+    // Paragraphs without an entry keep the alignment of the whole text.
+    assert!((lines[2].x + lines[2].width - right).abs() < 1e-9);
+}
+
+#[test]
+fn layout_transform_rotates_the_placed_glyphs() {
+    let text = "Wrapped at the logical width before it is rotated.";
+    let options = LayoutOptions::new().bounds(0, 0, 150, 300);
+    let plain = layout(text, &options);
+    // A quarter turn clockwise on screen: (x, y) becomes (-y, x).
+    let rotated = layout(
+        text,
+        &options.clone().transform([0.0, 1.0, -1.0, 0.0, 0.0, 0.0]),
+    );
+    assert!(plain.lines.len() > 1);
+    assert_eq!(rotated.lines, plain.lines);
+
+    for (plain, rotated) in plain.glyphs.iter().zip(rotated.glyphs.iter()) {
+        assert!((rotated.x + plain.y).abs() < 1e-9 && (rotated.y - plain.x).abs() < 1e-9);
+        let (plain, rotated) = (plain.bounds, rotated.bounds);
+        assert!((rotated.x + plain.y + plain.h).abs() < 1e-6);
+        assert!((rotated.y - plain.x).abs() < 1e-6);
+        assert!((rotated.w - plain.h).abs() < 1e-6 && (rotated.h - plain.w).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn top_aligned_text_starts_at_the_ascent() {
+    common::register_dejavu();
+    // DejaVu's ascent is the top of its accented capitals.
+    let options = LayoutOptions::new().fallback_font("dejavu").size(40);
+    let result = layout("ÉÅ\nÉÅ", &options);
+    let line = &result.lines[0];
+    assert!((line.y - line.ascent - PAD).abs() < 1e-9);
+
+    let top = result
+        .glyphs
+        .iter()
+        .map(|glyph| glyph.bounds.y)
+        .fold(f64::MAX, f64::min);
+    assert!((top - PAD).abs() < 0.5);
+    // The lines below are still a line height apart.
+    assert!((result.lines[1].y - line.y - result.line_height).abs() < 1e-9);
+}
+
+#[test]
+fn vertically_aligned_blocks_count_taller_lines() {
+    let text = "Small paragraph\nBig one";
+    let big = text.find("Big").unwrap();
+    let options = LayoutOptions::new()
+        .bounds(0, 0, 400, 400)
+        .size_range(big, text.len(), 40)
+        .vertical_alignment(VerticalAlignment::Center);
+    let gaps = |options: &LayoutOptions| {
+        let lines = layout(text, options).lines;
+        let (first, last) = (&lines[0], lines.last().unwrap());
+        (
+            first.y - first.ascent - PAD,
+            400.0 - PAD - (last.y + last.descent),
+        )
+    };
+
+    let (above, below) = gaps(&options);
+    assert!(above > 0.0);
+    assert!((above - below).abs() < 1e-9);
+
+    let (above, below) = gaps(
+        &options
+            .clone()
+            .vertical_alignment(VerticalAlignment::Reverse),
+    );
+    assert!(above > 0.0);
+    assert!(below.abs() < 1e-9);
+}
+
+#[test]
+fn jitter_is_the_same_for_the_same_seed() {
+    let text = "Dear diary, today was fine.";
+    let options = LayoutOptions::new().bounds(0, 0, 400, 100);
+    let plain = layout(text, &options);
+    let jittered = |seed| layout(text, &options.clone().jitter(1.5, 4.0, seed));
+
+    let first = jittered(7);
+    assert_eq!(first.glyphs, jittered(7).glyphs);
+    assert_ne!(first.glyphs, jittered(8).glyphs);
+    for (jittered, plain) in first.glyphs.iter().zip(plain.glyphs.iter()) {
+        assert!(plain.path.is_empty() || jittered.path != plain.path);
+        assert!((jittered.x - plain.x).abs() < 10.0 && (jittered.y - plain.y).abs() < 10.0);
+    }
+    // Only the glyphs move, the lines stay where they are.
+    assert_eq!(first.lines, plain.lines);
+
+    // Ruby glyphs are jittered like the rest.
+    let options = options.ruby(0, "Dear".len(), "dear");
+    let plain = layout(text, &options);
+    let jittered = layout(text, &options.jitter(1.5, 4.0, 7));
+    assert_eq!(jittered.ruby_glyphs.len(), plain.ruby_glyphs.len());
+    for (jittered, plain) in jittered.ruby_glyphs.iter().zip(plain.ruby_glyphs.iter()) {
+        assert_ne!(jittered.path, plain.path);
+    }
+}
+
+#[test]
+fn proportional_lines_are_as_tall_as_their_biggest_text() {
+    let text = "small\nBIG\nsmall\nsmall";
+    let big = text.find("BIG").unwrap();
+    let options = LayoutOptions::new()
+        .bounds(0, 0, 400, 400)
+        .size(16)
+        .size_range(big, big + 3, 40)
+        .line_height_ratio(1.5);
+    let result = layout(text, &options);
+    let baselines = result.lines.iter().map(|line| line.y).collect::<Vec<_>>();
+
+    // Lines of the same size are 1.5 × 16 apart, and the big one between two small ones takes
+    // 1.5 × 40 of room.
+    assert!((baselines[3] - baselines[2] - 24.0).abs() < 1e-9);
+    assert!((baselines[2] - baselines[0] - (24.0 + 60.0)).abs() < 1e-9);
+    // Its letters rise further above the baseline than they descend below it.
+    assert!(baselines[1] - baselines[0] > baselines[2] - baselines[1]);
+
+    // Without a ratio the small lines are a font height apart.
+    let plain = layout(
+        text,
+        &LayoutOptions {
+            line_height_ratio: None,
+            ..options
+        },
+    );
+    assert!((plain.lines[3].y - plain.lines[2].y - plain.line_height).abs() < 1e-9);
+}
+
+#[test]
+fn right_tab_stop_ends_the_number_after_the_tab() {
+    let text = "Total\t42\nShipping\t1250";
+    let stop = 200.0;
+    let options = LayoutOptions::new()
+        .bounds(0, 0, 400, 200)
+        .tab_stop(stop, TabAlignment::Right);
+    let result = layout(text, &options);
+    assert_eq!(result.lines.len(), 2);
+
+    for (line, number) in result.lines.iter().zip(["42", "1250"]) {
+        let number_start = text.find(number).unwrap();
+        let glyphs = &result.glyphs[line.glyphs.clone()];
+        let digits = glyphs
+            .iter()
+            .filter(|glyph| (number_start..number_start + number.len()).contains(&glyph.cluster))
+            .collect::<Vec<_>>();
+        assert_eq!(digits.len(), number.len());
+        let right_edge = digits
+            .iter()
+            .map(|glyph| glyph.x + glyph.advance)
+            .fold(0.0, f64::max);
+        assert!(
+            (right_edge - (line.x + stop)).abs() < 0.01,
+            "{right_edge} {}",
+            line.x
+        );
+    }
+}
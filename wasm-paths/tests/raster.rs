@@ -0,0 +1,74 @@
+#![cfg(feature = "raster")]
+
+use wasm_paths::{layout, render, FillRule, LayoutOptions};
+
+#[test]
+fn rendered_pixels_cover_the_glyphs() {
+    const WIDTH: usize = 300;
+    const HEIGHT: usize = 200;
+    let text = "Nul ne sera soumis à une arrestation, une détention ou un exil arbitraires.";
+    let options = LayoutOptions::new().bounds(0, 0, WIDTH as i32, HEIGHT as i32);
+    let raster = render(text, &options);
+    assert_eq!((raster.width, raster.height), (WIDTH as u32, HEIGHT as u32));
+    assert_eq!(raster.pixels.len(), WIDTH * HEIGHT * 4);
+    let is_drawn = |x: usize, y: usize| raster.pixels[(y * WIDTH + x) * 4 + 3] > 0;
+
+    let glyphs = layout(text, &options).glyphs;
+    let drawn = glyphs.iter().filter(|glyph| !glyph.path.is_empty());
+    for bounds in drawn.map(|glyph| glyph.bounds) {
+        let columns = bounds.x.floor() as usize..(bounds.x + bounds.w).ceil() as usize;
+        let rows = bounds.y.floor() as usize..(bounds.y + bounds.h).ceil() as usize;
+        assert!(columns
+            .clone()
+            .any(|x| rows.clone().any(|y| is_drawn(x, y))));
+    }
+    // Every drawn pixel is on a glyph, give or take the anti-aliasing.
+    for (x, y) in (0..WIDTH).flat_map(|x| (0..HEIGHT).map(move |y| (x, y))) {
+        let (x_f, y_f) = (x as f64, y as f64);
+        let on_glyph = glyphs.iter().any(|glyph| {
+            let bounds = glyph.bounds;
+            (bounds.x - 1.0..bounds.x + bounds.w + 1.0).contains(&x_f)
+                && (bounds.y - 1.0..bounds.y + bounds.h + 1.0).contains(&y_f)
+        });
+        assert!(on_glyph || !is_drawn(x, y));
+    }
+}
+
+#[test]
+fn counters_are_left_unfilled() {
+    const SIZE: usize = 200;
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .size(120)
+        .bounds(0, 0, SIZE as i32, SIZE as i32);
+    let raster = render("O", &options);
+    let alpha = |x: f64, y: f64| raster.pixels[(y as usize * SIZE + x as usize) * 4 + 3];
+
+    // With the nonzero rule the inner contour, wound the other way, cuts the hole out.
+    let bounds = layout("O", &options).glyphs[0].bounds;
+    let middle = bounds.y + bounds.h / 2.0;
+    assert_eq!(alpha(bounds.x + bounds.w / 2.0, middle), 0);
+    assert_eq!(alpha(bounds.x + 4.0, middle), 255);
+    assert_eq!(alpha(bounds.x + bounds.w - 4.0, middle), 255);
+}
+
+#[test]
+fn even_odd_leaves_overlaps_unfilled() {
+    const SIZE: usize = 200;
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .size(120)
+        .bounds(0, 0, SIZE as i32, SIZE as i32);
+    let bounds = layout("+", &options).glyphs[0].bounds;
+    let center = |options: &LayoutOptions| {
+        let raster = render("+", options);
+        let (x, y) = (bounds.x + bounds.w / 2.0, bounds.y + bounds.h / 2.0);
+        raster.pixels[(y as usize * SIZE + x as usize) * 4 + 3]
+    };
+
+    // The bars of Roboto's `+` are two contours wound the same way that cross in the middle.
+    assert_eq!(center(&options), 255);
+    let even_odd = options.fill_rule(FillRule::EvenOdd);
+    assert_eq!(layout("+", &even_odd).fill_rule, FillRule::EvenOdd);
+    assert_eq!(center(&even_odd), 0);
+}
@@ -0,0 +1,29 @@
+#![cfg(feature = "serde")]
+
+use wasm_paths::{layout, Decoration, DecorationLine, Highlight, Layout, LayoutOptions};
+
+#[test]
+fn layouts_survive_a_json_roundtrip() {
+    let options = LayoutOptions {
+        w: 200,
+        fallback_font: "noto".into(),
+        highlights: vec![Highlight {
+            start: 0,
+            end: 4,
+            color: "yellow".into(),
+        }],
+        decorations: vec![Decoration {
+            start: 5,
+            end: 12,
+            line: DecorationLine::Underline,
+            color: "red".into(),
+        }],
+        ..Default::default()
+    };
+    let original = layout("Some text, שלום עולם, wrapped over a few lines.", &options);
+    assert!(original.lines.len() > 1);
+
+    let json = serde_json::to_string(&original).unwrap();
+    let restored: Layout = serde_json::from_str(&json).unwrap();
+    assert_eq!(restored, original);
+}
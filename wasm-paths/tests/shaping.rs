@@ -0,0 +1,432 @@
+use wasm_paths::{
+    layout, register_font, shape, shape_gids, ClusterLevel, DottedCircles, LayoutGlyph,
+    LayoutOptions, Rect, TextTransform,
+};
+
+mod common;
+
+fn glyph_ids(text: &str, font: &str, language: Option<&str>) -> Vec<u16> {
+    let options = LayoutOptions {
+        fallback_font: font.into(),
+        language: language.map(String::from),
+        ..Default::default()
+    };
+
+    layout(text, &options)
+        .glyphs
+        .iter()
+        .map(|glyph| glyph.glyph_id)
+        .collect()
+}
+
+#[test]
+fn arabic_language_shapes_native_digits() {
+    common::register_dejavu();
+    let arabic_indic = glyph_ids("\u{0661}\u{0662}\u{0663}", "dejavu", None);
+    let western = glyph_ids("123", "dejavu", Some("en"));
+    assert_ne!(arabic_indic, western);
+    assert_eq!(glyph_ids("123", "dejavu", Some("ar")), arabic_indic);
+
+    // Roboto has no Arabic-Indic digits, so the ASCII ones are kept.
+    assert_eq!(
+        glyph_ids("123", "roboto", Some("ar")),
+        glyph_ids("123", "roboto", Some("en")),
+    );
+
+    // Inside a right-to-left paragraph the digits are substituted as well, and still read left
+    // to right, to the left of the word before them.
+    let text = "سنة 123";
+    let digits_start = text.find('1').unwrap();
+    let options = LayoutOptions {
+        fallback_font: "dejavu".into(),
+        language: Some("ar".into()),
+        ..Default::default()
+    };
+    let glyphs = layout(text, &options).glyphs;
+    let (mut digits, word): (Vec<_>, Vec<_>) = glyphs
+        .iter()
+        .partition(|glyph| glyph.cluster >= digits_start);
+    digits.sort_by(|a, b| a.x.total_cmp(&b.x));
+    let ids = digits
+        .iter()
+        .map(|glyph| glyph.glyph_id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, arabic_indic);
+    assert!(digits
+        .windows(2)
+        .all(|pair| pair[0].cluster < pair[1].cluster));
+    assert!(word.iter().all(|glyph| glyph.x > digits[2].x));
+}
+
+#[test]
+fn advances_in_font_units() {
+    let options = LayoutOptions {
+        fallback_font: "roboto".into(),
+        size: 16,
+        letter_spacing: 2.0,
+        ..Default::default()
+    };
+    // Roboto has 2048 units per em.
+    let to_px = 16.0 / 2048.0;
+
+    let glyphs = layout("AV", &options).glyphs;
+    let (first, last) = (&glyphs[0], &glyphs[1]);
+    assert!(first.advance_units > 0);
+    assert_eq!(first.advance_units as f64 * to_px + 2.0, first.advance);
+    assert_eq!(last.advance_units as f64 * to_px, last.advance);
+}
+
+#[test]
+fn shaped_runs_add_up_to_the_measured_width() {
+    let options = LayoutOptions {
+        fallback_font: "roboto".into(),
+        letter_spacing: 1.5,
+        ..Default::default()
+    };
+    let text = "Shaped without wrapping";
+
+    let glyphs = shape(text, &options);
+    let width = glyphs.iter().map(|glyph| glyph.advance).sum::<f64>();
+    let lines = layout(text, &options).lines;
+    assert_eq!(lines.len(), 1);
+    assert!((width - lines[0].width).abs() < 1e-9);
+    assert_eq!(glyphs[0].x, 0.0);
+    assert!(glyphs
+        .windows(2)
+        .all(|pair| (pair[0].x + pair[0].advance - pair[1].x).abs() < 1e-9));
+
+    // Right-to-left runs come in logical order too and still start at the origin.
+    let options = LayoutOptions {
+        fallback_font: "noto".into(),
+        ..options
+    };
+    let text = "שלום עולם";
+    let glyphs = shape(text, &options);
+    let width = glyphs.iter().map(|glyph| glyph.advance).sum::<f64>();
+    assert!((width - layout(text, &options).lines[0].width).abs() < 1e-9);
+    assert!(glyphs
+        .windows(2)
+        .all(|pair| pair[0].cluster < pair[1].cluster));
+    assert!(glyphs
+        .windows(2)
+        .all(|pair| (pair[1].x + pair[1].advance - pair[0].x).abs() < 1e-9));
+    let left = glyphs.iter().map(|glyph| glyph.x).fold(f64::MAX, f64::min);
+    assert!(left.abs() < 1e-9);
+}
+
+#[test]
+fn feature_ranges_only_change_their_glyphs() {
+    let text = "plain small caps";
+    let (start, end) = (text.find("small").unwrap(), text.find(" caps").unwrap());
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let ids = |options: &LayoutOptions| {
+        layout(text, options)
+            .glyphs
+            .iter()
+            .map(|glyph| (glyph.cluster, glyph.glyph_id))
+            .collect::<Vec<_>>()
+    };
+
+    let plain = ids(&options);
+    let small_caps = ids(&options.clone().feature(start, end, "smcp", 1));
+    assert_eq!(small_caps.len(), plain.len());
+    for (plain, small_caps) in plain.iter().zip(small_caps.iter()) {
+        assert_eq!(plain.0, small_caps.0);
+        assert_eq!(plain.1 != small_caps.1, (start..end).contains(&plain.0));
+    }
+    // Turning the feature off is the same as not asking for it.
+    assert_eq!(ids(&options.feature(start, end, "smcp", 0)), plain);
+}
+
+#[test]
+fn cluster_level_sets_how_marks_are_merged() {
+    // Roboto has an "ffi" ligature but no precomposed x with an acute accent.
+    let text = "office x\u{301}";
+    let mark = text.find('\u{301}').unwrap();
+    let clusters = |level| {
+        let options = LayoutOptions::new()
+            .fallback_font("roboto")
+            .cluster_level(level);
+        layout(text, &options)
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.cluster)
+            .collect::<Vec<_>>()
+    };
+
+    let characters = clusters(ClusterLevel::MonotoneCharacters);
+    let graphemes = clusters(ClusterLevel::MonotoneGraphemes);
+    assert_eq!(characters.len(), graphemes.len());
+    // The ligature is one cluster either way.
+    assert_eq!(&characters[..3], [0, 1, 4]);
+    assert_eq!(&graphemes[..3], [0, 1, 4]);
+    // The mark only has its own with character clusters.
+    assert_eq!(characters.last(), Some(&mark));
+    assert_eq!(graphemes.last(), Some(&(mark - 1)));
+}
+
+#[test]
+fn marks_sit_on_the_side_of_the_base_they_belong_to() {
+    let options = LayoutOptions::new().fallback_font("roboto").size(40);
+    // Neither has a precomposed form in Roboto, so the marks are positioned by the shaper.
+    for (text, above) in [("x\u{301}", true), ("x\u{323}", false)] {
+        let glyphs = layout(text, &options).glyphs;
+        assert_eq!(glyphs.len(), 2);
+        let (base, mark) = (&glyphs[0].bounds, &glyphs[1].bounds);
+        // Screen y grows downwards.
+        if above {
+            assert!(
+                mark.y + mark.h <= base.y + 1.0,
+                "{text:?}: {mark:?} over {base:?}"
+            );
+        } else {
+            assert!(
+                mark.y >= base.y + base.h - 1.0,
+                "{text:?}: {mark:?} under {base:?}"
+            );
+        }
+        // And horizontally over the base, not after it.
+        let center = mark.x + mark.w / 2.0;
+        assert!(base.x < center && center < base.x + base.w);
+    }
+}
+
+#[test]
+fn enclosing_marks_are_centered_on_their_base() {
+    // None of the built-in fonts have U+20DD, and this one doesn't position it.
+    let font = include_bytes!("../fonts/DejaVuMathTeXGyre.ttf").to_vec();
+    assert!(register_font("math", font));
+    let options = LayoutOptions::new().fallback_font("math").size(40);
+    let center = |rect: &Rect| (rect.x + rect.w / 2.0, rect.y + rect.h / 2.0);
+
+    for text in ["1\u{20dd}", "W\u{20dd}"] {
+        let result = layout(text, &options);
+        let (base, mark) = (&result.glyphs[0], &result.glyphs[1]);
+        let (base_center, mark_center) = (center(&base.bounds), center(&mark.bounds));
+        assert!((base_center.0 - mark_center.0).abs() < 1e-9, "{text:?}");
+        assert!((base_center.1 - mark_center.1).abs() < 1e-9, "{text:?}");
+        // The circle is drawn around all of the base, even a wide one.
+        assert!(mark.bounds.w >= base.bounds.w - 1e-9 && mark.bounds.h >= base.bounds.h - 1e-9);
+        // And takes no room of its own.
+        assert_eq!(mark.advance, 0.0);
+        assert_eq!(
+            result.lines[0].width,
+            layout(&text[..1], &options).lines[0].width
+        );
+    }
+}
+
+#[test]
+fn arabic_letters_keep_their_joining_form_across_break_opportunities() {
+    common::register_dejavu();
+    let word = "بيت";
+    let yeh = word.find('ي').unwrap();
+    let options = LayoutOptions {
+        fallback_font: "dejavu".into(),
+        w: 800,
+        ..Default::default()
+    };
+    let form = |text: &str, options: &LayoutOptions, cluster: usize| {
+        let glyphs = layout(text, options).glyphs;
+        glyphs
+            .iter()
+            .find(|glyph| glyph.cluster == cluster)
+            .unwrap()
+            .glyph_id
+    };
+
+    let medial = form(word, &options, yeh);
+    assert_ne!(medial, form("ي", &options, 0));
+    // Breaking around the letter is allowed, but it still joins both of its neighbours.
+    let split = LayoutOptions {
+        break_opportunities: vec![yeh, yeh + 'ي'.len_utf8()],
+        ..options.clone()
+    };
+    assert_eq!(form(word, &split, yeh), medial);
+    // Even once the word is actually broken over several lines.
+    let wrapped = LayoutOptions { w: 30, ..split };
+    assert_eq!(layout(word, &wrapped).lines.len(), 3);
+    assert_eq!(form(word, &wrapped, yeh), medial);
+}
+
+#[test]
+fn text_transforms_keep_the_source_clusters() {
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let glyphs = |text: &str, options: &LayoutOptions| {
+        layout(text, options)
+            .glyphs
+            .iter()
+            .map(|glyph| (glyph.cluster, glyph.glyph_id))
+            .collect::<Vec<_>>()
+    };
+    let ids = |glyphs: &[(usize, u16)]| glyphs.iter().map(|glyph| glyph.1).collect::<Vec<_>>();
+
+    let text = "straße";
+    let uppercase = options
+        .clone()
+        .text_transform(0, text.len(), TextTransform::Uppercase);
+    let transformed = glyphs(text, &uppercase);
+    assert_eq!(ids(&transformed), ids(&glyphs("STRASSE", &options)));
+    // Both of the S the sharp s turns into map back to it.
+    let clusters = transformed.iter().map(|glyph| glyph.0).collect::<Vec<_>>();
+    assert_eq!(clusters, [0, 1, 2, 3, 4, 4, 6]);
+    let positions = layout(text, &uppercase).char_positions(text);
+    assert!(positions[4].is_some() && positions[5] > positions[4]);
+
+    let text = "don't stop";
+    let capitalize = options
+        .clone()
+        .text_transform(0, text.len(), TextTransform::Capitalize);
+    assert_eq!(
+        ids(&glyphs(text, &capitalize)),
+        ids(&glyphs("Don't Stop", &options))
+    );
+    // Only the range is transformed.
+    let lowercase = options
+        .clone()
+        .text_transform(0, 3, TextTransform::Lowercase);
+    assert_eq!(
+        ids(&glyphs("ABC DEF", &lowercase)),
+        ids(&glyphs("abc DEF", &options))
+    );
+}
+
+#[test]
+fn kerning_overrides_tighten_their_pair() {
+    let options = LayoutOptions::new().fallback_font("roboto");
+    let tightened = options.clone().kerning_override('A', 'V', -3.0);
+    let pen = |text: &str, options: &LayoutOptions| {
+        let glyphs = layout(text, options).glyphs;
+        (glyphs[0].advance, glyphs[1].x - glyphs[0].x)
+    };
+
+    let (advance, gap) = pen("AV", &options);
+    let (tight_advance, tight_gap) = pen("AV", &tightened);
+    assert!((advance - 3.0 - tight_advance).abs() < 1e-9);
+    assert!((gap - 3.0 - tight_gap).abs() < 1e-9);
+    // Only that pair, in that order.
+    assert_eq!(pen("VA", &tightened), pen("VA", &options));
+    assert_eq!(pen("AW", &tightened), pen("AW", &options));
+
+    // Pairs are in reading order in right-to-left text too.
+    let options = LayoutOptions::new().fallback_font("noto");
+    let text = "של";
+    let tightened = options.clone().kerning_override('ש', 'ל', -3.0);
+    let width = |options: &LayoutOptions| layout(text, options).lines[0].width;
+    assert!((width(&options) - 3.0 - width(&tightened)).abs() < 1e-9);
+    let visual_order = options.clone().kerning_override('ל', 'ש', -3.0);
+    assert_eq!(width(&visual_order), width(&options));
+}
+
+#[test]
+fn glyph_ids_come_in_logical_order() {
+    // Roboto has an "ffi" ligature.
+    let text = "office";
+    let gids = shape_gids(text, "roboto", 16);
+    assert!(gids.len() < text.chars().count());
+    let glyphs = layout(text, &LayoutOptions::new().fallback_font("roboto"));
+    assert_eq!(
+        gids,
+        glyphs
+            .glyphs
+            .iter()
+            .map(|glyph| glyph.glyph_id)
+            .collect::<Vec<_>>()
+    );
+
+    // Right-to-left text isn't reversed.
+    let text = "שלום";
+    let mut glyphs = layout(text, &LayoutOptions::new().fallback_font("noto")).glyphs;
+    glyphs.sort_by_key(|glyph| glyph.cluster);
+    let logical = glyphs
+        .iter()
+        .map(|glyph| glyph.glyph_id)
+        .collect::<Vec<_>>();
+    assert_eq!(shape_gids(text, "noto", 16), logical);
+}
+
+#[test]
+fn horizontal_scale_condenses_advances_but_not_outlines() {
+    let options = LayoutOptions::new()
+        .fallback_font("roboto")
+        .letter_spacing(2.0);
+    let text = "Wide";
+    let unspaced = layout(text, &options.clone().letter_spacing(0.0)).glyphs;
+    let normal = layout(text, &options).glyphs;
+    let condensed = layout(text, &options.clone().horizontal_scale(0.5)).glyphs;
+
+    for (i, (normal, condensed)) in normal.iter().zip(condensed.iter()).enumerate() {
+        assert!((condensed.advance - normal.advance / 2.0).abs() < 1e-9);
+        // The spacing after every glyph but the last is scaled too.
+        let spacing = if i + 1 < text.len() { 2.0 } else { 0.0 };
+        assert!((condensed.advance - (unspaced[i].advance + spacing) / 2.0).abs() < 1e-9);
+        assert!((condensed.bounds.w - normal.bounds.w).abs() < 1e-9);
+        assert!((condensed.bounds.h - normal.bounds.h).abs() < 1e-9);
+        assert_eq!(condensed.advance_units, normal.advance_units);
+    }
+    let right = |glyphs: &[LayoutGlyph]| {
+        glyphs
+            .last()
+            .map(|glyph| glyph.x + glyph.advance - glyphs[0].x)
+            .unwrap()
+    };
+    assert!((right(&condensed) - right(&normal) / 2.0).abs() < 1e-9);
+
+    // Or the outlines too.
+    let squeezed = options
+        .clone()
+        .horizontal_scale(0.5)
+        .horizontal_scale_outlines(true);
+    let squeezed = layout(text, &squeezed).glyphs;
+    for (normal, squeezed) in normal.iter().zip(squeezed.iter()) {
+        assert!((squeezed.advance - normal.advance / 2.0).abs() < 1e-9);
+        assert!((squeezed.bounds.w - normal.bounds.w / 2.0).abs() < 1e-6);
+        assert!((squeezed.bounds.h - normal.bounds.h).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn merged_runs_take_fewer_shaping_calls_for_the_same_glyphs() {
+    let text = "The quick brown fox jumps over the lazy dog, and then it runs far away into the \
+        woods, where nobody will ever find it again. It was a good day for the fox.";
+    let options = LayoutOptions::new().bounds(0, 0, 300, 600);
+    let separate = layout(text, &options);
+    let merged = layout(text, &options.clone().merge_runs(true));
+
+    assert!(separate.shaping_calls > text.split(' ').count() / 2);
+    assert_eq!(merged.shaping_calls, 1);
+    assert_eq!(merged.glyphs, separate.glyphs);
+    assert_eq!(merged.lines, separate.lines);
+
+    // A line separator still ends the run.
+    let separated = layout("One two\u{2028}three four", &options.merge_runs(true));
+    assert_eq!(separated.shaping_calls, 2);
+    assert_eq!(separated.lines.len(), 2);
+}
+
+#[test]
+fn marks_without_a_base_get_a_dotted_circle_when_asked() {
+    common::register_dejavu();
+    let text = "\u{301}abc \u{301}x e\u{301}";
+    let circle = glyph_ids("\u{25cc}", "dejavu", None)[0];
+    let glyphs = |dotted_circles| {
+        let options = LayoutOptions::new()
+            .fallback_font("dejavu")
+            .dotted_circles(dotted_circles);
+        layout(text, &options).glyphs
+    };
+
+    // The leading mark, and the one after the space that starts a fragment of its own.
+    let always = glyphs(DottedCircles::Always);
+    let circles = always
+        .iter()
+        .filter(|glyph| glyph.glyph_id == circle)
+        .map(|glyph| glyph.cluster)
+        .collect::<Vec<_>>();
+    assert_eq!(circles, vec![0, text.find(" ").unwrap() + 1]);
+
+    let suppressed = glyphs(DottedCircles::Suppress);
+    assert_eq!(suppressed.len(), always.len() - 2);
+    assert!(suppressed.iter().all(|glyph| glyph.glyph_id != circle));
+    assert_eq!(glyphs(DottedCircles::Shaper), suppressed);
+}
@@ -25,7 +25,7 @@ fn textbox() {
         "<rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"white\" stroke=\"cyan\" stroke-width=\"5\"></rect>",
         WIDTH, HEIGHT
     );
-    svg += &format!("<g fill=\"black\" stroke=\"transparent\">");
+    svg += "<g fill=\"black\" stroke=\"transparent\">";
     for path in svg_paths.iter() {
         svg += path;
     }